@@ -8,14 +8,43 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use cadence_crater::cleanup;
 use cadence_crater::cmd::CraterApplication;
+use cadence_crater::interrupt::{self, Action};
+use cadence_crater::types::ErrorChain;
 use clap::Clap;
+use std::process;
 
 fn main() {
     let app = CraterApplication::parse();
+    let remove_clones = !app.keep_partial_clones();
+    let skip_projects = app.skip_on_interrupt();
+
+    let handler = ctrlc::set_handler(move || {
+        if skip_projects && interrupt::interrupted() == Action::SkipProject {
+            eprintln!(
+                "cadence-crater: skipping the current project, press Ctrl-C again within {}s \
+                 to stop the run",
+                interrupt::ABORT_WINDOW.as_secs()
+            );
+            return;
+        }
+
+        for path in cleanup::remove_tracked(remove_clones) {
+            eprintln!("cadence-crater: removed {:?}", path);
+        }
+
+        process::exit(130);
+    });
+
+    if let Err(e) = handler {
+        eprintln!("cadence-crater: unable to install signal handler: {}", e);
+    }
+
     let res = app.run();
 
     if let Err(e) = res {
-        eprintln!("cadence-crater: {}", e);
+        eprintln!("cadence-crater: {}", ErrorChain(&e));
+        process::exit(1);
     }
 }
@@ -0,0 +1,617 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::cleanup::{self, Kind};
+use crate::types::CraterError;
+use crate::vcs::{self, FetchKind, Fetched, Source};
+use flate2::read::GzDecoder;
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+use zip::ZipArchive;
+
+/// User agent sent when downloading archives
+const USER_AGENT: &str = concat!("cadence-crater/", env!("CARGO_PKG_VERSION"));
+
+/// Maximum number of bytes extracted from an archive when no maximum size is set,
+/// since archives are extracted in memory and a small archive can decompress to far
+/// more than would fit
+const MAX_EXTRACTED_SIZE: u64 = 1 << 30;
+
+/// Format of a source archive, determined by the extension of its URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Gzip compressed tarball, `.tar.gz` or `.tgz`
+    TarGz,
+    /// Zip file, `.zip`
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Get the format of the archive at the given URL from its extension, ignoring
+    /// any query string or fragment
+    pub fn from_url(url: &str) -> Option<Self> {
+        let path = url.split(['?', '#']).next().unwrap_or(url).to_lowercase();
+
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if path.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Download a project from a `.tar.gz` or `.zip` source archive instead of cloning it
+///
+/// The archive is extracted into a directory named after it, without the extension.
+/// When everything in the archive is under a single top-level directory (as with
+/// archives of a GitHub tag or a gist) the contents of that directory are used.
+/// The project is downloaded and extracted again each time it's fetched so that
+/// patches made to a previous extraction don't linger.
+#[derive(Debug)]
+pub struct ArchiveSource {
+    url: String,
+    format: ArchiveFormat,
+    expected_sha256: Option<String>,
+    proxy: Option<String>,
+    offline: bool,
+    max_size: Option<u64>,
+    exceeded: Cell<bool>,
+}
+
+impl ArchiveSource {
+    pub fn new<S: Into<String>>(url: S, format: ArchiveFormat) -> Self {
+        ArchiveSource {
+            url: url.into(),
+            format,
+            expected_sha256: None,
+            proxy: None,
+            offline: false,
+            max_size: None,
+            exceeded: Cell::new(false),
+        }
+    }
+
+    /// Create an `ArchiveSource` if the given repository is an `http` or `https` URL
+    /// of a `.tar.gz`, `.tgz`, or `.zip` archive
+    pub fn from_repo(repo: &str) -> Option<Self> {
+        if !repo.starts_with("https://") && !repo.starts_with("http://") {
+            return None;
+        }
+
+        ArchiveFormat::from_url(repo).map(|f| Self::new(repo, f))
+    }
+
+    /// Fail the download unless the SHA-256 checksum of the archive is the given
+    /// hex encoded value
+    pub fn with_sha256<S: Into<String>>(mut self, sha256: S) -> Self {
+        self.expected_sha256 = Some(sha256.into());
+        self
+    }
+
+    /// Use the given proxy for downloading instead of the `HTTPS_PROXY` or `HTTP_PROXY`
+    /// environment variables
+    pub fn with_proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Never access the network, failing to download the archive
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Cancel downloading once more than the given number of bytes have been received
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Format of the archive
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    /// Download the archive, checking its size and checksum
+    fn download(&self) -> Result<Vec<u8>, CraterError> {
+        if self.offline {
+            return Err(CraterError::new(format!(
+                "unable to download {} while offline",
+                self.url
+            )));
+        }
+
+        let mut builder = ureq::AgentBuilder::new().user_agent(USER_AGENT);
+        builder = match &self.proxy {
+            Some(p) => builder.proxy(ureq::Proxy::new(p).map_err(|e| {
+                CraterError::new_err(format!("invalid proxy {:?} for {}", p, self.url), e)
+            })?),
+            None => builder.try_proxy_from_env(true),
+        };
+
+        let response = builder
+            .build()
+            .get(&self.url)
+            .call()
+            .map_err(|e| CraterError::new_err(format!("unable to download {}", self.url), e))?;
+
+        let limit = self
+            .max_size
+            .map(|m| m.saturating_add(1))
+            .unwrap_or(u64::MAX);
+        let mut body = Vec::new();
+        response
+            .into_reader()
+            .take(limit)
+            .read_to_end(&mut body)
+            .map_err(|e| CraterError::new_err(format!("unable to download {}", self.url), e))?;
+
+        if let Some(max) = self.max_size.filter(|m| body.len() as u64 > *m) {
+            self.exceeded.set(true);
+            return Err(CraterError::new(format!(
+                "download of {} cancelled after exceeding {} bytes",
+                self.url, max
+            )));
+        }
+
+        if let Some(expected) = &self.expected_sha256 {
+            check_sha256(&body, expected)
+                .map_err(|e| CraterError::new(format!("archive {}: {}", self.url, e)))?;
+        }
+
+        Ok(body)
+    }
+}
+
+impl Source for ArchiveSource {
+    fn fetch(&self, into: &Path) -> Result<Fetched, CraterError> {
+        let full = into.join(vcs::repo_dir_name(&self.url)?);
+        let body = self.download()?;
+        let limit = self.max_size.unwrap_or(MAX_EXTRACTED_SIZE);
+        let entries = read_archive(self.format, &body, limit).map_err(|e| {
+            if let ReadError::TooLarge(_) = e {
+                self.exceeded.set(self.max_size.is_some());
+            }
+
+            CraterError::new(format!("unable to read {}: {}", self.url, e))
+        })?;
+
+        let extract = || {
+            if full.exists() {
+                fs::remove_dir_all(&full)?;
+            }
+
+            let _tracked = cleanup::track(Kind::PartialClone, &full);
+
+            write_entries(&full, &entries)
+        };
+
+        extract().map_err(|e: io::Error| {
+            CraterError::new_err(format!("unable to extract {} to {:?}", self.url, full), e)
+        })?;
+
+        Ok(Fetched::new(full, FetchKind::Downloaded))
+    }
+
+    fn find_fetched(&self, into: &Path) -> Result<Fetched, CraterError> {
+        let full = vcs::fetched_dir(into.join(vcs::repo_dir_name(&self.url)?), &self.url)?;
+        Ok(Fetched::new(full, FetchKind::Reused))
+    }
+
+    fn exceeded_max_size(&self) -> Option<u64> {
+        self.max_size.filter(|_| self.exceeded.get())
+    }
+}
+
+/// Check that the SHA-256 checksum of some bytes is the given hex encoded value
+pub fn check_sha256(bytes: &[u8], expected: &str) -> Result<(), String> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, bytes);
+    let actual: String = digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        Ok(())
+    } else {
+        Err(format!(
+            "SHA-256 checksum {} doesn't match the expected {}",
+            actual,
+            expected.trim()
+        ))
+    }
+}
+
+/// File or directory read from an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    path: PathBuf,
+    dir: bool,
+    executable: bool,
+    contents: Vec<u8>,
+}
+
+/// Reason an archive couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ReadError {
+    /// The archive isn't valid or has an entry that can't be extracted
+    Invalid(String),
+    /// The contents of the archive are larger than the given number of bytes
+    TooLarge(u64),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Invalid(msg) => write!(f, "{}", msg),
+            ReadError::TooLarge(limit) => {
+                write!(f, "contents are larger than {} bytes when extracted", limit)
+            }
+        }
+    }
+}
+
+impl From<String> for ReadError {
+    fn from(msg: String) -> Self {
+        ReadError::Invalid(msg)
+    }
+}
+
+/// Read the files and directories in an archive, without the single top-level
+/// directory everything is in, if there is one
+///
+/// Symbolic links and other special files are skipped. An error is returned if the
+/// archive is invalid, any entry would be extracted outside of the destination, or
+/// the contents of the archive are more than `limit` bytes once decompressed.
+fn read_archive(format: ArchiveFormat, bytes: &[u8], limit: u64) -> Result<Vec<Entry>, ReadError> {
+    let entries = match format {
+        ArchiveFormat::TarGz => {
+            let mut tar = Vec::new();
+            GzDecoder::new(bytes)
+                .take(limit.saturating_add(1))
+                .read_to_end(&mut tar)
+                .map_err(|e| format!("invalid gzip data: {}", e))?;
+            if tar.len() as u64 > limit {
+                return Err(ReadError::TooLarge(limit));
+            }
+
+            read_tar(&tar)?
+        }
+        ArchiveFormat::Zip => read_zip(bytes, limit)?,
+    };
+
+    Ok(strip_top_level(entries))
+}
+
+/// Get the relative path an archive entry is extracted to, rejecting absolute paths
+/// and paths with `..` components
+///
+/// `None` is returned for entries that refer to the destination itself, like `./`.
+fn safe_path(name: &str) -> Result<Option<PathBuf>, String> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(c) => path.push(c),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(format!(
+                    "entry {:?} would be extracted outside of the destination",
+                    name
+                ))
+            }
+        }
+    }
+
+    Ok(Some(path).filter(|p| p.components().next().is_some()))
+}
+
+/// Remove the single top-level directory all entries are in, if there is one
+fn strip_top_level(entries: Vec<Entry>) -> Vec<Entry> {
+    let first = |e: &Entry| e.path.components().next().map(|c| c.as_os_str().to_owned());
+    let top = match entries.first().and_then(first) {
+        Some(top) => top,
+        None => return entries,
+    };
+
+    let single = entries
+        .iter()
+        .all(|e| first(e).as_ref() == Some(&top) && (e.dir || e.path.components().count() > 1));
+    if !single {
+        return entries;
+    }
+
+    entries
+        .into_iter()
+        .filter_map(|mut e| {
+            let rest = e.path.strip_prefix(&top).ok()?.to_path_buf();
+            if rest.as_os_str().is_empty() {
+                return None;
+            }
+
+            e.path = rest;
+            Some(e)
+        })
+        .collect()
+}
+
+/// Write the entries of an archive under the given directory
+fn write_entries(dir: &Path, entries: &[Entry]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    for entry in entries {
+        let path = dir.join(&entry.path);
+        if entry.dir {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(&path, &entry.contents)?;
+
+        #[cfg(unix)]
+        if entry.executable {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the files and directories in an uncompressed tar file
+fn read_tar(bytes: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut archive = tar::Archive::new(bytes);
+    let mut entries = Vec::new();
+
+    let invalid = |e: io::Error| format!("invalid tar file: {}", e);
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let dir = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => false,
+            EntryType::Directory => true,
+            // Links and other special files are skipped
+            _ => continue,
+        };
+
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let path = match safe_path(&name)? {
+            Some(path) => path,
+            None => continue,
+        };
+
+        let executable = entry.header().mode().unwrap_or(0) & 0o111 != 0;
+        let mut contents = Vec::new();
+        if !dir {
+            entry
+                .read_to_end(&mut contents)
+                .map_err(|e| format!("unable to read tar entry {:?}: {}", name, e))?;
+        }
+
+        entries.push(Entry {
+            path,
+            dir,
+            executable,
+            contents,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Read the files and directories in a zip file, failing once more than `limit`
+/// bytes have been decompressed
+fn read_zip(bytes: &[u8], limit: u64) -> Result<Vec<Entry>, ReadError> {
+    let mut archive =
+        ZipArchive::new(Cursor::new(bytes)).map_err(|e| format!("invalid zip file: {}", e))?;
+    let mut entries = Vec::new();
+    let mut remaining = limit;
+
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| format!("invalid zip file: {}", e))?;
+        let name = file.name().to_owned();
+        let path = match safe_path(&name)? {
+            Some(path) => path,
+            None => continue,
+        };
+
+        // Symbolic links are skipped, like they are for tar files
+        let mode = file.unix_mode().unwrap_or(0);
+        if mode & 0o170_000 == 0o120_000 {
+            continue;
+        }
+
+        let dir = file.is_dir();
+        let mut contents = Vec::new();
+        if !dir {
+            // The size in the header can't be trusted so the contents are limited
+            // as they're decompressed
+            (&mut file)
+                .take(remaining.saturating_add(1))
+                .read_to_end(&mut contents)
+                .map_err(|e| format!("unable to read zip entry {:?}: {}", name, e))?;
+            remaining = remaining
+                .checked_sub(contents.len() as u64)
+                .ok_or(ReadError::TooLarge(limit))?;
+        }
+
+        entries.push(Entry {
+            path,
+            dir,
+            executable: mode & 0o111 != 0,
+            contents,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_sha256, read_archive, safe_path, write_entries, ArchiveFormat, ReadError};
+    use crate::testing::ScratchDir;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::{Cursor, Write};
+    use std::path::PathBuf;
+    use zip::write::FileOptions;
+    use zip::{CompressionMethod, ZipWriter};
+
+    /// Build a gzip compressed tarball of the given (name, contents) entries,
+    /// where names ending in `/` are directories
+    fn tar_gz(entries: &[(&str, &str)]) -> Vec<u8> {
+        let gz = GzEncoder::new(Vec::new(), Compression::default());
+        let mut tar = tar::Builder::new(gz);
+        for (name, contents) in entries {
+            let mut header = tar::Header::new_ustar();
+            // Names are set directly since `set_path` rejects the unsafe ones tested here
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_mode(0o755);
+            header.set_size(contents.len() as u64);
+            header.set_entry_type(if name.ends_with('/') {
+                tar::EntryType::Directory
+            } else {
+                tar::EntryType::Regular
+            });
+            header.set_cksum();
+            tar.append(&header, contents.as_bytes()).unwrap();
+        }
+
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// Build a zip file of the given (name, contents) entries, compressed with deflate
+    fn zip(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(0o644);
+        for (name, contents) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_archive_format_from_url() {
+        assert_eq!(
+            Some(ArchiveFormat::TarGz),
+            ArchiveFormat::from_url("https://example.com/proj-1.0.tar.gz")
+        );
+        assert_eq!(
+            Some(ArchiveFormat::TarGz),
+            ArchiveFormat::from_url("https://example.com/proj.TGZ?download=1")
+        );
+        assert_eq!(
+            Some(ArchiveFormat::Zip),
+            ArchiveFormat::from_url("https://gist.github.com/u/abc/archive/def.zip")
+        );
+        assert_eq!(
+            None,
+            ArchiveFormat::from_url("https://github.com/56quarters/cadence.git")
+        );
+    }
+
+    #[test]
+    fn test_read_tar_gz() {
+        let bytes = tar_gz(&[
+            ("proj-1.0/", ""),
+            ("proj-1.0/Cargo.toml", "[package]\n"),
+            ("proj-1.0/src/lib.rs", "pub fn f() {}\n"),
+        ]);
+        let entries = read_archive(ArchiveFormat::TarGz, &bytes, 1 << 20).unwrap();
+        let paths: Vec<PathBuf> = entries.iter().map(|e| e.path.clone()).collect();
+
+        assert_eq!(
+            vec![PathBuf::from("Cargo.toml"), PathBuf::from("src/lib.rs")],
+            paths
+        );
+        assert_eq!(b"[package]\n".to_vec(), entries[0].contents);
+        assert!(entries[0].executable);
+    }
+
+    #[test]
+    fn test_read_zip() {
+        let bytes = zip(&[
+            ("Cargo.toml", "[package]\n"),
+            ("src/lib.rs", "pub fn f() {}\n"),
+        ]);
+        let entries = read_archive(ArchiveFormat::Zip, &bytes, 1 << 20).unwrap();
+
+        // Files at the top level mean there's no directory to strip
+        assert_eq!(2, entries.len());
+        assert_eq!(PathBuf::from("src/lib.rs"), entries[1].path);
+        assert_eq!(b"pub fn f() {}\n".to_vec(), entries[1].contents);
+        assert!(!entries[1].executable);
+
+        let dir = ScratchDir::new("archive");
+        write_entries(&dir, &entries).unwrap();
+        assert_eq!(
+            "[package]\n",
+            fs::read_to_string(dir.join("Cargo.toml")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_archive_path_traversal() {
+        let tar = tar_gz(&[("proj/Cargo.toml", ""), ("../escape", "oops")]);
+        let zip = zip(&[("/etc/escape", "oops")]);
+
+        assert!(read_archive(ArchiveFormat::TarGz, &tar, 1 << 20).is_err());
+        assert!(read_archive(ArchiveFormat::Zip, &zip, 1 << 20).is_err());
+        assert!(safe_path("a/../../b").is_err());
+        assert_eq!(Ok(None), safe_path("./"));
+        assert_eq!(Ok(Some(PathBuf::from("a/b"))), safe_path("./a/b"));
+    }
+
+    #[test]
+    fn test_read_archive_too_large() {
+        // Tarballs are padded to at least 10KiB and compress well, unlike the limit
+        let large = "0".repeat(1 << 16);
+        let tar = tar_gz(&[("proj/Cargo.toml", &large)]);
+        let zip = zip(&[("Cargo.toml", "[package]\n"), ("src/lib.rs", &large)]);
+        assert!(tar.len() < 1 << 12);
+        assert!(zip.len() < 1 << 12);
+
+        assert_eq!(
+            Err(ReadError::TooLarge(1 << 15)),
+            read_archive(ArchiveFormat::TarGz, &tar, 1 << 15)
+        );
+        assert_eq!(
+            Err(ReadError::TooLarge(1 << 15)),
+            read_archive(ArchiveFormat::Zip, &zip, 1 << 15)
+        );
+        assert!(read_archive(ArchiveFormat::TarGz, &tar, 1 << 20).is_ok());
+        assert!(read_archive(ArchiveFormat::Zip, &zip, 1 << 20).is_ok());
+    }
+
+    #[test]
+    fn test_check_sha256() {
+        let empty = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        assert!(check_sha256(b"", empty).is_ok());
+        assert!(check_sha256(b"", &empty.to_uppercase()).is_ok());
+        assert!(check_sha256(b"x", empty).is_err());
+    }
+}
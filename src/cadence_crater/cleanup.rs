@@ -0,0 +1,166 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Track files and directories that are in the middle of being written so they
+//! can be removed if crater is interrupted, and find any left behind by runs that
+//! were interrupted anyway.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Name of the temporary file a patched Cargo.toml is written to before being
+/// renamed into place, followed by a suffix unique to the process and write
+pub const TEMP_FILE_NAME: &str = ".cadence-rename";
+
+static TRACKED: Mutex<Vec<(u64, Kind, PathBuf)>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+/// Type of in-progress path being tracked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Temporary file written before being renamed into place
+    TempFile,
+    /// Directory a repository is being cloned or copied into
+    PartialClone,
+}
+
+/// Handle to a tracked path that stops tracking it when dropped
+#[derive(Debug)]
+pub struct Tracked {
+    id: u64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+        tracked.retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+/// Track a path that is in progress until the returned handle is dropped
+pub fn track<P: AsRef<Path>>(kind: Kind, path: P) -> Tracked {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    tracked.push((id, kind, path.as_ref().to_path_buf()));
+    Tracked { id }
+}
+
+/// Path of a new temporary file in `dir` that no other thread or crater process
+/// writes to
+pub fn temp_file_path(dir: &Path) -> PathBuf {
+    let n = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!("{}.{}.{}", TEMP_FILE_NAME, process::id(), n))
+}
+
+/// Returns true if the file name is one of crater's temporary files, including
+/// the unsuffixed name used by older versions
+pub fn is_temp_file_name(name: &OsStr) -> bool {
+    name.to_str()
+        .map(|name| {
+            name == TEMP_FILE_NAME
+                || name
+                    .strip_prefix(TEMP_FILE_NAME)
+                    .map(|suffix| suffix.starts_with('.'))
+                    .unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+/// Remove every tracked temporary file and, optionally, partial clones
+///
+/// Returns the paths that were removed. Errors removing paths are ignored since
+/// this is meant to be called as a best-effort step when crater is interrupted.
+pub fn remove_tracked(partial_clones: bool) -> Vec<PathBuf> {
+    let tracked = TRACKED.lock().unwrap_or_else(|e| e.into_inner());
+    let mut removed = Vec::new();
+
+    for (_, kind, path) in tracked.iter() {
+        let res = match kind {
+            Kind::TempFile => fs::remove_file(path),
+            Kind::PartialClone if partial_clones => fs::remove_dir_all(path),
+            Kind::PartialClone => continue,
+        };
+
+        if res.is_ok() {
+            removed.push(path.clone());
+        }
+    }
+
+    removed
+}
+
+/// Find temporary files left behind by interrupted runs anywhere under `dir`
+///
+/// Symbolic links and `.git` directories are not followed.
+pub fn find_temp_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    find_temp_files_into(dir, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+fn find_temp_files_into(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() && entry.file_name() != ".git" {
+            find_temp_files_into(&entry.path(), found)?;
+        } else if file_type.is_file() && is_temp_file_name(&entry.file_name()) {
+            found.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_temp_files, is_temp_file_name, temp_file_path, TEMP_FILE_NAME};
+    use crate::testing::ScratchDir;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_find_temp_files() {
+        let dir = ScratchDir::new("prune");
+        fs::create_dir_all(dir.join("crates").join("a")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join(TEMP_FILE_NAME), "").unwrap();
+        let suffixed = temp_file_path(&dir.join("crates").join("a"));
+        fs::write(&suffixed, "").unwrap();
+        fs::write(dir.join(".git").join(TEMP_FILE_NAME), "").unwrap();
+        fs::write(dir.join("Cargo.toml"), "").unwrap();
+
+        let found = find_temp_files(&dir).unwrap();
+
+        assert_eq!(vec![dir.join(TEMP_FILE_NAME), suffixed], found);
+    }
+
+    #[test]
+    fn test_temp_file_path() {
+        let first = temp_file_path(Path::new("/tmp"));
+        let second = temp_file_path(Path::new("/tmp"));
+
+        assert_ne!(first, second);
+        assert_eq!(Some(Path::new("/tmp")), first.parent());
+        assert!(is_temp_file_name(first.file_name().unwrap()));
+        assert!(is_temp_file_name(OsStr::new(TEMP_FILE_NAME)));
+        assert!(!is_temp_file_name(OsStr::new(".cadence-renamed")));
+        assert!(!is_temp_file_name(OsStr::new("Cargo.toml")));
+    }
+}
@@ -8,93 +8,3704 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::toml::{LocalOverride, LocalVersion};
-use crate::types::CraterError;
-use crate::vcs::RemoteRepo;
-use clap::{crate_version, Clap};
-use serde_derive::Deserialize;
+use crate::archive::ArchiveSource;
+use crate::cleanup;
+use crate::interrupt;
+use crate::journal::{journal_path, Completed, Journal, JOURNAL_FILE_NAME};
+use crate::pipeline::{ClonePipeline, Fetch, Prefetched};
+use crate::registry::CratesIo;
+use crate::report::{PreviousRun, ProjectOutcome, RunReport, Timings, Warning};
+use crate::runner::{self, CargoLog, CargoRunner, Target};
+use crate::toml::{
+    depends_on, package_name, profile_settings, CratePatch, DependencySection, LocalOverride,
+    LocalVersion, OverrideMode, PatchSource, PatchedFile, CADENCE_CRATE,
+};
+use crate::types::{CraterError, ErrorChain};
+use crate::usage;
+use crate::vcs::{self, FetchKind, Fetched, LocalDirSource, RemoteRepo, Source};
+use cargo_metadata::Version;
+use clap::{crate_version, AppSettings, ArgEnum, Clap};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use schemars::gen::SchemaSettings;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
 
-/// Fetch and patch projects to use the local Cadence version
+/// Fetch, patch, build, and test projects using the local Cadence version
 #[derive(Debug, Clap)]
-#[clap(name = "cadence-crater", version = crate_version ! ())]
+#[clap(
+    name = "cadence-crater",
+    version = crate_version ! (),
+    setting = AppSettings::SubcommandsNegateReqs
+)]
 pub struct CraterApplication {
-    #[clap(long = "dest")]
+    #[clap(flatten)]
+    fetch: FetchOptions,
+    #[clap(flatten)]
+    configs: ConfigOptions,
+    /// Write a JUnit XML report with a test case for each project to this path
+    #[clap(long = "junit", parse(from_os_str = expand_home))]
+    junit: Option<PathBuf>,
+    /// Write the complete report (a `summary.json` and a subdirectory with the outcome,
+    /// any error, the patch diff, and the cargo output of each project) to this
+    /// directory for archiving
+    #[clap(long = "output-dir", parse(from_os_str = expand_home))]
+    output_dir: Option<PathBuf>,
+    /// Record the outcome of each project to this journal as it completes, defaulting
+    /// to `journal.jsonl` in the `--output-dir` or the `--continue-from` journal
+    #[clap(long = "journal", parse(from_os_str = expand_home))]
+    journal: Option<PathBuf>,
+    /// Resume an interrupted run from its journal (or the `--output-dir` containing
+    /// it), reusing the outcomes of projects it completed instead of running them again
+    #[clap(long = "continue-from", parse(from_os_str = expand_home))]
+    continue_from: Option<PathBuf>,
+    /// Skip projects whose commit is the same as when they last passed, carrying over
+    /// their outcomes from the report in the `--output-dir`. Projects are only skipped
+    /// when the same Cadence versions, at the same commits, are being tested.
+    #[clap(long = "incremental")]
+    incremental: bool,
+    /// Build and test every project even if `--incremental` is set
+    #[clap(long = "no-incremental")]
+    no_incremental: bool,
+    /// Also build and test each project without the patch, only reporting new failures
+    #[clap(long = "baseline")]
+    baseline: bool,
+    /// Never access the network: only reuse existing clones and cache entries and run
+    /// cargo with `--offline`. Projects that aren't available locally fail.
+    #[clap(long = "offline")]
+    offline: bool,
+    /// Leave projects that passed out of the summary printed at the end of the run.
+    /// They're still included in the JUnit and `--output-dir` reports.
+    #[clap(long = "report-only-failures")]
+    report_only_failures: bool,
+    /// Skip projects that don't depend on Cadence after fetching them, instead of
+    /// counting them as passing. Dependencies are looked for in each Cargo.toml of the
+    /// project and, for transitive ones, in its Cargo.lock.
+    #[clap(long = "filter-by-dependency")]
+    filter_by_dependency: bool,
+    /// Print where each project would be cloned from and into, the revision checked
+    /// out, and the subprojects patched, then exit without accessing the network
+    #[clap(long = "plan")]
+    plan: bool,
+    /// Print the JSON Schema of the configuration format, such as for editors to
+    /// validate and complete configurations with, then exit
+    #[clap(long = "print-config-schema")]
+    print_config_schema: bool,
+    /// Write the configuration the run uses, after defaults and the global `env` are
+    /// applied to each project and projects are selected by `--tag`, to this file. It's
+    /// written as JSON if the file name ends with `.json` and as TOML otherwise.
+    #[clap(long = "dump-effective-config", parse(from_os_str = expand_home))]
+    dump_effective_config: Option<PathBuf>,
+    /// Only print the summary, without the progress of each project
+    #[clap(long = "quiet")]
+    quiet: bool,
+    /// When to color the summary and progress: `auto` colors them when stdout is a
+    /// terminal and the `NO_COLOR` environment variable isn't set
+    #[clap(long = "color", arg_enum, default_value = "auto")]
+    color: ColorChoice,
+    /// Format of the summary written at the end of the run: `text`, a `markdown` table
+    /// for pasting into a GitHub comment, `csv`, or `json`. Progress is written to
+    /// stderr instead of stdout for formats other than `text`.
+    #[clap(long = "summary-format", arg_enum, default_value = "text")]
+    summary_format: SummaryFormat,
+    /// Stop starting new projects once the run has taken this long, such as `90s`, `45m`,
+    /// or `2h` (seconds if no unit is given). Projects already started are allowed
+    /// to finish and the rest are reported as skipped.
+    #[clap(long = "max-duration", parse(try_from_str = parse_duration))]
+    max_duration: Option<Duration>,
+    /// Leave partially cloned repositories in place when interrupted
+    #[clap(long = "keep-partial-clones")]
+    keep_partial_clones: bool,
+    /// Local Cadence checkout (a directory or Cargo.toml path) to test each project
+    /// against, instead of or in addition to the `cadence` argument. Repeat it to test
+    /// against several checkouts.
+    #[clap(long = "cadence", value_name = "PATH", number_of_values = 1, parse(from_os_str = expand_home))]
+    extra_cadence: Vec<PathBuf>,
+    /// Path to the configuration or `-` to read it from standard input, instead of
+    /// the `config` argument
+    #[clap(long = "config", value_name = "PATH", parse(from_os_str = expand_home))]
+    config_flag: Option<PathBuf>,
+    /// Versions of Cadence published to crates.io to test each project against, only
+    /// changing the version required instead of patching the source. The local Cadence
+    /// checkout may be omitted when this is set.
+    #[clap(long = "cadence-version", number_of_values = 1)]
+    cadence_version: Vec<String>,
+    /// Build each local Cadence checkout before cloning any project, stopping the run
+    /// if one doesn't build since every project would fail the same way
+    #[clap(long = "check-cadence")]
+    check_cadence: bool,
+    /// Check with `cargo metadata` that patched projects actually use the local Cadence
+    #[clap(long = "verify-patch")]
+    verify_patch: bool,
+    /// Report which Cadence items each project refers to, found by scanning its Rust
+    /// sources for `cadence::` paths, to show what a failure may have been caused by
+    #[clap(long = "analyze-usage")]
+    analyze_usage: bool,
+    /// Fail projects that emit compiler warnings by building them with `-D warnings`
+    #[clap(long = "deny-warnings")]
+    deny_warnings: bool,
+    /// Number of parallel jobs cargo uses when building each project, passed as `-j`.
+    /// Projects are processed one at a time so this bounds the total number of jobs,
+    /// defaulting to the number of CPUs like cargo itself.
+    #[clap(long = "build-jobs")]
+    build_jobs: Option<usize>,
+    /// Number of times to retry building a project when the build fails without any
+    /// compiler errors, such as when rustc is killed or a file lock can't be taken.
+    /// Builds that fail with compiler errors are never retried.
+    #[clap(long = "build-retries", default_value = "0")]
+    build_retries: usize,
+    /// Target triple to build and test each project for, passed to cargo as `--target`,
+    /// for projects that don't set their own `target`. Projects are only built, not
+    /// tested, for targets other than the host.
+    #[clap(long = "target", value_name = "TRIPLE")]
+    target: Option<String>,
+    /// Update every dependency of each project with `cargo update` after patching and
+    /// before building, keeping its `Cargo.lock` from hiding incompatibilities
+    #[clap(long = "update-deps")]
+    update_deps: bool,
+    /// Save the Cargo.lock of each project after it passes and restore it on later
+    /// runs, building and testing with `--locked` so results don't change because of
+    /// new dependency versions. Saved under `locks` in the cache directory, or in the
+    /// output directory with --no-cache.
+    #[clap(long = "frozen-lock")]
+    frozen_lock: bool,
+    /// Build and test without optimizations or debug info to speed up builds, unless
+    /// a project sets `opt-level` or `debug` in its own dev or test profile
+    #[clap(long = "fast-profile")]
+    fast_profile: bool,
+    /// Fail projects with warnings, such as a patch that has no effect, instead of
+    /// only reporting them
+    #[clap(long = "fail-on-warnings")]
+    fail_on_warnings: bool,
+    /// Write each patched Cargo.toml to a sibling file with this suffix appended to
+    /// its name (e.g. `.crater` for `Cargo.toml.crater`) instead of replacing it.
+    /// Cargo only reads `Cargo.toml` so projects are patched but not built or tested.
+    #[clap(long = "patch-suffix")]
+    patch_suffix: Option<String>,
+    /// Don't sync patched Cargo.toml files to disk before replacing the originals.
+    /// Patching is faster but files may be lost if the system crashes, which only
+    /// matters when projects are kept after the run.
+    #[clap(long = "no-fsync")]
+    no_fsync: bool,
+    /// Build and test each project as it is without patching it to use Cadence, such
+    /// as to check that projects build before testing them with a local checkout. The
+    /// local Cadence checkout may be omitted when this is set.
+    #[clap(long = "no-patch")]
+    no_patch: bool,
+    /// Fail a project if any of its subprojects don't exist instead of skipping them
+    #[clap(long = "strict-subprojects")]
+    strict_subprojects: bool,
+    /// Leave projects that already patch Cadence (such as to a developer's own checkout)
+    /// as they are with a warning instead of replacing their patch
+    #[clap(long = "respect-existing-patch")]
+    respect_existing_patch: bool,
+    /// Comma separated dependency sections to change the version of Cadence required
+    /// in: `dependencies`, `dev-dependencies`, and `build-dependencies`. Defaults to all of them.
+    #[clap(long = "sections", use_delimiter = true)]
+    sections: Vec<DependencySection>,
+    /// How to make projects use Cadence: `patch` adds it to the `[patch]` section of
+    /// the root Cargo.toml while `direct` rewrites each dependency on Cadence to use
+    /// its source directly, for projects the `[patch]` section doesn't apply to.
+    #[clap(long = "override-mode", default_value = "patch")]
+    override_mode: OverrideMode,
+    /// Patch projects to use Cadence from this git repository instead of the local
+    /// checkout. The local checkout is still used to determine the version required.
+    #[clap(long = "cadence-git")]
+    cadence_git: Option<String>,
+    /// Branch of the `--cadence-git` repository to use for projects that don't set
+    /// their own `cadence_branch`
+    #[clap(long = "cadence-branch")]
+    cadence_branch: Option<String>,
+    /// Local Cadence checkout to test projects against, either the directory
+    /// containing its Cargo.toml or the path to the Cargo.toml itself. Can also be
+    /// given with `--cadence`, in which case the only argument is the configuration.
+    #[clap(required_unless_present_any = &["cadence-version", "extra-cadence", "no-patch", "print-config-schema"], parse(from_os_str = expand_home))]
+    cadence: Option<PathBuf>,
+    /// Path to the configuration or `-` to read it from standard input. Can also be
+    /// given with `--config`, in which case the only argument is the Cadence checkout.
+    #[clap(required_unless_present_any = &["cadence-version", "config-dir", "config-flag", "extra-cadence", "no-patch", "print-config-schema"], parse(from_os_str = expand_home))]
+    config: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clap)]
+enum Command {
+    Init(InitCommand),
+    Prune(PruneCommand),
+    Check(CheckCommand),
+    Fetch(FetchCommand),
+    RunBuilt(RunBuiltCommand),
+}
+
+// Where and how projects are fetched, shared by running them and `fetch`. This isn't a
+// doc comment since clap would use it as the about text of the commands it's part of.
+#[derive(Debug, Clap)]
+struct FetchOptions {
+    /// Directory to clone projects into, defaulting to the system temporary directory.
+    /// Relative paths are resolved against the current directory and any missing
+    /// parent directories are created.
+    #[clap(long = "dest", parse(from_os_str = expand_home))]
     destination: Option<PathBuf>,
-    cadence: PathBuf,
-    config: PathBuf,
+    /// Proxy used when cloning projects, defaulting to the `HTTPS_PROXY` or `HTTP_PROXY`
+    /// environment variables or git configuration. This only affects cloning done by
+    /// crater itself, not cargo builds (which respect their own proxy settings).
+    #[clap(long = "proxy")]
+    proxy: Option<String>,
+    /// Try cloning projects from a mirror under this base URL first, falling back to the
+    /// configured `repo` if that fails, e.g. `https://git.example.com/mirror` for
+    /// `https://git.example.com/mirror/github.com/56quarters/cadence.git`
+    #[clap(long = "mirror-base")]
+    mirror_base: Option<String>,
+    /// Directory to cache clones of projects pinned to a `rev` in, defaulting to
+    /// `$XDG_CACHE_HOME/cadence-crater` or `~/.cache/cadence-crater`
+    #[clap(long = "cache-dir", parse(from_os_str = expand_home))]
+    cache_dir: Option<PathBuf>,
+    /// Clone projects directly instead of reusing clones from the cache
+    #[clap(long = "no-cache")]
+    no_cache: bool,
+    /// Cancel cloning a project once this much has been received, such as `500M` or
+    /// `2G` (bytes if no unit is given), and report it as skipped
+    #[clap(long = "max-clone-size", parse(try_from_str = parse_size))]
+    max_clone_size: Option<u64>,
+    /// Number of projects to clone at once, ahead of the project being built. Cloning
+    /// is bound by the network rather than the CPU, so many projects can be cloned while
+    /// they're built one at a time. Defaults to cloning each project just before it's built.
+    /// There's no option to build several projects at once, `--build-jobs` only sets the
+    /// number of cargo jobs used by each build.
+    #[clap(long = "clone-jobs", default_value = "1")]
+    clone_jobs: usize,
+}
+
+impl FetchOptions {
+    /// Resolve where projects are fetched into and the cache they're copied from, once
+    /// before any project is fetched
+    fn context(&self, cfg: &RunConfig, offline: bool) -> Result<FetchContext, CraterError> {
+        Ok(FetchContext {
+            downloads: self.destination()?,
+            dests: project_destinations(cfg)?,
+            cache: self.cache_dir()?,
+            offline,
+        })
+    }
+
+    fn destination(&self) -> Result<PathBuf, CraterError> {
+        let dest = self.destination.clone().unwrap_or_else(env::temp_dir);
+
+        env::current_dir()
+            .and_then(|cwd| create_dir(&cwd, &dest))
+            .map_err(|e| CraterError::new_err("unable to determine repository destination", e))
+    }
+
+    fn cache_dir(&self) -> Result<Option<PathBuf>, CraterError> {
+        if self.no_cache {
+            return Ok(None);
+        }
+
+        open_cache_dir(self.cache_dir.clone())
+    }
+
+    /// Where a project is fetched from
+    fn source(&self, project: &RunProject, ctx: &FetchContext) -> Box<dyn Source> {
+        project.source(
+            self.proxy.as_deref(),
+            ctx.cache.as_deref(),
+            self.mirror_base.as_deref(),
+            ctx.offline,
+            self.max_clone_size,
+        )
+    }
+
+    /// Start cloning projects ahead of building them when `--clone-jobs` is more than
+    /// one, returning the pipeline and the number of projects it clones at once
+    ///
+    /// Only projects that `include` returns true for, given their index, are cloned.
+    /// Neither are projects checked out into a directory with the same name as another
+    /// project's, since they'd share a checkout or cached clone, so they're cloned
+    /// just before they're built instead.
+    fn clone_pipeline<F>(
+        &self,
+        cfg: &RunConfig,
+        ctx: &FetchContext,
+        include: F,
+    ) -> Option<(ClonePipeline, usize)>
+    where
+        F: Fn(usize, &RunProject) -> bool,
+    {
+        if self.clone_jobs <= 1 {
+            return None;
+        }
+
+        let names: Vec<Option<String>> = cfg
+            .projects
+            .iter()
+            .map(|p| vcs::repo_dir_name(&p.repo).ok())
+            .collect();
+
+        let fetches: Vec<Fetch> = cfg
+            .projects
+            .iter()
+            .enumerate()
+            .filter(|(i, p)| include(*i, p))
+            .filter(|(i, _)| {
+                names[*i].is_some() && names.iter().filter(|n| *n == &names[*i]).count() == 1
+            })
+            .map(|(i, p)| (i, self.source(p, ctx), ctx.downloads_for(p).to_path_buf()))
+            .collect();
+
+        let jobs = self.clone_jobs.min(fetches.len()).max(1);
+        Some((ClonePipeline::start(jobs, fetches), jobs))
+    }
+}
+
+/// Where projects are fetched into and the cache they're copied from, resolved once
+/// before any project is fetched
+#[derive(Debug)]
+struct FetchContext {
+    downloads: PathBuf,
+    dests: HashMap<PathBuf, PathBuf>,
+    cache: Option<PathBuf>,
+    offline: bool,
+}
+
+impl FetchContext {
+    /// Directory the project is fetched into, its own `dest` or the global destination
+    fn downloads_for(&self, project: &RunProject) -> &Path {
+        project
+            .dest
+            .as_ref()
+            .and_then(|dest| self.dests.get(dest))
+            .unwrap_or(&self.downloads)
+    }
+}
+
+// Which configurations are read and which of their projects are used, shared by
+// running projects and `fetch`. Like `FetchOptions`, this isn't a doc comment.
+#[derive(Debug, Clap)]
+struct ConfigOptions {
+    /// Directory of `.toml` configurations to run together instead of a single
+    /// configuration, each with its own defaults
+    #[clap(long = "config-dir", parse(from_os_str = expand_home))]
+    config_dir: Option<PathBuf>,
+    /// Format of the configuration, inferred from the file extension when not set
+    #[clap(long = "config-format", arg_enum)]
+    config_format: Option<ConfigFormat>,
+    /// Only run projects with this tag in their `tags`. Repeat it to run projects with
+    /// any of several tags.
+    #[clap(long = "tag", number_of_values = 1)]
+    tags: Vec<String>,
+}
+
+impl ConfigOptions {
+    /// Load the configuration at `path`, or every configuration in `--config-dir` when
+    /// no path is given, keeping only the projects selected by `--tag`
+    fn load(&self, path: Option<&Path>) -> Result<RunConfig, CraterError> {
+        let cfg = match (&self.config_dir, path) {
+            (Some(_), Some(_)) => {
+                return Err(CraterError::new(
+                    "a configuration path can't be given along with --config-dir",
+                ))
+            }
+            (Some(dir), None) => self.config_from_dir(dir)?,
+            (None, Some(path)) => load_config(path, self.config_format(path))?,
+            (None, None) => return Err(CraterError::new("no configuration path given")),
+        };
+
+        self.select_tagged(cfg)
+    }
+
+    /// Leave out projects without any of the tags given with `--tag`, if any
+    ///
+    /// A warning is printed for each tag no project has and an error is returned if
+    /// no projects are left.
+    fn select_tagged(&self, mut cfg: RunConfig) -> Result<RunConfig, CraterError> {
+        if self.tags.is_empty() {
+            return Ok(cfg);
+        }
+
+        for tag in self.tags.iter() {
+            if !cfg.projects.iter().any(|p| p.tags.contains(tag)) {
+                eprintln!("cadence-crater: warning: no projects are tagged {:?}", tag);
+            }
+        }
+
+        cfg.projects
+            .retain(|p| p.tags.iter().any(|t| self.tags.contains(t)));
+        if cfg.projects.is_empty() {
+            return Err(CraterError::new(format!(
+                "no projects have any of the tags {}",
+                self.tags.join(", ")
+            )));
+        }
+
+        Ok(cfg)
+    }
+
+    /// Load every `.toml` configuration in a directory, in order of file name, and
+    /// concatenate their projects
+    ///
+    /// The defaults and `env` of each file only apply to the projects in that file.
+    /// Errors are returned if the same project is in more than one file or if a crate
+    /// in `patch_crates` is given different paths by different files.
+    fn config_from_dir(&self, dir: &Path) -> Result<RunConfig, CraterError> {
+        let paths = toml_files(dir)?;
+
+        let mut merged = RunConfig {
+            defaults: RunDefaults::default(),
+            patch_crates: BTreeMap::new(),
+            env: BTreeMap::new(),
+            projects: Vec::new(),
+        };
+
+        let mut crate_sources: HashMap<String, &Path> = HashMap::new();
+        let mut project_sources: HashMap<String, &Path> = HashMap::new();
+
+        for path in paths.iter() {
+            let cfg = load_config(path, ConfigFormat::Toml)?;
+
+            for (name, crate_path) in cfg.patch_crates {
+                if let Some(existing) = merged.patch_crates.get(&name) {
+                    if existing != &crate_path {
+                        return Err(CraterError::new(format!(
+                            "crate {} is patched with {:?} in {:?} and {:?} in {:?}",
+                            name, existing, crate_sources[&name], crate_path, path
+                        )));
+                    }
+                }
+
+                crate_sources.insert(name.clone(), path);
+                merged.patch_crates.insert(name, crate_path);
+            }
+
+            for project in cfg.projects {
+                if let Some(other) = project_sources.get(&project.repo) {
+                    if other != path {
+                        return Err(CraterError::new(format!(
+                            "project {} is in both {:?} and {:?}",
+                            project.repo, other, path
+                        )));
+                    }
+                }
+
+                project_sources.insert(project.repo.clone(), path);
+                merged.projects.push(project);
+            }
+        }
+
+        Ok(merged)
+    }
+
+    fn config_format(&self, path: &Path) -> ConfigFormat {
+        self.config_format.unwrap_or_else(|| format_for_path(path))
+    }
+}
+
+/// Clone (or download or copy) projects into a destination without patching,
+/// building, or testing them, to be run later by `run-built` with the same `--dest`
+#[derive(Debug, Clap)]
+struct FetchCommand {
+    #[clap(flatten)]
+    fetch: FetchOptions,
+    #[clap(flatten)]
+    configs: ConfigOptions,
+    /// Don't print the progress of each project
+    #[clap(long = "quiet")]
+    quiet: bool,
+    /// Path to the configuration or `-` to read it from standard input
+    #[clap(required_unless_present = "config-dir", parse(from_os_str = expand_home))]
+    config: Option<PathBuf>,
+}
+
+impl FetchCommand {
+    fn run(&self) -> Result<(), CraterError> {
+        if self.fetch.destination.is_none() {
+            return Err(CraterError::new(
+                "fetch requires --dest, the directory run-built finds projects in",
+            ));
+        }
+
+        let cfg = self.configs.load(self.config.as_deref())?;
+        let ctx = self.fetch.context(&cfg, false)?;
+        let mut pipeline = self.fetch.clone_pipeline(&cfg, &ctx, |_, _| true);
+
+        let total = cfg.projects.len();
+        let output = ProgressOutput::new(total, self.quiet, false);
+        let mut failed = 0;
+
+        for (i, project) in cfg.projects.iter().enumerate() {
+            let progress = Progress::new(&output, i + 1, total, &project.repo);
+            progress.phase(Phase::Clone);
+
+            let (source, res) = match pipeline.as_mut().and_then(|(p, _)| p.take(i)) {
+                Some(p) => (p.source, p.res),
+                None => {
+                    let source = self.fetch.source(project, &ctx);
+                    let res = source.fetch(ctx.downloads_for(project));
+                    (source, res)
+                }
+            };
+
+            match res {
+                Ok(fetched) => {
+                    report_fetched(project, source.as_ref(), &fetched, &progress);
+                    progress.message(format!(
+                        "fetched {} into {:?}",
+                        project.repo,
+                        fetched.path()
+                    ));
+                }
+                Err(e) if source.exceeded_max_size().is_some() => {
+                    progress.message(format!("skipping {}: {}", project.repo, ErrorChain(&e)))
+                }
+                Err(e) => {
+                    failed += 1;
+                    progress.message(format!(
+                        "unable to fetch {}: {}",
+                        project.repo,
+                        ErrorChain(&e)
+                    ));
+                }
+            }
+
+            progress.done();
+        }
+
+        drop(pipeline);
+        output.finish();
+
+        if failed > 0 {
+            return Err(CraterError::new(format!(
+                "{} of {} projects could not be fetched",
+                failed, total
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Patch, build, and test projects already fetched into `--dest` by `fetch` instead
+/// of fetching them again. Every other option and argument is given before it, e.g.
+/// `cadence-crater --dest clones ../cadence crater.toml run-built`.
+#[derive(Debug, Clap)]
+struct RunBuiltCommand {}
+
+/// Validate configurations without cloning, building, or writing anything, reporting
+/// every problem found and failing if there are any
+#[derive(Debug, Clap)]
+struct CheckCommand {
+    /// Format of the configurations, detected from their file extensions by default
+    #[clap(long = "config-format", arg_enum)]
+    config_format: Option<ConfigFormat>,
+    /// Configurations to check, or directories of `.toml` configurations
+    #[clap(required = true, parse(from_os_str = expand_home))]
+    configs: Vec<PathBuf>,
+}
+
+impl CheckCommand {
+    fn run(&self) -> Result<(), CraterError> {
+        // Configurations in the same directory are run together with --config-dir
+        // so they're checked for projects in more than one of them.
+        let mut paths = Vec::new();
+        for (i, path) in self.configs.iter().enumerate() {
+            if path.is_dir() {
+                paths.extend(toml_files(path)?.into_iter().map(|p| (i, p)));
+            } else {
+                paths.push((i, path.clone()));
+            }
+        }
+
+        let mut problems = Vec::new();
+        let mut project_sources: HashMap<(usize, String), &Path> = HashMap::new();
+        let mut projects = 0;
+
+        for (dir, path) in paths.iter() {
+            let format = self.config_format.unwrap_or_else(|| format_for_path(path));
+            let mut cfg = match read_config(path, format) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    problems.push(format!("{}: {}", path.display(), ErrorChain(&e)));
+                    continue;
+                }
+            };
+
+            cfg.apply_defaults();
+            for problem in cfg.validate().into_iter().chain(cfg.lint()) {
+                problems.push(format!("{}: {}", path.display(), problem));
+            }
+
+            for project in cfg.projects.iter() {
+                let key = (*dir, project.repo.clone());
+                match project_sources.get(&key) {
+                    Some(other) if other != path => problems.push(format!(
+                        "{}: project {} is also in {}, it can't be run from both with \
+                         --config-dir",
+                        path.display(),
+                        project.repo,
+                        other.display()
+                    )),
+                    _ => {
+                        project_sources.insert(key, path);
+                    }
+                }
+            }
+
+            projects += cfg.projects.len();
+        }
+
+        for problem in problems.iter() {
+            println!("{}", problem);
+        }
+
+        if !problems.is_empty() {
+            return Err(CraterError::new(format!(
+                "{} problem(s) found in {} configuration(s)",
+                problems.len(),
+                paths.len()
+            )));
+        }
+
+        println!(
+            "{} configuration(s) with {} project(s) ok",
+            paths.len(),
+            projects
+        );
+        Ok(())
+    }
+}
+
+/// Find temporary files left in patched projects by interrupted runs and remove them
+#[derive(Debug, Clap)]
+struct PruneCommand {
+    /// Actually remove the files instead of only listing them
+    #[clap(long = "yes")]
+    yes: bool,
+    /// Directory to search, such as the destination projects were cloned into
+    #[clap(parse(from_os_str = expand_home))]
+    dir: PathBuf,
+}
+
+impl PruneCommand {
+    fn run(&self) -> Result<(), CraterError> {
+        let found = cleanup::find_temp_files(&self.dir).map_err(|e| {
+            CraterError::new_err(
+                format!("unable to search {:?} for temporary files", self.dir),
+                e,
+            )
+        })?;
+
+        for path in found.iter() {
+            if self.yes {
+                fs::remove_file(path)
+                    .map_err(|e| CraterError::new_err(format!("unable to remove {:?}", path), e))?;
+                println!("removed {:?}", path);
+            } else {
+                println!("would remove {:?}", path);
+            }
+        }
+
+        if !self.yes && !found.is_empty() {
+            println!("run again with --yes to remove {} file(s)", found.len());
+        }
+
+        Ok(())
+    }
+}
+
+/// Generate a starter configuration for crates using their repositories on crates.io
+#[derive(Debug, Clap)]
+struct InitCommand {
+    /// Names of crates to include, read from standard input when none are given
+    crates: Vec<String>,
+}
+
+impl InitCommand {
+    fn run(&self) -> Result<(), CraterError> {
+        let names = self.names()?;
+        let registry = CratesIo::new();
+        let mut projects = Vec::with_capacity(names.len());
+
+        for name in names.iter() {
+            match registry.repository(name) {
+                Ok(repo) => projects.push(RunProject {
+                    repo,
+                    root: Some(String::new()),
+                    subprojects: Some(Vec::new()),
+                    ..Default::default()
+                }),
+                Err(e) => eprintln!("cadence-crater: {}", e),
+            }
+        }
+
+        let found = projects.len();
+        let cfg = RunConfig {
+            defaults: RunDefaults::default(),
+            patch_crates: BTreeMap::new(),
+            env: BTreeMap::new(),
+            projects,
+        };
+
+        let out = toml::to_string(&cfg)
+            .map_err(|e| CraterError::new_err("unable to serialize configuration", e))?;
+        print!("{}", out);
+
+        if found < names.len() {
+            return Err(CraterError::new(format!(
+                "{} of {} crates could not be looked up",
+                names.len() - found,
+                names.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn names(&self) -> Result<Vec<String>, CraterError> {
+        if !self.crates.is_empty() {
+            return Ok(self.crates.clone());
+        }
+
+        let mut buf = String::new();
+        io::stdin()
+            .read_to_string(&mut buf)
+            .map_err(|e| CraterError::new_err("unable to read crate names from stdin", e))?;
+
+        Ok(buf.split_whitespace().map(|s| s.to_owned()).collect())
+    }
 }
 
 impl CraterApplication {
+    /// Returns true if partial clones should be kept when the run is interrupted
+    pub fn keep_partial_clones(&self) -> bool {
+        self.keep_partial_clones
+    }
+
+    /// Returns true if an interrupt should skip the project being run rather than
+    /// stopping the run, which is only done for runs started from a terminal
+    pub fn skip_on_interrupt(&self) -> bool {
+        matches!(self.command, None | Some(Command::RunBuilt(_)))
+            && !self.plan
+            && io::stdin().is_terminal()
+    }
+
+    /// Returns true if projects were already fetched into the destination by the
+    /// `fetch` command and shouldn't be fetched again
+    fn run_built(&self) -> bool {
+        matches!(self.command, Some(Command::RunBuilt(_)))
+    }
+
+    /// Report of the previous run to carry over the outcomes of unchanged projects
+    /// from when `--incremental` is set
+    fn previous_run(&self) -> Result<Option<PreviousRun>, CraterError> {
+        if !self.incremental || self.no_incremental {
+            return Ok(None);
+        }
+
+        match &self.output_dir {
+            Some(dir) => PreviousRun::read(dir).map(Some),
+            None => Err(CraterError::new(
+                "--incremental requires --output-dir to record the commit of each project in",
+            )),
+        }
+    }
+
+    /// Path of the journal to record progress to, if any
+    fn journal_path(&self) -> Option<PathBuf> {
+        self.journal
+            .clone()
+            .or_else(|| self.output_dir.as_ref().map(|d| d.join(JOURNAL_FILE_NAME)))
+            .or_else(|| self.continue_from.as_deref().map(journal_path))
+    }
+
     pub fn run(self) -> Result<(), CraterError> {
-        let local_cadence = LocalVersion::new(self.cadence.clone());
-        let local_version = local_cadence.version()?;
-        let local_path = local_cadence.path()?;
+        match &self.command {
+            Some(Command::Init(init)) => return init.run(),
+            Some(Command::Prune(prune)) => return prune.run(),
+            Some(Command::Check(check)) => return check.run(),
+            Some(Command::Fetch(fetch)) => return fetch.run(),
+            Some(Command::RunBuilt(_)) | None => {}
+        }
+
+        if self.print_config_schema {
+            return serde_json::to_writer_pretty(io::stdout(), &config_schema())
+                .and_then(|_| writeln!(io::stdout()).map_err(serde_json::Error::io))
+                .map_err(|e| CraterError::new_err("unable to write configuration schema", e));
+        }
+
+        if self.run_built() && self.fetch.destination.is_none() {
+            return Err(CraterError::new(
+                "run-built requires --dest, the directory projects were fetched into",
+            ));
+        }
+
+        let start = Instant::now();
 
         let cfg = self.config()?;
-        let downloads = self.destination()?;
+        if let Some(path) = &self.dump_effective_config {
+            write_config(path, &cfg, format_for_path(path))?;
+        }
 
-        for project in cfg.projects.iter() {
-            let remote = RemoteRepo::new(project.repo.clone());
-            let repo = remote.download(&downloads)?;
+        if self.plan {
+            return self.plan(&cfg);
+        }
 
-            let root = repo.join(&project.root).join("Cargo.toml");
-            let crates: Vec<PathBuf> = project
-                .subprojects
-                .iter()
-                .map(|subproject| repo.join(&project.root).join(subproject).join("Cargo.toml"))
-                .collect();
+        let cross = self.target.is_some() || cfg.projects.iter().any(|p| p.target.is_some());
+        let fetch = self.fetch.context(&cfg, self.offline)?;
+        let mut ctx = RunContext {
+            host: if cross {
+                Some(runner::host_triple()?)
+            } else {
+                None
+            },
+            cadences: self.cadences()?,
+            extra_patches: cfg.extra_patches()?,
+            locks: self.lock_dir(fetch.cache.as_deref())?,
+            previous: None,
+            fetch,
+        };
+
+        let total = cfg.projects.len();
+        let color = self.color.enabled();
+        console::set_colors_enabled(color);
 
-            println!("CRATES: {:?}", crates);
+        let mut report = RunReport::new()
+            .with_only_failures(self.report_only_failures)
+            .with_unpatched(self.no_patch)
+            .with_color(color);
+        for cadence in ctx.cadences.iter() {
+            report.add_cadence(
+                cadence.version.clone(),
+                cadence.path.clone(),
+                cadence.sha.clone(),
+            );
+        }
+
+        // Keep stdout for the summary alone when it's meant for other programs
+        let header = if self.summary_format == SummaryFormat::Text {
+            report.write_header(io::stdout())
+        } else {
+            report.write_header(io::stderr())
+        };
+        header.map_err(|e| CraterError::new_err("unable to write summary", e))?;
+
+        if self.check_cadence {
+            self.check_cadences(&ctx.cadences)?;
+        }
+
+        let mut completed = match &self.continue_from {
+            Some(path) => Completed::read(path)?,
+            None => Completed::new(),
+        };
+
+        let mut pipeline = if self.run_built() {
+            None
+        } else {
+            self.fetch
+                .clone_pipeline(&cfg, &ctx.fetch, |i, p| !completed.contains(i + 1, &p.repo))
+        };
+        if let Some((_, jobs)) = &pipeline {
+            let concurrency = format!(
+                "cloning up to {} projects at once, building one at a time with {}",
+                jobs,
+                match self.build_jobs {
+                    Some(n) => format!("{} cargo jobs", n),
+                    None => "a cargo job per CPU".to_owned(),
+                }
+            );
+
+            let res = if self.summary_format == SummaryFormat::Text {
+                writeln!(io::stdout(), "{}", concurrency)
+            } else {
+                writeln!(io::stderr(), "{}", concurrency)
+            };
+            res.map_err(|e| CraterError::new_err("unable to write summary", e))?;
+        }
+
+        ctx.previous = self
+            .previous_run()?
+            .filter(|previous| previous.same_cadences(&report));
+
+        let mut journal = match self.journal_path() {
+            Some(path) => Some(Journal::create(&path)?),
+            None => None,
+        };
+
+        let machine = self.summary_format != SummaryFormat::Text;
+        let output = ProgressOutput::new(total, self.quiet, machine);
+        for (i, project) in cfg.projects.iter().enumerate() {
+            let progress = Progress::new(&output, i + 1, total, &project.repo);
+            let mut outcomes = completed.take(i + 1, &project.repo);
+
+            let out_of_time = self
+                .max_duration
+                .map(|max| start.elapsed() >= max)
+                .unwrap_or(false);
+
+            if !outcomes.is_empty() {
+                progress.phase(Phase::Resume);
+            } else if out_of_time {
+                // Projects that weren't started aren't recorded in the journal so
+                // that they're run when continuing from it.
+                progress.phase(Phase::OutOfTime);
+                if let Some((pipeline, _)) = &pipeline {
+                    pipeline.stop();
+                }
+
+                report.add(ProjectOutcome::not_started(
+                    project.repo.clone(),
+                    "time budget",
+                ));
+                progress.done();
+                continue;
+            } else {
+                let prefetched = pipeline.as_mut().and_then(|(p, _)| p.take(i));
+                outcomes = self.run_project(project, &progress, &ctx, prefetched);
+            }
+
+            if interrupt::take_skip() {
+                // Like projects skipped for time, these aren't recorded in the journal
+                // so that they're run again when continuing from it.
+                progress.phase(Phase::Interrupted);
+                report.add(ProjectOutcome::not_started(
+                    project.repo.clone(),
+                    "interrupted by user",
+                ));
+                progress.done();
+                continue;
+            }
+
+            if let Some(journal) = journal.as_mut() {
+                journal.record(i + 1, &project.repo, &outcomes)?;
+            }
 
-            let patch = LocalOverride::new(root, crates);
-            patch.patch(&local_version, &local_path)?;
+            for outcome in outcomes {
+                report.add(outcome.with_tags(project.tags.clone()));
+            }
+
+            progress.done();
+        }
+
+        drop(pipeline);
+        output.finish();
+
+        let summary = match self.summary_format {
+            SummaryFormat::Text if ctx.cadences.len() > 1 => {
+                let labels: Vec<String> = ctx.cadences.iter().map(|c| c.label()).collect();
+                report
+                    .write_summary(io::stdout())
+                    .and_then(|_| report.write_matrix(io::stdout(), &labels))
+            }
+            SummaryFormat::Text => report.write_summary(io::stdout()),
+            SummaryFormat::Markdown => report.write_markdown(io::stdout()),
+            SummaryFormat::Csv => report.write_csv(io::stdout()),
+            SummaryFormat::Json => report.write_json(io::stdout()),
+        };
+        summary.map_err(|e| CraterError::new_err("unable to write summary", e))?;
+
+        if let Some(junit) = &self.junit {
+            fs::File::create(junit)
+                .and_then(|fd| report.write_junit(io::BufWriter::new(fd)))
+                .map_err(|e| {
+                    CraterError::new_err(format!("unable to write JUnit report to {:?}", junit), e)
+                })?;
+        }
+
+        if let Some(dir) = &self.output_dir {
+            report.write_dir(dir).map_err(|e| {
+                CraterError::new_err(format!("unable to write report to {:?}", dir), e)
+            })?;
+        }
+
+        if report.failures() > 0 {
+            return Err(CraterError::new(format!(
+                "{} of {} projects failed",
+                report.failures(),
+                report.projects().len()
+            )));
         }
 
         Ok(())
     }
 
-    fn destination(&self) -> Result<PathBuf, CraterError> {
-        let dest = self.destination.clone().unwrap_or_else(env::temp_dir);
+    fn run_project(
+        &self,
+        project: &RunProject,
+        progress: &Progress,
+        ctx: &RunContext,
+        prefetched: Option<Prefetched>,
+    ) -> Vec<ProjectOutcome> {
+        let mut timings = Timings::new();
+        let (source, prefetched) = match prefetched {
+            Some(p) => (p.source, Some(p.res)),
+            None => (self.fetch.source(project, &ctx.fetch), None),
+        };
+        let (project_root, fetched) = match timings.time("clone", || {
+            self.fetch_project(project, source.as_ref(), prefetched, progress, ctx)
+        }) {
+            Ok(p) => p,
+            Err(e) if source.exceeded_max_size().is_some() => {
+                progress.message(format!("skipping {}: {}", project.repo, e));
+                return vec![ProjectOutcome::not_started(
+                    project.repo.clone(),
+                    format!(
+                        "larger than --max-clone-size of {} bytes",
+                        source.exceeded_max_size().unwrap_or_default()
+                    ),
+                )
+                .with_timings(timings)];
+            }
+            Err(e) => {
+                return vec![ProjectOutcome::new(project.repo.clone(), Err(e)).with_timings(timings)]
+            }
+        };
 
-        fs::create_dir_all(&dest)
-            .map(|_| dest)
-            .and_then(|p| p.canonicalize())
-            .map_err(|e| CraterError::new_err("unable to determine repository destination", e))
+        if interrupt::skip_requested() {
+            return Vec::new();
+        }
+
+        let sha = vcs::head_sha(&project_root);
+        if let (Some(previous), Some(sha)) = (&ctx.previous, &sha) {
+            let expected = if self.no_patch { 1 } else { ctx.cadences.len() };
+            let carried = previous.outcomes(&project.repo, sha, expected);
+
+            if !carried.is_empty() {
+                progress.phase(Phase::Unchanged);
+                return carried
+                    .into_iter()
+                    .map(|o| o.with_fetched(fetched).with_timings(timings.clone()))
+                    .collect();
+            }
+        }
+
+        let mut warnings = Vec::new();
+        if self.filter_by_dependency {
+            let cargo_toml = project_root.join("Cargo.toml");
+            match depends_on(&cargo_toml, CADENCE_CRATE) {
+                Ok(Some(true)) => {}
+                // Without a Cargo.lock, transitive dependencies can't be checked
+                Ok(None) if project.transitive_only() => warnings.push(Warning::new(format!(
+                    "{} has no Cargo.lock to check its transitive dependency on Cadence in",
+                    project.repo
+                ))),
+                Ok(_) => {
+                    progress.message(format!(
+                        "skipping {}: it doesn't depend on Cadence",
+                        project.repo
+                    ));
+                    return vec![ProjectOutcome::not_started(
+                        project.repo.clone(),
+                        "doesn't depend on Cadence",
+                    )
+                    .with_fetched(fetched)
+                    .with_timings(timings)];
+                }
+                Err(e) => {
+                    return vec![ProjectOutcome::new(project.repo.clone(), Err(e))
+                        .with_fetched(fetched)
+                        .with_timings(timings)]
+                }
+            }
+        }
+
+        if let Some(reason) = source.missing_lfs() {
+            warnings.push(Warning::new(format!(
+                "{} stores files with Git LFS which weren't fetched ({}), builds using \
+                 them may fail",
+                project.repo, reason
+            )));
+        }
+
+        let usage = if self.analyze_usage {
+            usage::crate_usage(&project_root, CADENCE_CRATE).unwrap_or_else(|e| {
+                warnings.push(Warning::new(format!(
+                    "unable to analyze the usage of Cadence by {}: {}",
+                    project.repo, e
+                )));
+                BTreeMap::new()
+            })
+        } else {
+            BTreeMap::new()
+        };
+
+        let skipped = match self.excluded_packages(project, &project_root, &mut warnings) {
+            Ok(s) => s,
+            Err(e) => {
+                return vec![ProjectOutcome::new(project.repo.clone(), Err(e))
+                    .with_fetched(fetched)
+                    .with_timings(timings)]
+            }
+        };
+
+        let targets = match project.targets() {
+            Ok(t) => t,
+            Err(e) => {
+                return vec![ProjectOutcome::new(project.repo.clone(), Err(e))
+                    .with_fetched(fetched)
+                    .with_timings(timings)]
+            }
+        };
+
+        // Tests built for another target can't be run on this host, and projects that
+        // only have to build don't run them at all
+        let target = project.target.clone().or_else(|| self.target.clone());
+        let build_only = project.build_only() || (target.is_some() && target != ctx.host);
+
+        let mut runner = CargoRunner::new()
+            .with_target_triple(target.clone())
+            .with_build_only(build_only)
+            .with_retries(self.build_retries)
+            .with_deny_warnings(self.deny_warnings)
+            .with_jobs(self.build_jobs)
+            .with_exclude(skipped.clone())
+            .with_offline(self.offline)
+            .with_env(project.env.clone())
+            .with_targets(targets)
+            .with_test_command(project.test_command.clone());
+
+        if self.fast_profile {
+            match fast_profile_env(&runner, &project_root, &project.env) {
+                Ok((env, kept)) => {
+                    if !kept.is_empty() {
+                        progress.message(format!(
+                            "keeping {} from the profiles of {}",
+                            kept.join(", "),
+                            project.repo
+                        ));
+                    }
+
+                    runner = runner.with_env(env);
+                }
+                Err(e) => {
+                    return vec![ProjectOutcome::new(project.repo.clone(), Err(e))
+                        .with_fetched(fetched)
+                        .with_timings(timings)]
+                }
+            }
+        }
+
+        let baseline = if self.baseline && self.patch_suffix.is_none() {
+            progress.phase(Phase::Baseline);
+            Some(timings.time("baseline", || {
+                run_pre_build(project, &project_root)
+                    .and_then(|_| self.update_deps(project, &project_root, &runner, progress))
+                    .and_then(|_| build_project(project, &project_root, &runner, progress))
+                    .and_then(|_| runner.test(&project_root))
+                    .map_err(|e| e.to_string())
+            }))
+        } else {
+            None
+        };
+
+        let mut outcomes = Vec::with_capacity(ctx.cadences.len());
+        // Contents of each file before it was first patched, to diff against
+        let mut originals: HashMap<PathBuf, String> = HashMap::new();
+
+        // Each Cadence version overwrites the patch made for the previous one so the
+        // project only needs to be fetched (and the baseline tested) once. Without
+        // patching, the project is built and tested once as it is.
+        let cadences: Vec<Option<&LocalCadence>> = if self.no_patch {
+            vec![None]
+        } else {
+            ctx.cadences.iter().map(Some).collect()
+        };
+
+        for cadence in cadences {
+            if interrupt::skip_requested() {
+                break;
+            }
+
+            let label = cadence
+                .filter(|_| ctx.cadences.len() > 1)
+                .map(LocalCadence::label);
+            let progress = match &label {
+                Some(label) => progress.with_cadence(label.clone()),
+                None => progress.clone(),
+            };
+
+            let lock = cadence.and_then(|cadence| {
+                ctx.locks
+                    .as_ref()
+                    .map(|dir| dir.join(lock_file_name(project, cadence)))
+            });
+            let locked = lock.as_deref().map(Path::is_file).unwrap_or(false);
+            let log = CargoLog::new();
+            let runner = runner.clone().with_locked(locked).with_log(log.clone());
+            let commands = if self.patch_suffix.is_none() {
+                runner.commands(&project_root)
+            } else {
+                Vec::new()
+            };
+
+            let mut cadence_timings = timings.clone();
+            let mut cadence_warnings = warnings.clone();
+            let mut patched_files = Vec::new();
+            let mut manifest_diff = String::new();
+            let patched = match cadence {
+                Some(cadence) => {
+                    progress.phase(Phase::Patch);
+                    cadence_timings.time("patch", || {
+                        self.patch_project(
+                            project,
+                            &project_root,
+                            cadence,
+                            ctx,
+                            &runner,
+                            &mut cadence_warnings,
+                        )
+                    })
+                }
+                None => Ok(Vec::new()),
+            };
+
+            let res = patched.and_then(|files| {
+                for file in files {
+                    let original = originals
+                        .entry(file.path().to_path_buf())
+                        .or_insert_with(|| file.original().to_owned());
+                    let file = file.with_original(original.clone());
+                    manifest_diff.push_str(&file.diff(&project_root));
+                    patched_files.push(file.path().to_path_buf());
+                }
+
+                self.build_and_test(
+                    project,
+                    &project_root,
+                    &runner,
+                    lock.as_deref(),
+                    &progress,
+                    &mut cadence_timings,
+                )
+            });
+
+            let res = match res {
+                Ok(_) if self.fail_on_warnings && !cadence_warnings.is_empty() => {
+                    Err(CraterError::new(format!(
+                        "{} warning(s) treated as errors",
+                        cadence_warnings.len()
+                    )))
+                }
+                res => res,
+            };
+
+            let mut outcome = ProjectOutcome::new(project.repo.clone(), res);
+            if let Some(label) = label {
+                outcome = outcome.with_cadence(label);
+            }
+
+            if let Some(b) = &baseline {
+                outcome = outcome.with_baseline(b.clone());
+            }
+
+            if let Some(sha) = &sha {
+                outcome = outcome.with_sha(sha.clone());
+            }
+
+            if let Some(target) = &target {
+                outcome = outcome.with_target(target.clone());
+            }
+
+            if let Some(success) = project.success {
+                outcome = outcome.with_criterion(success.to_string());
+            }
+
+            outcome = outcome
+                .with_skipped(skipped.clone())
+                .with_warnings(cadence_warnings)
+                .with_commands(commands)
+                .with_patched_files(patched_files)
+                .with_manifest_diff(manifest_diff)
+                .with_cargo_log(log.contents())
+                .with_fetched(fetched)
+                .with_usage(usage.clone())
+                .with_timings(cadence_timings);
+
+            outcomes.push(outcome);
+        }
+
+        outcomes
     }
 
-    fn config(&self) -> Result<RunConfig, CraterError> {
-        let mut buf = String::new();
+    /// Patch the project to use a local Cadence version and any other crates to patch,
+    /// returning the files written along with their contents before and after
+    fn patch_project(
+        &self,
+        project: &RunProject,
+        project_root: &Path,
+        cadence: &LocalCadence,
+        ctx: &RunContext,
+        runner: &CargoRunner,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Vec<PatchedFile>, CraterError> {
+        let crates = subproject_manifests(
+            project_root,
+            project.subprojects(),
+            self.strict_subprojects,
+            warnings,
+        )?;
+        let mut patch = LocalOverride::new(project_root.join("Cargo.toml"), crates)
+            .with_suffix(self.patch_suffix.clone())
+            .with_mode(self.override_mode)
+            .with_transitive_only(project.transitive_only())
+            .with_fsync(!self.no_fsync)
+            .with_discover_members(true);
+        if !self.sections.is_empty() {
+            patch = patch.with_sections(self.sections.clone());
+        }
 
-        let _ = fs::File::open(&self.config)
-            .and_then(|mut fd| fd.read_to_string(&mut buf))
-            .map_err(|e| {
+        let existing = if self.respect_existing_patch {
+            patch.existing_patches(CADENCE_CRATE)?
+        } else {
+            Vec::new()
+        };
+
+        let mut patches = Vec::with_capacity(ctx.extra_patches.len() + 1);
+        if existing.is_empty() {
+            patches.push(CratePatch::new(
+                CADENCE_CRATE,
+                cadence.version.clone(),
+                self.patch_source(project, cadence),
+            ));
+        } else {
+            warnings.push(Warning::new(format!(
+                "Cadence is already patched in [patch.{}] of {:?}, leaving it instead of \
+                 using Cadence {}",
+                existing.join("], [patch."),
+                project_root.join("Cargo.toml"),
+                cadence.label()
+            )));
+        }
+        patches.extend(ctx.extra_patches.iter().cloned());
+        let written = if patches.is_empty() {
+            Vec::new()
+        } else {
+            patch.patch_crates_files(&patches)?
+        };
+
+        // Verification compares against the path of the local checkout which
+        // isn't where Cadence comes from when using a git source or published version,
+        // against the original Cargo.toml when the patch was written elsewhere, and
+        // doesn't apply when the existing patch was kept.
+        let local = cadence.path.as_deref().filter(|_| {
+            self.verify_patch
+                && self.cadence_git.is_none()
+                && self.patch_suffix.is_none()
+                && existing.is_empty()
+        });
+
+        if let Some(path) = local {
+            warnings.extend(verify_patch(runner, project_root, path)?);
+        }
+
+        Ok(written)
+    }
+
+    /// Run any pre-build commands of the patched project then build and test it
+    ///
+    /// When given a saved Cargo.lock, it's restored before building if it exists or
+    /// the Cargo.lock of the project is saved to it after passing if it doesn't.
+    fn build_and_test(
+        &self,
+        project: &RunProject,
+        project_root: &Path,
+        runner: &CargoRunner,
+        lock: Option<&Path>,
+        progress: &Progress,
+        timings: &mut Timings,
+    ) -> Result<(), CraterError> {
+        if let Some(suffix) = &self.patch_suffix {
+            progress.message(format!(
+                "wrote patched Cargo.toml{} files of {}, skipping build and test",
+                suffix, project.repo
+            ));
+            return Ok(());
+        }
+
+        if !project.pre_build().is_empty() {
+            check_interrupt()?;
+            progress.phase(Phase::PreBuild);
+            timings.time("pre-build", || run_pre_build(project, project_root))?;
+        }
+
+        if self.update_deps {
+            progress.phase(Phase::Update);
+            timings.time("update", || {
+                self.update_deps(project, project_root, runner, progress)
+            })?;
+        }
+
+        let saved = lock.filter(|l| l.is_file());
+        if let Some(saved) = saved {
+            let lock_file = runner.lock_file(project_root)?;
+            fs::copy(saved, &lock_file).map_err(|e| {
                 CraterError::new_err(
-                    format!("unable to open configuration from {:?}", self.config),
+                    format!("unable to restore {:?} from {:?}", lock_file, saved),
                     e,
                 )
             })?;
+            progress.message(format!("restored saved Cargo.lock of {}", project.repo));
+        }
 
-        toml::from_str(&buf).map_err(|e| {
-            CraterError::new_err(
-                format!("unable to parse configuration from {:?}", self.config),
-                e,
-            )
-        })
-    }
-}
+        for command in runner.commands(project_root) {
+            progress.message(format!("running {}", command));
+        }
 
-#[derive(Deserialize, Debug)]
-struct RunConfig {
-    projects: Vec<RunProject>,
+        check_interrupt()?;
+        progress.phase(Phase::Build);
+        timings.time("build", || build_project(project, project_root, runner, progress))?;
+        if project.build_only() {
+            progress.message(format!(
+                "only built {}, its success criterion is `build`",
+                project.repo
+            ));
+        } else if runner.is_build_only() {
+            progress.message(format!(
+                "only built {}, its tests can't run on this host",
+                project.repo
+            ));
+        } else {
+            check_interrupt()?;
+            progress.phase(Phase::Test);
+            timings.time("test", || runner.test(project_root))?;
+        }
+
+        if let Some(lock) = lock.filter(|_| saved.is_none()) {
+            let lock_file = runner.lock_file(project_root)?;
+            fs::copy(&lock_file, lock).map_err(|e| {
+                CraterError::new_err(format!("unable to save {:?} to {:?}", lock_file, lock), e)
+            })?;
+            progress.message(format!("saved Cargo.lock of {}", project.repo));
+        }
+
+        Ok(())
+    }
+
+    /// Update the dependencies of the project if `--update-deps` is set, reporting
+    /// whether its `Cargo.lock` changed
+    fn update_deps(
+        &self,
+        project: &RunProject,
+        project_root: &Path,
+        runner: &CargoRunner,
+        progress: &Progress,
+    ) -> Result<(), CraterError> {
+        if !self.update_deps {
+            return Ok(());
+        }
+
+        if runner.update(project_root)? {
+            progress.message(format!("updated Cargo.lock of {}", project.repo));
+        } else {
+            progress.message(format!("Cargo.lock of {} already up to date", project.repo));
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the project and return the path to its root and how it was fetched,
+    /// checking that it can be patched
+    fn fetch_project(
+        &self,
+        project: &RunProject,
+        source: &dyn Source,
+        prefetched: Option<Result<Fetched, CraterError>>,
+        progress: &Progress,
+        ctx: &RunContext,
+    ) -> Result<(PathBuf, FetchKind), CraterError> {
+        let downloads = ctx.fetch.downloads_for(project);
+        let fetched = match prefetched {
+            // Already fetched by the clone stage of the pipeline
+            Some(res) => {
+                progress.phase(Phase::Clone);
+                res?
+            }
+            None if self.run_built() => {
+                progress.phase(Phase::Fetched);
+                source.find_fetched(downloads)?
+            }
+            None => {
+                progress.phase(Phase::Clone);
+                source.fetch(downloads)?
+            }
+        };
+
+        report_fetched(project, source, &fetched, progress);
+        let kind = fetched.kind();
+        let repo = fetched.into_path();
+
+        if let Ok(canonical) = repo.canonicalize() {
+            let inside = ctx
+                .cadences
+                .iter()
+                .filter_map(|c| c.path.as_deref())
+                .find(|p| Path::new(p).starts_with(&canonical));
+
+            if let Some(path) = inside {
+                return Err(CraterError::new(format!(
+                    "local Cadence path {} is inside the checkout of {} at {:?}, use a \
+                     Cadence checkout outside of the project",
+                    path, project.repo, repo
+                )));
+            }
+        }
+
+        let project_root = repo.join(project.root());
+        if !project_root.join("Cargo.toml").is_file() {
+            return Err(CraterError::new(format!(
+                "project root '{}' not found in repo {}",
+                project.root(),
+                project.repo
+            )));
+        }
+
+        Ok((project_root, kind))
+    }
+
+    /// Get the package names of the `exclude`d workspace members of a project
+    fn excluded_packages(
+        &self,
+        project: &RunProject,
+        project_root: &Path,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Vec<String>, CraterError> {
+        subproject_manifests(
+            project_root,
+            project.exclude(),
+            self.strict_subprojects,
+            warnings,
+        )?
+        .iter()
+        .map(|manifest| package_name(manifest))
+        .collect()
+    }
+
+    fn patch_source(&self, project: &RunProject, cadence: &LocalCadence) -> PatchSource {
+        if cadence.path.is_none() {
+            return PatchSource::Registry;
+        }
+
+        match &self.cadence_git {
+            Some(url) => PatchSource::Git {
+                url: url.clone(),
+                branch: project
+                    .cadence_branch
+                    .clone()
+                    .or_else(|| self.cadence_branch.clone()),
+            },
+            None => PatchSource::Path(cadence.path.clone().unwrap_or_default()),
+        }
+    }
+
+    /// Path of the local Cadence checkout given as an argument, if one was given
+    ///
+    /// When the checkout is given with `--cadence` or isn't needed, such as when
+    /// testing published versions, the only positional argument is the configuration
+    /// unless it's given with `--config` or `--config-dir`.
+    fn cadence_path(&self) -> Option<&Path> {
+        let elsewhere =
+            self.no_patch || !self.cadence_version.is_empty() || !self.extra_cadence.is_empty();
+
+        match (&self.cadence, &self.config) {
+            (Some(_), None)
+                if elsewhere && self.config_flag.is_none() && self.configs.config_dir.is_none() =>
+            {
+                None
+            }
+            (cadence, _) => cadence.as_deref(),
+        }
+    }
+
+    fn cadences(&self) -> Result<Vec<LocalCadence>, CraterError> {
+        if self.no_patch {
+            let conflicts = [
+                ("--baseline", self.baseline),
+                ("--patch-suffix", self.patch_suffix.is_some()),
+                ("--cadence", !self.extra_cadence.is_empty()),
+                ("--cadence-version", !self.cadence_version.is_empty()),
+                ("--frozen-lock", self.frozen_lock),
+            ];
+
+            return match conflicts.iter().find(|(_, set)| *set) {
+                Some((flag, _)) => Err(CraterError::new(format!(
+                    "--no-patch can't be used along with {}",
+                    flag
+                ))),
+                None => Ok(Vec::new()),
+            };
+        }
+
+        let mut cadences = self
+            .cadence_path()
+            .into_iter()
+            .chain(self.extra_cadence.iter().map(|p| p.as_path()))
+            .map(|p| {
+                let local = LocalVersion::new(p);
+                let path = local.path()?;
+                Ok(LocalCadence {
+                    version: local.version()?,
+                    sha: vcs::head_sha(Path::new(&path)),
+                    path: Some(path),
+                })
+            })
+            .collect::<Result<Vec<LocalCadence>, CraterError>>()?;
+
+        cadences.extend(self.cadence_version.iter().map(|version| LocalCadence {
+            version: version.clone(),
+            path: None,
+            sha: None,
+        }));
+
+        Ok(cadences)
+    }
+
+    /// Check that each local Cadence checkout builds on its own
+    fn check_cadences(&self, cadences: &[LocalCadence]) -> Result<(), CraterError> {
+        let runner = CargoRunner::new()
+            .with_jobs(self.build_jobs)
+            .with_offline(self.offline);
+
+        for path in cadences.iter().filter_map(|c| c.path.as_deref()) {
+            runner.build_crate(Path::new(path)).map_err(|e| {
+                CraterError::new_err(
+                    format!(
+                        "local Cadence at {} doesn't build, fix it before testing projects \
+                         with it",
+                        path
+                    ),
+                    e,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Print the plan for fetching each project without fetching or creating anything
+    fn plan(&self, cfg: &RunConfig) -> Result<(), CraterError> {
+        let cwd = env::current_dir()
+            .map_err(|e| CraterError::new_err("unable to determine repository destination", e))?;
+        let dest = cwd.join(self.fetch.destination.clone().unwrap_or_else(env::temp_dir));
+
+        write_plan(
+            io::stdout(),
+            &cfg.projects,
+            &cwd,
+            &dest,
+            self.fetch.mirror_base.as_deref(),
+        )
+        .map_err(|e| CraterError::new_err("unable to write plan", e))
+    }
+
+    /// Directory to save the Cargo.lock of each project in when `--frozen-lock` is set
+    fn lock_dir(&self, cache: Option<&Path>) -> Result<Option<PathBuf>, CraterError> {
+        if !self.frozen_lock {
+            return Ok(None);
+        }
+
+        if self.update_deps {
+            return Err(CraterError::new(
+                "--frozen-lock can't be used along with --update-deps",
+            ));
+        }
+
+        let base = match cache
+            .map(Path::to_path_buf)
+            .or_else(|| self.output_dir.clone())
+        {
+            Some(b) => b,
+            None => {
+                return Err(CraterError::new(
+                    "--frozen-lock requires a cache directory or --output-dir to save locks in",
+                ))
+            }
+        };
+
+        env::current_dir()
+            .and_then(|cwd| create_dir(&cwd, &base.join(LOCKS_DIR_NAME)))
+            .map(Some)
+            .map_err(|e| CraterError::new_err("unable to create Cargo.lock directory", e))
+    }
+
+    fn config_path(&self) -> Result<&Path, CraterError> {
+        if let Some(path) = &self.config_flag {
+            return match &self.config {
+                Some(_) => Err(CraterError::new(
+                    "a configuration path can't be given both with --config and as an argument",
+                )),
+                None => Ok(path),
+            };
+        }
+
+        self.config
+            .as_deref()
+            .or_else(|| {
+                self.cadence
+                    .as_deref()
+                    .filter(|_| self.cadence_path().is_none())
+            })
+            .ok_or_else(|| CraterError::new("no configuration path given"))
+    }
+
+    /// Load the configuration, or every configuration in `--config-dir`, keeping only
+    /// the projects selected by `--tag`
+    fn config(&self) -> Result<RunConfig, CraterError> {
+        match &self.configs.config_dir {
+            Some(_) if self.config.is_some() || self.config_flag.is_some() => Err(
+                CraterError::new("a configuration path can't be given along with --config-dir"),
+            ),
+            Some(_) => self.configs.load(None),
+            None => self.configs.load(Some(self.config_path()?)),
+        }
+    }
+}
+
+/// Load a single configuration, filling in settings not given by each project
+fn load_config(path: &Path, format: ConfigFormat) -> Result<RunConfig, CraterError> {
+    let mut cfg = read_config(path, format)?;
+    cfg.apply_defaults();
+
+    let problems = cfg.validate();
+    if !problems.is_empty() {
+        return Err(CraterError::new(format!(
+            "invalid configuration {:?}: {}",
+            path,
+            problems.join(", ")
+        )));
+    }
+
+    Ok(cfg)
+}
+
+/// Write a configuration to a file in the given format
+fn write_config(path: &Path, cfg: &RunConfig, format: ConfigFormat) -> Result<(), CraterError> {
+    let out = match format {
+        ConfigFormat::Toml => toml::to_string(cfg)
+            .map_err(|e| CraterError::new_err("unable to serialize configuration", e))?,
+        ConfigFormat::Json => serde_json::to_string_pretty(cfg)
+            .map(|json| json + "\n")
+            .map_err(|e| CraterError::new_err("unable to serialize configuration", e))?,
+    };
+
+    fs::write(path, out).map_err(|e| {
+        CraterError::new_err(format!("unable to write configuration to {:?}", path), e)
+    })
+}
+
+/// Get every `.toml` configuration in a directory, in order of file name
+fn toml_files(dir: &Path) -> Result<Vec<PathBuf>, CraterError> {
+    let mut paths = fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|e| e.map(|e| e.path()))
+                .collect::<io::Result<Vec<PathBuf>>>()
+        })
+        .map_err(|e| {
+            CraterError::new_err(format!("unable to read configurations from {:?}", dir), e)
+        })?;
+
+    paths.retain(|p| p.is_file() && p.extension().map(|e| e == "toml").unwrap_or(false));
+    paths.sort();
+
+    if paths.is_empty() {
+        return Err(CraterError::new(format!(
+            "no .toml configurations found in {:?}",
+            dir
+        )));
+    }
+
+    Ok(paths)
+}
+
+/// Determine the format of a configuration from its file extension, defaulting to TOML
+fn format_for_path(path: &Path) -> ConfigFormat {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => ConfigFormat::Json,
+        _ => ConfigFormat::Toml,
+    }
+}
+
+/// Read and parse a single configuration, or read it from stdin if the path is `-`,
+/// without filling in defaults or validating it
+fn read_config(path: &Path, format: ConfigFormat) -> Result<RunConfig, CraterError> {
+    let mut buf = String::new();
+
+    if path == Path::new(STDIN_CONFIG) {
+        let _ = io::stdin()
+            .read_to_string(&mut buf)
+            .map_err(|e| CraterError::new_err("unable to read configuration from stdin", e))?;
+    } else {
+        let _ = fs::File::open(path)
+            .and_then(|mut fd| fd.read_to_string(&mut buf))
+            .map_err(|e| {
+                CraterError::new_err(format!("unable to open configuration from {:?}", path), e)
+            })?;
+    }
+
+    match format {
+        ConfigFormat::Toml => toml::from_str(&buf).map_err(|e| {
+            CraterError::new_err(format!("unable to parse configuration from {:?}", path), e)
+        }),
+        ConfigFormat::Json => serde_json::from_str(&buf).map_err(|e| {
+            CraterError::new_err(format!("unable to parse configuration from {:?}", path), e)
+        }),
+    }
+}
+
+/// Settings resolved once at the start of a run and shared by every project
+#[derive(Debug)]
+struct RunContext {
+    /// Target triple of the host, only determined when building for other targets
+    host: Option<String>,
+    cadences: Vec<LocalCadence>,
+    extra_patches: Vec<CratePatch>,
+    fetch: FetchContext,
+    locks: Option<PathBuf>,
+    previous: Option<PreviousRun>,
+}
+
+/// Report where a project was fetched from, when it's not the configured `repo`,
+/// and whether an existing clone or the cache was used
+fn report_fetched(
+    project: &RunProject,
+    source: &dyn Source,
+    fetched: &Fetched,
+    progress: &Progress,
+) {
+    if let Some(url) = source.cloned_from() {
+        if url != project.repo {
+            progress.message(format!("cloned {} from mirror {}", project.repo, url));
+        }
+    }
+
+    match fetched.kind() {
+        FetchKind::Reused => progress.message(format!(
+            "reused an existing clone of {} at {:?}",
+            project.repo,
+            fetched.path()
+        )),
+        FetchKind::CacheHit => progress.message(format!("copied {} from the cache", project.repo)),
+        _ => {}
+    }
+}
+
+/// Warn if the project doesn't use the local Cadence checkout after being patched
+///
+/// Patching can succeed but have no effect if, for example, the version required
+/// excludes the local version or the project doesn't depend on Cadence at all.
+fn verify_patch(
+    runner: &CargoRunner,
+    project_root: &Path,
+    path: &str,
+) -> Result<Option<Warning>, CraterError> {
+    let local = Path::new(path).join("Cargo.toml");
+    let manifests = runner.package_manifests(project_root, "cadence")?;
+
+    if manifests.is_empty() {
+        Ok(Some(Warning::new(format!(
+            "{:?} does not depend on Cadence, the patch has no effect",
+            project_root
+        ))))
+    } else if !manifests.contains(&local) {
+        Ok(Some(Warning::new(format!(
+            "{:?} uses Cadence from {:?} instead of the local version at {}",
+            project_root, manifests, path
+        ))))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Run each of the pre-build commands of a project in its root, stopping at the first failure
+fn run_pre_build(project: &RunProject, project_root: &Path) -> Result<(), CraterError> {
+    for command in project.pre_build() {
+        runner::run_shell(project_root, command, &project.env)?;
+    }
+
+    Ok(())
+}
+
+/// Resolve subproject paths or glob patterns to the Cargo.toml of each subproject
+///
+/// Glob patterns (like `crates/*`) are expanded relative to the project root and
+/// only directories containing a Cargo.toml are kept. A pattern that matches nothing
+/// is treated like a missing subproject. Each Cargo.toml is only included once, even if it's matched
+/// by multiple patterns.
+///
+/// Subprojects that don't exist (or patterns that don't match anything) are an
+/// error when `strict` is set, otherwise a warning is added to `warnings` and they
+/// are skipped so that the remaining subprojects can still be patched.
+fn subproject_manifests(
+    project_root: &Path,
+    subprojects: &[String],
+    strict: bool,
+    warnings: &mut Vec<Warning>,
+) -> Result<Vec<PathBuf>, CraterError> {
+    let mut out = Vec::new();
+    let mut seen = HashSet::new();
+
+    for subproject in subprojects {
+        let res = if is_glob(subproject) {
+            expand_glob(project_root, subproject)
+        } else {
+            let manifest = project_root.join(subproject).join("Cargo.toml");
+            if manifest.is_file() {
+                Ok(vec![manifest])
+            } else {
+                Err(CraterError::new(format!(
+                    "subproject '{}' not found in {:?}",
+                    subproject, project_root
+                )))
+            }
+        };
+
+        let manifests = match res {
+            Ok(m) => m,
+            Err(e) if strict => return Err(e),
+            Err(e) => {
+                warnings.push(Warning::new(format!("{}, skipping it", e)));
+                continue;
+            }
+        };
+
+        for manifest in manifests {
+            if seen.insert(manifest.clone()) {
+                out.push(manifest);
+            }
+        }
+    }
+
+    Ok(out)
 }
 
-#[derive(Deserialize, Debug)]
+/// Returns true if the subproject contains glob pattern characters
+fn is_glob(subproject: &str) -> bool {
+    subproject.contains(['*', '?', '['])
+}
+
+/// Find the Cargo.toml of each directory matching the pattern under the project root
+fn expand_glob(project_root: &Path, pattern: &str) -> Result<Vec<PathBuf>, CraterError> {
+    let root = project_root.to_str().ok_or_else(|| {
+        CraterError::new(format!(
+            "unable to expand subproject pattern '{}' in non-UTF-8 path {:?}",
+            pattern, project_root
+        ))
+    })?;
+
+    let full = format!("{}/{}", glob::Pattern::escape(root), pattern);
+    let paths = glob::glob(&full).map_err(|e| {
+        CraterError::new_err(format!("invalid subproject pattern '{}'", pattern), e)
+    })?;
+
+    let mut manifests = Vec::new();
+    for path in paths {
+        let path = path.map_err(|e| {
+            CraterError::new_err(
+                format!("unable to expand subproject pattern '{}'", pattern),
+                e,
+            )
+        })?;
+
+        let manifest = path.join("Cargo.toml");
+        if manifest.is_file() {
+            manifests.push(manifest);
+        }
+    }
+
+    if manifests.is_empty() {
+        return Err(CraterError::new(format!(
+            "subproject pattern '{}' did not match any crates in {:?}",
+            pattern, project_root
+        )));
+    }
+
+    Ok(manifests)
+}
+
+/// Version and canonical path of a local Cadence checkout to test projects with
+#[derive(Debug)]
+struct LocalCadence {
+    version: String,
+    /// Canonical path of the local checkout, `None` for a version published to crates.io
+    path: Option<String>,
+    sha: Option<String>,
+}
+
+impl LocalCadence {
+    /// Name for this checkout that can distinguish it from others in the same run
+    fn label(&self) -> String {
+        match &self.path {
+            Some(path) => format!("{} ({})", self.version, path),
+            None => format!("{} (crates.io)", self.version),
+        }
+    }
+}
+
+/// Create the destination of every project that sets its own, returning their
+/// canonical paths keyed by the destination in the configuration
+///
+/// This is done once before any project is run so that projects sharing a
+/// destination don't race to create it.
+fn project_destinations(cfg: &RunConfig) -> Result<HashMap<PathBuf, PathBuf>, CraterError> {
+    let cwd = env::current_dir()
+        .map_err(|e| CraterError::new_err("unable to determine repository destination", e))?;
+    let mut dests = HashMap::new();
+
+    for project in cfg.projects.iter() {
+        let dest = match &project.dest {
+            Some(dest) if !dests.contains_key(dest) => dest,
+            _ => continue,
+        };
+
+        let full = create_dir(&cwd, dest).map_err(|e| {
+            CraterError::new_err(
+                format!(
+                    "unable to create destination {:?} for {}",
+                    dest, project.repo
+                ),
+                e,
+            )
+        })?;
+        dests.insert(dest.clone(), full);
+    }
+
+    Ok(dests)
+}
+
+/// Create `dir` and any missing parents, returning its canonical path
+///
+/// Relative paths are resolved against `base` before creating them so that the
+/// result doesn't depend on which parts of the path already exist.
+fn create_dir(base: &Path, dir: &Path) -> io::Result<PathBuf> {
+    let full = base.join(dir);
+    fs::create_dir_all(&full)?;
+    full.canonicalize()
+}
+
+/// Write the plan for fetching each project: where it's cloned (or copied) from and
+/// into, the revision checked out, and the subprojects patched
+///
+/// Anything that can only be determined after cloning, such as the subprojects
+/// matched by a glob pattern, is labeled as requiring a clone.
+fn write_plan<W: Write>(
+    mut out: W,
+    projects: &[RunProject],
+    cwd: &Path,
+    dest: &Path,
+    mirror_base: Option<&str>,
+) -> io::Result<()> {
+    let total = projects.len();
+
+    for (i, project) in projects.iter().enumerate() {
+        writeln!(out, "[{}/{}] {}", i + 1, total, project.repo)?;
+
+        let into = project
+            .dest
+            .as_ref()
+            .map(|d| cwd.join(d))
+            .unwrap_or_else(|| dest.to_path_buf());
+
+        match LocalDirSource::from_repo(&project.repo, project.in_place()) {
+            Some(local) if project.in_place() => {
+                writeln!(out, "         patch in place: {}", local.path().display())?;
+            }
+            Some(local) => {
+                let name = local.path().file_name().unwrap_or_default();
+                writeln!(out, "         copy from: {}", local.path().display())?;
+                writeln!(out, "         into: {}", into.join(name).display())?;
+            }
+            None if ArchiveSource::from_repo(&project.repo).is_some() => {
+                writeln!(out, "         download from: {}", project.repo)?;
+                match vcs::repo_dir_name(&project.repo) {
+                    Ok(name) => writeln!(out, "         into: {}", into.join(name).display())?,
+                    Err(e) => writeln!(out, "         into: {}", e)?,
+                }
+
+                if let Some(sha256) = &project.expected_sha256 {
+                    writeln!(out, "         sha256: {}", sha256)?;
+                }
+            }
+            None => {
+                let mirror = mirror_base.and_then(|b| vcs::mirror_url(b, &project.repo));
+                match mirror {
+                    Some(url) => writeln!(
+                        out,
+                        "         clone from: {} (falling back to {})",
+                        url, project.repo
+                    )?,
+                    None => writeln!(out, "         clone from: {}", project.repo)?,
+                }
+
+                match vcs::repo_dir_name(&project.repo) {
+                    Ok(name) => writeln!(out, "         into: {}", into.join(name).display())?,
+                    Err(e) => writeln!(out, "         into: {}", e)?,
+                }
+
+                writeln!(
+                    out,
+                    "         rev: {}{}",
+                    project.rev.as_deref().unwrap_or("default branch"),
+                    if project.single_branch.unwrap_or(false) {
+                        " (single branch)"
+                    } else {
+                        ""
+                    }
+                )?;
+            }
+        }
+
+        if !project.root().is_empty() {
+            writeln!(out, "         root: {}", project.root())?;
+        }
+
+        if !project.tags.is_empty() {
+            writeln!(out, "         tags: {}", project.tags.join(", "))?;
+        }
+
+        if let Some(success) = project.success {
+            writeln!(out, "         success: {}", success)?;
+        }
+
+        if !project.subprojects().is_empty() {
+            let subprojects: Vec<String> = project
+                .subprojects()
+                .iter()
+                .map(|s| {
+                    if is_glob(s) {
+                        format!("{} (requires clone)", s)
+                    } else {
+                        s.clone()
+                    }
+                })
+                .collect();
+
+            writeln!(out, "         subprojects: {}", subprojects.join(", "))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the project, reporting each build that's retried after failing without any
+/// compiler errors
+fn build_project(
+    project: &RunProject,
+    project_root: &Path,
+    runner: &CargoRunner,
+    progress: &Progress,
+) -> Result<(), CraterError> {
+    runner.build_retrying(project_root, |retry, e| {
+        // Only the first line since the error can include the output of cargo
+        let err = e.to_string();
+        progress.message(format!(
+            "retrying build of {} ({} of {}) after a failure without compiler errors: {}",
+            project.repo,
+            retry,
+            runner.retries(),
+            err.lines().next().unwrap_or_default()
+        ));
+    })
+}
+
+/// Expand a leading `~` in a path given on the command line to the home directory
+///
+/// Shells usually do this already, but not when the path is part of an argument like
+/// `--dest=~/work` or is quoted. Paths starting with `~user` are used as-is.
+fn expand_home(arg: &OsStr) -> PathBuf {
+    let path = Path::new(arg);
+    let home = env::var_os("HOME").filter(|h| !h.is_empty());
+
+    match (path.strip_prefix("~"), home) {
+        (Ok(rest), Some(home)) if rest.as_os_str().is_empty() => PathBuf::from(home),
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Parse a duration given on the command line as a whole number of seconds (`s`),
+/// minutes (`m`), or hours (`h`), defaulting to seconds without a unit
+fn parse_duration(arg: &str) -> Result<Duration, CraterError> {
+    let arg = arg.trim();
+    let (num, multiplier) = match arg.char_indices().last() {
+        Some((i, 's')) => (&arg[..i], 1),
+        Some((i, 'm')) => (&arg[..i], 60),
+        Some((i, 'h')) => (&arg[..i], 60 * 60),
+        _ => (arg, 1),
+    };
+
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| CraterError::new(format!("invalid duration {:?}", arg)))
+}
+
+/// Parse a size given on the command line as a whole number of kibibytes (`K`),
+/// mebibytes (`M`), or gibibytes (`G`), defaulting to bytes without a unit
+fn parse_size(arg: &str) -> Result<u64, CraterError> {
+    let arg = arg.trim();
+    let (num, multiplier) = match arg.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&arg[..i], 1024),
+        Some((i, 'm')) | Some((i, 'M')) => (&arg[..i], 1024 * 1024),
+        Some((i, 'g')) | Some((i, 'G')) => (&arg[..i], 1024 * 1024 * 1024),
+        _ => (arg, 1),
+    };
+
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| CraterError::new(format!("invalid size {:?}", arg)))
+}
+
+/// Profiles changed by `--fast-profile`
+const FAST_PROFILES: &[&str] = &["dev", "test"];
+
+/// Settings changed by `--fast-profile`, by their Cargo.toml key, and their values
+const FAST_PROFILE_SETTINGS: &[(&str, &str)] = &[("opt-level", "0"), ("debug", "false")];
+
+/// Add the `CARGO_PROFILE_*` environment variables used by `--fast-profile` to the
+/// environment of a project, returning it along with the settings the project sets
+/// itself, which are left alone
+///
+/// A setting is left alone for every profile if the project sets it for either the
+/// dev or test profile since the test profile inherits from the dev profile. Variables
+/// already set by the project or the environment of crater take precedence too.
+fn fast_profile_env(
+    runner: &CargoRunner,
+    project_root: &Path,
+    project_env: &BTreeMap<String, String>,
+) -> Result<(BTreeMap<String, String>, Vec<String>), CraterError> {
+    let manifest = runner.workspace_root(project_root)?.join("Cargo.toml");
+    let mut own = HashSet::new();
+    for profile in FAST_PROFILES {
+        own.extend(profile_settings(&manifest, profile)?);
+    }
+
+    let mut env = project_env.clone();
+    let mut kept = Vec::new();
+    for (key, value) in FAST_PROFILE_SETTINGS {
+        if own.contains(*key) {
+            kept.push((*key).to_owned());
+            continue;
+        }
+
+        for profile in FAST_PROFILES {
+            let name = format!(
+                "CARGO_PROFILE_{}_{}",
+                profile.to_uppercase(),
+                key.to_uppercase().replace('-', "_")
+            );
+
+            if env::var_os(&name).is_none() {
+                env.entry(name).or_insert_with(|| (*value).to_owned());
+            }
+        }
+    }
+
+    Ok((env, kept))
+}
+
+/// Return an error if skipping the current project was requested so that it stops
+/// before starting another step
+fn check_interrupt() -> Result<(), CraterError> {
+    if interrupt::skip_requested() {
+        Err(CraterError::new("interrupted by user"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Returns true if the name can be set as an environment variable
+fn valid_env_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('=') && !name.contains('\0')
+}
+
+/// Name of the file the Cargo.lock of a project patched to use the given Cadence is
+/// saved as by `--frozen-lock`
+///
+/// Locks are saved separately for each revision, root, and Cadence version tested
+/// since each of them resolves to a different dependency graph.
+fn lock_file_name(project: &RunProject, cadence: &LocalCadence) -> String {
+    let hash = vcs::stable_hash(&[
+        project.repo.as_str(),
+        project.rev.as_deref().unwrap_or_default(),
+        project.root(),
+        &cadence.label(),
+    ]);
+
+    let name = vcs::repo_dir_name(&project.repo).unwrap_or_else(|_| "project".to_owned());
+    format!("{}-{:016x}.lock", name, hash)
+}
+
+/// Determine the per-user cache directory for cloned repositories, if any
+fn default_cache_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .map(|p| p.join("cadence-crater"))
+}
+
+/// Create the given cache directory, or the per-user one if none is given, returning
+/// its canonical path or `None` if there's no per-user cache directory
+fn open_cache_dir(dir: Option<PathBuf>) -> Result<Option<PathBuf>, CraterError> {
+    let cache = match dir.or_else(default_cache_dir) {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    env::current_dir()
+        .and_then(|cwd| create_dir(&cwd, &cache))
+        .map(Some)
+        .map_err(|e| CraterError::new_err("unable to create repository cache directory", e))
+}
+
+/// Directory under the cache or output directory that Cargo.lock files are saved in
+const LOCKS_DIR_NAME: &str = "locks";
+
+/// Path given for the configuration to indicate it should be read from stdin
+const STDIN_CONFIG: &str = "-";
+
+/// Serialization format of the run configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// Format of the summary written at the end of a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum SummaryFormat {
+    Text,
+    Markdown,
+    Csv,
+    Json,
+}
+
+/// When to use colors in terminal output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Returns true if output written to stdout should be colored
+    fn enabled(&self) -> bool {
+        match self {
+            ColorChoice::Auto => {
+                io::stdout().is_terminal()
+                    && env::var_os("NO_COLOR").filter(|v| !v.is_empty()).is_none()
+            }
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Step of processing a single project, reported as part of progress output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Resume,
+    Unchanged,
+    OutOfTime,
+    Interrupted,
+    Clone,
+    Fetched,
+    Baseline,
+    Patch,
+    PreBuild,
+    Update,
+    Build,
+    Test,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Resume => write!(f, "skipping already completed"),
+            Phase::Unchanged => write!(f, "unchanged since it last passed, skipping"),
+            Phase::OutOfTime => write!(f, "time budget used up, skipping"),
+            Phase::Interrupted => write!(f, "interrupted, skipping"),
+            Phase::Clone => write!(f, "cloning"),
+            Phase::Fetched => write!(f, "using the fetched checkout of"),
+            Phase::Baseline => write!(f, "testing baseline of"),
+            Phase::Patch => write!(f, "patching"),
+            Phase::PreBuild => write!(f, "running pre-build commands for"),
+            Phase::Update => write!(f, "updating dependencies of"),
+            Phase::Build => write!(f, "building"),
+            Phase::Test => write!(f, "testing"),
+        }
+    }
+}
+
+/// Concise per-project heartbeat like `[3/20] patching https://github.com/foo/bar.git`
+#[derive(Debug, Clone)]
+struct Progress<'a> {
+    output: &'a ProgressOutput,
+    bar: Option<ProgressBar>,
+    current: usize,
+    total: usize,
+    repo: &'a str,
+    cadence: Option<String>,
+}
+
+impl<'a> Progress<'a> {
+    fn new(output: &'a ProgressOutput, current: usize, total: usize, repo: &'a str) -> Self {
+        Progress {
+            output,
+            bar: output.project_bar(current, total),
+            current,
+            total,
+            repo,
+            cadence: None,
+        }
+    }
+
+    /// Include the Cadence version being tested in each progress line
+    fn with_cadence(&self, cadence: String) -> Self {
+        Progress {
+            cadence: Some(cadence),
+            ..self.clone()
+        }
+    }
+
+    fn phase(&self, phase: Phase) {
+        let status = match &self.cadence {
+            Some(c) => format!("{} {} with Cadence {}", phase, self.repo, c),
+            None => format!("{} {}", phase, self.repo),
+        };
+
+        self.output
+            .status(self.bar.as_ref(), self.current, self.total, status);
+    }
+
+    /// Report something about the project that should remain visible after the run
+    fn message(&self, message: String) {
+        self.output.message(message);
+    }
+
+    /// Mark the project as completed, removing its bar
+    fn done(&self) {
+        self.output.project_done(self.bar.as_ref());
+    }
+}
+
+/// Template of the overall progress bar shown when its output is a terminal
+const PROGRESS_TEMPLATE: &str = "[{elapsed_precise}] {bar:30.green} {pos}/{len} projects";
+
+/// Template of the bar showing the current phase of each project being run
+const PROJECT_TEMPLATE: &str = "{spinner:.green} {prefix:.cyan} {wide_msg}";
+
+/// Where progress of a run is shown: an overall progress bar with a bar below it for
+/// each project being run when the output is a terminal, a line for each phase
+/// otherwise, or nothing when quiet
+///
+/// Progress is shown on stdout unless the summary is written to stdout in a format
+/// meant for other programs, in which case it's shown on stderr.
+#[derive(Debug)]
+enum ProgressOutput {
+    Bars {
+        multi: MultiProgress,
+        overall: ProgressBar,
+    },
+    Lines {
+        stderr: bool,
+    },
+    Quiet,
+}
+
+impl ProgressOutput {
+    fn new(total: usize, quiet: bool, stderr: bool) -> Self {
+        if quiet {
+            return ProgressOutput::Quiet;
+        }
+
+        let terminal = if stderr {
+            io::stderr().is_terminal()
+        } else {
+            io::stdout().is_terminal()
+        };
+        if !terminal {
+            return ProgressOutput::Lines { stderr };
+        }
+
+        let target = if stderr {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::stdout()
+        };
+        let multi = MultiProgress::with_draw_target(target);
+        let overall = multi.add(ProgressBar::new(total as u64));
+        overall.set_style(
+            ProgressStyle::with_template(PROGRESS_TEMPLATE)
+                .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        );
+
+        ProgressOutput::Bars { multi, overall }
+    }
+
+    /// Add a bar for the project at position `current` of `total` below the overall
+    /// bar, if bars are shown
+    fn project_bar(&self, current: usize, total: usize) -> Option<ProgressBar> {
+        let multi = match self {
+            ProgressOutput::Bars { multi, .. } => multi,
+            _ => return None,
+        };
+
+        let bar = multi.add(ProgressBar::new_spinner());
+        bar.set_style(
+            ProgressStyle::with_template(PROJECT_TEMPLATE)
+                .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+        );
+        bar.set_prefix(format!("[{}/{}]", current, total));
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Some(bar)
+    }
+
+    /// Show the current phase of the project at position `current` of `total` on its
+    /// bar, or as a line when there are no bars
+    fn status(&self, bar: Option<&ProgressBar>, current: usize, total: usize, status: String) {
+        match self {
+            ProgressOutput::Bars { .. } => {
+                if let Some(bar) = bar {
+                    bar.set_message(status);
+                }
+            }
+            ProgressOutput::Lines { stderr } => Self::line(
+                *stderr,
+                format!(
+                    "{} {}",
+                    console::style(format!("[{}/{}]", current, total)).cyan(),
+                    status
+                ),
+            ),
+            ProgressOutput::Quiet => {}
+        }
+    }
+
+    /// Print a message above the progress bars, if any
+    fn message(&self, message: String) {
+        match self {
+            ProgressOutput::Bars { overall, .. } => overall.println(message),
+            ProgressOutput::Lines { stderr } => Self::line(*stderr, message),
+            ProgressOutput::Quiet => {}
+        }
+    }
+
+    /// Print a line of progress to stdout or stderr
+    fn line(stderr: bool, line: String) {
+        if stderr {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    /// Remove the bar of a completed project, if any, and advance the overall bar
+    fn project_done(&self, bar: Option<&ProgressBar>) {
+        if let ProgressOutput::Bars { multi, overall } = self {
+            if let Some(bar) = bar {
+                bar.finish_and_clear();
+                multi.remove(bar);
+            }
+
+            overall.inc(1);
+        }
+    }
+
+    /// Remove the progress bars, if any, so the summary can be written
+    fn finish(&self) {
+        if let ProgressOutput::Bars { multi, overall } = self {
+            overall.finish_and_clear();
+            let _ = multi.clear();
+        }
+    }
+}
+
+/// Projects to patch, build, and test and the settings shared by them
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[schemars(title = "cadence-crater configuration")]
+struct RunConfig {
+    /// Settings used for each project that doesn't specify its own value
+    #[serde(default, skip_serializing_if = "RunDefaults::is_empty")]
+    defaults: RunDefaults,
+    /// Other crates to patch along with Cadence, by name, and the path of each
+    /// local checkout
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    patch_crates: BTreeMap<String, PathBuf>,
+    /// Environment variables set when building and testing every project
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// Projects to patch, build, and test
+    projects: Vec<RunProject>,
+}
+
+impl RunConfig {
+    /// Determine the version and path of each of the other crates to patch
+    fn extra_patches(&self) -> Result<Vec<CratePatch>, CraterError> {
+        self.patch_crates
+            .iter()
+            .map(|(name, path)| {
+                let local = LocalVersion::new(path.clone());
+                Ok(CratePatch::new(
+                    name.clone(),
+                    local.version()?,
+                    PatchSource::Path(local.path()?),
+                ))
+            })
+            .collect()
+    }
+
+    /// Find problems with the settings of each project that would make running it
+    /// fail, after defaults have been applied
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for name in self.env.keys().filter(|n| !valid_env_name(n)) {
+            problems.push(format!(
+                "invalid environment variable name {:?} in env",
+                name
+            ));
+        }
+
+        for project in self.projects.iter() {
+            let res = project.targets().and_then(|_| match &project.test_command {
+                Some(command) => runner::check_test_command(command),
+                None => Ok(()),
+            });
+
+            if let Err(e) = res {
+                problems.push(format!("project {}: {}", project.repo, e));
+            }
+
+            if let Some(target) = &project.target {
+                if target.is_empty() || target.contains(char::is_whitespace) {
+                    problems.push(format!(
+                        "project {}: invalid target triple {:?}",
+                        project.repo, target
+                    ));
+                }
+            }
+
+            let sha256 = project.expected_sha256.as_deref().unwrap_or_default();
+            if project.expected_sha256.is_some()
+                && (sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()))
+            {
+                problems.push(format!(
+                    "project {}: expected_sha256 {:?} isn't a hex encoded SHA-256 checksum",
+                    project.repo, sha256
+                ));
+            }
+
+            // Invalid names from the global `env` were already reported above
+            let names = project.env.keys().filter(|n| !self.env.contains_key(*n));
+            for name in names.filter(|n| !valid_env_name(n)) {
+                problems.push(format!(
+                    "project {}: invalid environment variable name {:?}",
+                    project.repo, name
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Find problems that `validate` doesn't, such as missing local directories or
+    /// projects listed more than once, without accessing the network or writing
+    /// anything
+    ///
+    /// These are checked by `cadence-crater check` rather than before every run since
+    /// some of them only make part of the run fail or do needless work.
+    fn lint(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.projects.is_empty() {
+            problems.push("no projects".to_owned());
+        }
+
+        for (name, path) in self.patch_crates.iter() {
+            if name == CADENCE_CRATE {
+                problems.push(format!(
+                    "patch_crates: {} is always patched, it can't be in patch_crates",
+                    name
+                ));
+                continue;
+            }
+
+            match LocalVersion::new(path.clone()).version() {
+                Ok(v) if Version::parse(&v).is_err() => problems.push(format!(
+                    "patch_crates: version {:?} of {} at {:?} isn't a valid semver version",
+                    v, name, path
+                )),
+                Ok(_) => {}
+                Err(e) => problems.push(format!("patch_crates: {}: {}", name, e)),
+            }
+        }
+
+        let mut seen: HashMap<(&str, Option<&str>, &str), usize> = HashMap::new();
+        let mut dirs: HashMap<(String, Option<&Path>), &str> = HashMap::new();
+
+        for project in self.projects.iter() {
+            let problem = |msg: String| format!("project {}: {}", project.repo, msg);
+            let key = (
+                project.repo.as_str(),
+                project.rev.as_deref(),
+                project.root(),
+            );
+            // Later copies of a project have the same problems as the first
+            let count = seen.entry(key).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                if *count == 2 {
+                    problems.push(problem(
+                        "listed more than once with the same rev and root".to_owned(),
+                    ));
+                }
+
+                continue;
+            }
+
+            let local = LocalDirSource::from_repo(&project.repo, project.in_place());
+            match &local {
+                Some(l) if !l.path().is_dir() => problems.push(problem(format!(
+                    "local directory {:?} doesn't exist",
+                    l.path()
+                ))),
+                Some(_) => {}
+                None if !project.repo.contains("://") && !project.repo.contains('@') => problems
+                    .push(problem(
+                        "neither an existing directory nor a repository URL".to_owned(),
+                    )),
+                None => match vcs::repo_dir_name(&project.repo) {
+                    Ok(name) => {
+                        let dest = project.dest.as_deref();
+                        match dirs.get(&(name.clone(), dest)) {
+                            Some(other) if *other != project.repo => problems.push(problem(
+                                format!("would be cloned into the same directory as {}", other),
+                            )),
+                            _ => {
+                                dirs.insert((name, dest), &project.repo);
+                            }
+                        }
+                    }
+                    Err(e) => problems.push(problem(e.to_string())),
+                },
+            }
+
+            let commit = project
+                .rev
+                .as_deref()
+                .filter(|r| r.len() >= 7 && r.chars().all(|c| c.is_ascii_hexdigit()));
+            if let Some(rev) = commit.filter(|_| project.single_branch.unwrap_or(false)) {
+                problems.push(problem(format!(
+                    "rev {:?} looks like a commit but single_branch requires a branch",
+                    rev
+                )));
+            }
+
+            let archive = ArchiveSource::from_repo(&project.repo).is_some();
+            if project.expected_sha256.is_some() && !archive {
+                problems.push(problem(
+                    "expected_sha256 only applies to .tar.gz and .zip archive URLs".to_owned(),
+                ));
+            }
+
+            if archive && (project.rev.is_some() || project.single_branch.unwrap_or(false)) {
+                problems.push(problem(
+                    "rev and single_branch don't apply to archive URLs".to_owned(),
+                ));
+            }
+
+            if local.is_none() && project.in_place() {
+                problems.push(problem(
+                    "in_place only applies to local directories".to_owned(),
+                ));
+            }
+
+            let root = Path::new(project.root());
+            if root.is_absolute() || root.components().any(|c| c == Component::ParentDir) {
+                problems.push(problem(format!(
+                    "root {:?} must be a relative path inside the repository",
+                    project.root()
+                )));
+            }
+
+            for pattern in project.subprojects().iter().chain(project.exclude()) {
+                if let Err(e) = glob::Pattern::new(pattern) {
+                    problems.push(problem(format!("invalid pattern {:?}: {}", pattern, e)));
+                }
+            }
+
+            if project.pre_build().iter().any(|c| c.trim().is_empty()) {
+                problems.push(problem("empty pre_build command".to_owned()));
+            }
+        }
+
+        problems
+    }
+
+    /// Fill in any settings not specified by each project from the defaults and
+    /// any environment variables not set by each project from the global `env`
+    fn apply_defaults(&mut self) {
+        for project in self.projects.iter_mut() {
+            project.merge(&self.defaults);
+
+            for (name, value) in self.env.iter() {
+                project
+                    .env
+                    .entry(name.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+}
+
+/// JSON Schema of the configuration, generated from how `RunConfig` is deserialized
+fn config_schema() -> RootSchema {
+    // TOML has no null so optional settings are only ever omitted, never null
+    let settings = SchemaSettings::draft07().with(|s| {
+        s.option_nullable = false;
+        s.option_add_null_type = false;
+        s.inline_subschemas = true;
+    });
+
+    settings
+        .into_generator()
+        .into_root_schema_for::<RunConfig>()
+}
+
+/// Settings used for each project that doesn't specify its own value
+///
+/// Values set on a project always take precedence over these defaults.
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
+struct RunDefaults {
+    /// Branch, tag, or commit to check out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    /// Branch of the `--cadence-git` repository to use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cadence_branch: Option<String>,
+    /// Directory of the project's Cargo.toml in the repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    /// Workspace members (paths or globs) to patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subprojects: Option<Vec<String>>,
+    /// Patch local directories directly instead of a copy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_place: Option<bool>,
+    /// Initialize and update git submodules after cloning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submodules: Option<bool>,
+    /// Only clone the branch given by `rev` or the default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    single_branch: Option<bool>,
+    /// Cargo subcommand and arguments used to run tests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    test_command: Option<Vec<String>>,
+}
+
+impl RunDefaults {
+    fn is_empty(&self) -> bool {
+        self.rev.is_none()
+            && self.cadence_branch.is_none()
+            && self.root.is_none()
+            && self.subprojects.is_none()
+            && self.in_place.is_none()
+            && self.submodules.is_none()
+            && self.single_branch.is_none()
+            && self.test_command.is_none()
+    }
+}
+
+/// What a project must do for it to be considered compatible with Cadence
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum SuccessCriterion {
+    /// Only build the project, skipping its tests
+    Build,
+    /// Build the project and pass its tests
+    Test,
+}
+
+impl fmt::Display for SuccessCriterion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuccessCriterion::Build => write!(f, "build"),
+            SuccessCriterion::Test => write!(f, "test"),
+        }
+    }
+}
+
+/// Project to patch, build, and test
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Default)]
 struct RunProject {
+    /// Git repository URL, archive URL, or local directory of the project
     repo: String,
-    root: String,
-    subprojects: Vec<String>,
+    /// Branch, tag, or commit to check out instead of the default branch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rev: Option<String>,
+    /// Hex encoded SHA-256 checksum that a `.tar.gz` or `.zip` archive downloaded
+    /// for this project must have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_sha256: Option<String>,
+    /// Branch of the `--cadence-git` repository to use for this project
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cadence_branch: Option<String>,
+    /// Directory of the project's Cargo.toml in the repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    /// Workspace members (paths or glob patterns relative to the root) to patch
+    /// instead of every member
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subprojects: Option<Vec<String>>,
+    /// Patch a local project directory directly instead of a copy of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_place: Option<bool>,
+    /// Directory to clone this project into instead of the global destination
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dest: Option<PathBuf>,
+    /// Shell commands run in the project root after patching and before building
+    ///
+    /// These are run with the same privileges as crater itself so configurations
+    /// that set them must be trusted just like any other script you'd run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pre_build: Option<Vec<String>>,
+    /// Initialize and update git submodules after cloning
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submodules: Option<bool>,
+    /// Only clone the branch given by `rev`, or the default branch, instead of every
+    /// branch of the repository
+    #[serde(skip_serializing_if = "Option::is_none")]
+    single_branch: Option<bool>,
+    /// Only add Cadence to the `[patch]` section without changing the version required
+    /// by any dependency, for projects that only depend on Cadence transitively
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transitive_only: Option<bool>,
+    /// Workspace members (paths or glob patterns relative to the root) that are
+    /// patched but not built or tested, such as examples with heavy dependencies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclude: Option<Vec<String>>,
+    /// Targets to build and test instead of every target of the workspace, written
+    /// like the cargo options that select them such as `--example foo` or `--bin bar`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    targets: Option<Vec<String>>,
+    /// Target triple to build and test for instead of `--target` or the host, such as
+    /// `aarch64-unknown-linux-gnu`. Projects are only built for targets other than the host.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    /// What the project must do to be compatible: `build` to only build it, for projects
+    /// without meaningful tests of their Cadence usage, or `test` (the default) to also
+    /// pass its tests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    success: Option<SuccessCriterion>,
+    /// Cargo subcommand and arguments used to run tests instead of `test`, such as
+    /// `["nextest", "run"]` or `["hack", "test", "--each-feature"]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    test_command: Option<Vec<String>>,
+    /// Tags to group the project by, such as `core` or `async`, to run only the
+    /// projects with a tag using `--tag`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Environment variables set when running pre-build commands and cargo for this
+    /// project, taking precedence over the global `env` and the environment of crater
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+}
+
+impl RunProject {
+    fn merge(&mut self, defaults: &RunDefaults) {
+        if self.rev.is_none() {
+            self.rev = defaults.rev.clone();
+        }
+
+        if self.cadence_branch.is_none() {
+            self.cadence_branch = defaults.cadence_branch.clone();
+        }
+
+        if self.root.is_none() {
+            self.root = defaults.root.clone();
+        }
+
+        if self.subprojects.is_none() {
+            self.subprojects = defaults.subprojects.clone();
+        }
+
+        if self.in_place.is_none() {
+            self.in_place = defaults.in_place;
+        }
+
+        if self.submodules.is_none() {
+            self.submodules = defaults.submodules;
+        }
+
+        if self.single_branch.is_none() {
+            self.single_branch = defaults.single_branch;
+        }
+
+        if self.test_command.is_none() {
+            self.test_command = defaults.test_command.clone();
+        }
+    }
+
+    fn root(&self) -> &str {
+        self.root.as_deref().unwrap_or("")
+    }
+
+    fn subprojects(&self) -> &[String] {
+        self.subprojects.as_deref().unwrap_or(&[])
+    }
+
+    fn exclude(&self) -> &[String] {
+        self.exclude.as_deref().unwrap_or(&[])
+    }
+
+    fn targets(&self) -> Result<Vec<Target>, CraterError> {
+        self.targets
+            .as_deref()
+            .unwrap_or(&[])
+            .iter()
+            .map(|t| t.parse())
+            .collect()
+    }
+
+    fn pre_build(&self) -> &[String] {
+        self.pre_build.as_deref().unwrap_or(&[])
+    }
+
+    fn in_place(&self) -> bool {
+        self.in_place.unwrap_or(false)
+    }
+
+    fn transitive_only(&self) -> bool {
+        self.transitive_only.unwrap_or(false)
+    }
+
+    /// Whether the project only has to build, without running its tests
+    fn build_only(&self) -> bool {
+        self.success == Some(SuccessCriterion::Build)
+    }
+
+    fn source(
+        &self,
+        proxy: Option<&str>,
+        cache: Option<&Path>,
+        mirror_base: Option<&str>,
+        offline: bool,
+        max_size: Option<u64>,
+    ) -> Box<dyn Source> {
+        if let Some(local) = LocalDirSource::from_repo(&self.repo, self.in_place()) {
+            return Box::new(local);
+        }
+
+        if let Some(mut archive) = ArchiveSource::from_repo(&self.repo) {
+            archive = archive.with_offline(offline);
+
+            if let Some(sha256) = &self.expected_sha256 {
+                archive = archive.with_sha256(sha256);
+            }
+
+            if let Some(p) = proxy {
+                archive = archive.with_proxy(p);
+            }
+
+            if let Some(max) = max_size {
+                archive = archive.with_max_size(max);
+            }
+
+            return Box::new(archive);
+        }
+
+        let mut remote = RemoteRepo::new(self.repo.clone())
+            .with_submodules(self.submodules.unwrap_or(false))
+            .with_single_branch(self.single_branch.unwrap_or(false))
+            .with_offline(offline);
+
+        if let Some(rev) = &self.rev {
+            remote = remote.with_rev(rev);
+        }
+
+        if let Some(p) = proxy {
+            remote = remote.with_proxy(p);
+        }
+
+        if let Some(c) = cache {
+            remote = remote.with_cache(c);
+        }
+
+        if let Some(m) = mirror_base {
+            remote = remote.with_mirror_base(m);
+        }
+
+        if let Some(max) = max_size {
+            remote = remote.with_max_size(max);
+        }
+
+        Box::new(remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        config_schema, create_dir, expand_home, lock_file_name, parse_duration, parse_size,
+        read_config, write_config, write_plan, Command, ConfigFormat, CraterApplication,
+        LocalCadence, Phase, Progress, ProgressOutput, RunConfig, RunDefaults, RunProject,
+        SuccessCriterion,
+    };
+    use crate::testing::ScratchDir;
+    use clap::Clap;
+    use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    #[test]
+    fn test_create_dir_relative_nested() {
+        let base = ScratchDir::new("relative");
+        let dest = create_dir(&base, Path::new("./work/clones")).unwrap();
+
+        assert_eq!(base.join("work").join("clones"), dest);
+        assert!(dest.is_dir());
+    }
+
+    #[test]
+    fn test_create_dir_partially_exists() {
+        let base = ScratchDir::new("partial");
+        fs::create_dir_all(base.join("work")).unwrap();
+        let dest = create_dir(&base, Path::new("work/../work/clones")).unwrap();
+
+        assert_eq!(base.join("work").join("clones"), dest);
+        assert!(dest.is_dir());
+    }
+
+    #[test]
+    fn test_create_dir_absolute_ignores_base() {
+        let base = ScratchDir::new("absolute-base");
+        let other = ScratchDir::new("absolute-other");
+        let dest = create_dir(&base, &other.join("clones")).unwrap();
+
+        assert_eq!(other.join("clones"), dest);
+    }
+
+    #[test]
+    fn test_expand_home() {
+        assert_eq!(
+            PathBuf::from("../cadence"),
+            expand_home("../cadence".as_ref())
+        );
+        assert_eq!(
+            PathBuf::from("~user/cadence"),
+            expand_home("~user/cadence".as_ref())
+        );
+
+        if let Some(home) = env::var_os("HOME").filter(|h| !h.is_empty()) {
+            let home = PathBuf::from(home);
+            assert_eq!(home, expand_home("~".as_ref()));
+            assert_eq!(
+                home.join("src/cadence"),
+                expand_home("~/src/cadence".as_ref())
+            );
+        }
+    }
+
+    #[test]
+    fn test_progress_bars() {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+        let overall = multi.add(ProgressBar::new(2));
+        let output = ProgressOutput::Bars { multi, overall };
+
+        let first = Progress::new(&output, 1, 2, "https://example.com/a.git");
+        let second = Progress::new(&output, 2, 2, "https://example.com/b.git");
+        first.phase(Phase::Build);
+        second.phase(Phase::Clone);
+        let message = first.bar.as_ref().map(|b| b.message());
+        first.done();
+
+        assert_eq!(
+            Some("building https://example.com/a.git"),
+            message.as_deref()
+        );
+        assert!(first.bar.unwrap().is_finished());
+        assert!(!second.bar.unwrap().is_finished());
+        match &output {
+            ProgressOutput::Bars { overall, .. } => assert_eq!(1, overall.position()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_write_plan() {
+        let dir = ScratchDir::new("plan");
+        let cfg: RunConfig = toml::from_str(&format!(
+            r#"
+            [[projects]]
+            repo = "https://github.com/example/app.git"
+            rev = "v1.2.0"
+            dest = "pinned"
+            root = "crates"
+            subprojects = ["cli", "plugins/*"]
+
+            [[projects]]
+            repo = "{}"
+            in_place = true
+            "#,
+            dir.display()
+        ))
+        .unwrap();
+
+        let mut buf = Vec::new();
+        write_plan(
+            &mut buf,
+            &cfg.projects,
+            Path::new("/work"),
+            Path::new("/tmp"),
+            Some("https://git.example.com/mirror"),
+        )
+        .unwrap();
+        let plan = String::from_utf8(buf).unwrap();
+
+        let expected = format!(
+            "[1/2] https://github.com/example/app.git
+         clone from: https://git.example.com/mirror/github.com/example/app.git (falling back to https://github.com/example/app.git)
+         into: /work/pinned/app
+         rev: v1.2.0
+         root: crates
+         subprojects: cli, plugins/* (requires clone)
+[2/2] {0}
+         patch in place: {0}
+",
+            dir.display()
+        );
+        assert_eq!(expected, plan);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Duration::from_secs(90), parse_duration("90").unwrap());
+        assert_eq!(Duration::from_secs(90), parse_duration("90s").unwrap());
+        assert_eq!(Duration::from_secs(45 * 60), parse_duration("45m").unwrap());
+        assert_eq!(
+            Duration::from_secs(2 * 60 * 60),
+            parse_duration(" 2h ").unwrap()
+        );
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("1.5h").is_err());
+        assert!(parse_duration("2d").is_err());
+        assert!(parse_duration("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(512, parse_size("512").unwrap());
+        assert_eq!(2048, parse_size("2K").unwrap());
+        assert_eq!(500 * 1024 * 1024, parse_size("500M").unwrap());
+        assert_eq!(2 * 1024 * 1024 * 1024, parse_size("2g").unwrap());
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999999999999G").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut cfg: RunConfig = toml::from_str(
+            r#"
+            [env]
+            "A=B" = "global"
+
+            [[projects]]
+            repo = "https://example.com/a.git"
+            targets = ["--lib"]
+            test_command = ["nextest", "run"]
+
+            [[projects]]
+            repo = "https://example.com/b.git"
+            targets = ["--nope"]
+            env = { "X\u0000Y" = "project" }
+            "#,
+        )
+        .unwrap();
+
+        cfg.apply_defaults();
+        let problems = cfg.validate();
+
+        assert_eq!(3, problems.len());
+        assert!(problems[0].contains("\"A=B\" in env"));
+        assert!(problems[1].starts_with("project https://example.com/b.git: invalid target"));
+        assert!(problems[2].contains("invalid environment variable name \"X\\0Y\""));
+    }
+
+    #[test]
+    fn test_lint() {
+        let cfg: RunConfig = toml::from_str(
+            r#"
+            [[projects]]
+            repo = "https://github.com/a/foo.git"
+            root = "../other"
+            exclude = ["[bad"]
+
+            [[projects]]
+            repo = "https://github.com/b/foo.git"
+
+            [[projects]]
+            repo = "https://github.com/b/foo.git"
+
+            [[projects]]
+            repo = "file:///nonexistent/cadence-crater"
+
+            [[projects]]
+            repo = "https://github.com/c/bar.git"
+            rev = "v1.0.0"
+            subprojects = ["crates/*"]
+
+            [[projects]]
+            repo = "https://github.com/d/baz.git"
+            rev = "3b4d3ff"
+            single_branch = true
+            "#,
+        )
+        .unwrap();
+
+        let problems = cfg.lint();
+
+        assert_eq!(
+            vec![
+                "project https://github.com/a/foo.git: root \"../other\" must be a relative path \
+                 inside the repository",
+                "project https://github.com/a/foo.git: invalid pattern \"[bad\": Pattern syntax \
+                 error near position 0: invalid range pattern",
+                "project https://github.com/b/foo.git: would be cloned into the same directory \
+                 as https://github.com/a/foo.git",
+                "project https://github.com/b/foo.git: listed more than once with the same rev \
+                 and root",
+                "project file:///nonexistent/cadence-crater: local directory \
+                 \"/nonexistent/cadence-crater\" doesn't exist",
+                "project https://github.com/d/baz.git: rev \"3b4d3ff\" looks like a commit but \
+                 single_branch requires a branch",
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn test_lock_file_name() {
+        let cfg: RunConfig = toml::from_str(
+            r#"
+            [[projects]]
+            repo = "https://example.com/a.git"
+
+            [[projects]]
+            repo = "https://example.com/a.git"
+            rev = "v1.0.0"
+            "#,
+        )
+        .unwrap();
+        let cadence = |version: &str| LocalCadence {
+            version: version.to_owned(),
+            path: None,
+            sha: None,
+        };
+
+        let name = lock_file_name(&cfg.projects[0], &cadence("0.29.0"));
+
+        assert!(name.starts_with("a-"));
+        assert!(name.ends_with(".lock"));
+        assert_eq!(name, lock_file_name(&cfg.projects[0], &cadence("0.29.0")));
+        assert_ne!(name, lock_file_name(&cfg.projects[0], &cadence("0.30.0")));
+        assert_ne!(name, lock_file_name(&cfg.projects[1], &cadence("0.29.0")));
+    }
+
+    #[test]
+    fn test_apply_defaults_env() {
+        let mut cfg: RunConfig = toml::from_str(
+            r#"
+            [env]
+            PROTOC = "/usr/bin/protoc"
+            RUSTFLAGS = "--cfg global"
+
+            [[projects]]
+            repo = "https://example.com/a.git"
+
+            [[projects]]
+            repo = "https://example.com/b.git"
+            env = { RUSTFLAGS = "--cfg project" }
+            "#,
+        )
+        .unwrap();
+
+        cfg.apply_defaults();
+
+        assert_eq!("--cfg global", cfg.projects[0].env["RUSTFLAGS"]);
+        assert_eq!("--cfg project", cfg.projects[1].env["RUSTFLAGS"]);
+        assert_eq!("/usr/bin/protoc", cfg.projects[1].env["PROTOC"]);
+    }
+
+    #[test]
+    fn test_write_config() {
+        let mut cfg: RunConfig = toml::from_str(
+            r#"
+            [defaults]
+            rev = "main"
+
+            [env]
+            RUSTFLAGS = "--cfg global"
+
+            [[projects]]
+            repo = "https://example.com/a.git"
+            tags = ["core"]
+            "#,
+        )
+        .unwrap();
+
+        cfg.apply_defaults();
+        let dir = ScratchDir::new("write-config");
+
+        let formats = vec![
+            ("crater.toml", ConfigFormat::Toml),
+            ("crater.json", ConfigFormat::Json),
+        ];
+
+        for (name, format) in formats {
+            let path = dir.join(name);
+            write_config(&path, &cfg, format).unwrap();
+            let written = read_config(&path, format).unwrap();
+
+            assert_eq!(Some("main"), written.projects[0].rev.as_deref());
+            assert_eq!("--cfg global", written.projects[0].env["RUSTFLAGS"]);
+            assert_eq!(vec!["core".to_owned()], written.projects[0].tags);
+        }
+    }
+
+    /// Names of the properties of a JSON object, sorted
+    fn keys(value: &serde_json::Value) -> Vec<String> {
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_config_schema() {
+        let text = || Some("x".to_owned());
+        let list = || Some(vec!["x".to_owned()]);
+        let env: BTreeMap<String, String> =
+            vec![("X".to_owned(), "1".to_owned())].into_iter().collect();
+
+        // Every field is set so that every one of them is serialized
+        let cfg = RunConfig {
+            defaults: RunDefaults {
+                rev: text(),
+                cadence_branch: text(),
+                root: text(),
+                subprojects: list(),
+                in_place: Some(true),
+                submodules: Some(true),
+                single_branch: Some(true),
+                test_command: list(),
+            },
+            patch_crates: vec![("x".to_owned(), PathBuf::from("x"))]
+                .into_iter()
+                .collect(),
+            env: env.clone(),
+            projects: vec![RunProject {
+                repo: "x".to_owned(),
+                rev: text(),
+                expected_sha256: text(),
+                cadence_branch: text(),
+                root: text(),
+                subprojects: list(),
+                in_place: Some(true),
+                dest: Some(PathBuf::from("x")),
+                pre_build: list(),
+                submodules: Some(true),
+                single_branch: Some(true),
+                transitive_only: Some(true),
+                exclude: list(),
+                targets: list(),
+                target: text(),
+                success: Some(SuccessCriterion::Build),
+                test_command: list(),
+                tags: vec!["x".to_owned()],
+                env,
+            }],
+        };
+
+        let serialized = serde_json::to_value(&cfg).unwrap();
+        let schema = serde_json::to_value(config_schema()).unwrap();
+        let properties = &schema["properties"];
+
+        assert_eq!(keys(&serialized), keys(properties));
+        assert_eq!(
+            keys(&serialized["defaults"]),
+            keys(&properties["defaults"]["properties"])
+        );
+        assert_eq!(
+            keys(&serialized["projects"][0]),
+            keys(&properties["projects"]["items"]["properties"])
+        );
+        assert_eq!(json!(["projects"]), schema["required"]);
+        assert_eq!(json!(["repo"]), properties["projects"]["items"]["required"]);
+        assert_eq!(
+            vec![json!(["build"]), json!(["test"])],
+            properties["projects"]["items"]["properties"]["success"]["oneOf"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| v["enum"].clone())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_fetch_shared_options() {
+        let parse = |args: &[&str]| {
+            CraterApplication::try_parse_from(Some("cadence-crater").iter().chain(args.iter()))
+        };
+
+        let app = parse(&[
+            "fetch",
+            "--dest",
+            "clones",
+            "--clone-jobs",
+            "4",
+            "--tag",
+            "core",
+            "--config-dir",
+            "configs",
+        ])
+        .unwrap();
+        let fetch = match app.command {
+            Some(Command::Fetch(fetch)) => fetch,
+            other => panic!("expected fetch, got {:?}", other),
+        };
+
+        assert_eq!(Some(PathBuf::from("clones")), fetch.fetch.destination);
+        assert_eq!(4, fetch.fetch.clone_jobs);
+        assert_eq!(vec!["core".to_owned()], fetch.configs.tags);
+        assert_eq!(Some(PathBuf::from("configs")), fetch.configs.config_dir);
+        assert_eq!(None, fetch.config);
+
+        // A configuration is required unless --config-dir is given, and so is --dest
+        assert!(parse(&["fetch", "--dest", "clones"]).is_err());
+        assert!(parse(&["fetch", "crater.toml"]).unwrap().run().is_err());
+    }
+
+    #[test]
+    fn test_named_cadence_and_config() {
+        let parse = |args: &[&str]| {
+            let app =
+                CraterApplication::try_parse_from(Some("cadence-crater").iter().chain(args.iter()))
+                    .unwrap();
+            let cadences: Vec<PathBuf> = app
+                .cadence_path()
+                .into_iter()
+                .chain(app.extra_cadence.iter().map(|p| p.as_path()))
+                .map(Path::to_path_buf)
+                .collect();
+            (cadences, app.config_path().map(Path::to_path_buf).ok())
+        };
+        let cadence = || vec![PathBuf::from("cadence")];
+        let config = || Some(PathBuf::from("crater.toml"));
+
+        assert_eq!((cadence(), config()), parse(&["cadence", "crater.toml"]));
+        assert_eq!(
+            (cadence(), config()),
+            parse(&["--cadence", "cadence", "--config", "crater.toml"])
+        );
+        assert_eq!(
+            (cadence(), config()),
+            parse(&["--config", "crater.toml", "cadence"])
+        );
+        assert_eq!(
+            (cadence(), config()),
+            parse(&["--cadence", "cadence", "crater.toml"])
+        );
+        assert_eq!(
+            (vec![PathBuf::from("a"), PathBuf::from("b")], config()),
+            parse(&["--cadence", "b", "a", "crater.toml"])
+        );
+        assert_eq!(
+            (cadence(), None),
+            parse(&["--config", "crater.toml", "cadence", "other.toml"])
+        );
+        assert!(CraterApplication::try_parse_from(["cadence-crater", "cadence"]).is_err());
+        assert!(
+            CraterApplication::try_parse_from(["cadence-crater", "--config", "crater.toml"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_success_criterion() {
+        let cfg: RunConfig = toml::from_str(
+            r#"
+            [[projects]]
+            repo = "https://example.com/a.git"
+            success = "build"
+
+            [[projects]]
+            repo = "https://example.com/b.git"
+            success = "test"
+
+            [[projects]]
+            repo = "https://example.com/c.git"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(Some(SuccessCriterion::Build), cfg.projects[0].success);
+        assert!(cfg.projects[0].build_only());
+        assert!(!cfg.projects[1].build_only());
+        assert!(!cfg.projects[2].build_only());
+
+        let invalid: Result<RunConfig, _> = toml::from_str(
+            r#"
+            [[projects]]
+            repo = "https://example.com/a.git"
+            success = "lint"
+            "#,
+        );
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_apply_defaults_test_command() {
+        let mut cfg: RunConfig = toml::from_str(
+            r#"
+            [defaults]
+            test_command = ["nextest", "run"]
+
+            [[projects]]
+            repo = "https://example.com/a.git"
+
+            [[projects]]
+            repo = "https://example.com/b.git"
+            test_command = ["hack", "test"]
+            "#,
+        )
+        .unwrap();
+
+        cfg.apply_defaults();
+
+        assert_eq!(
+            Some(vec!["nextest".to_owned(), "run".to_owned()]),
+            cfg.projects[0].test_command
+        );
+        assert_eq!(
+            Some(vec!["hack".to_owned(), "test".to_owned()]),
+            cfg.projects[1].test_command
+        );
+    }
 }
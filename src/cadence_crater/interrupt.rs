@@ -0,0 +1,114 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Decide whether an interrupt (Ctrl-C) should skip the project currently being
+//! run or stop the whole run, and let the run check whether a skip was requested.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How soon a second interrupt has to follow the first to stop the whole run
+/// instead of skipping another project
+pub const ABORT_WINDOW: Duration = Duration::from_secs(3);
+
+static STATE: Mutex<Interrupts> = Mutex::new(Interrupts::new());
+
+/// What to do in response to an interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Skip the project currently being run and continue with the next one
+    SkipProject,
+    /// Stop the whole run
+    Abort,
+}
+
+/// Interrupts received so far and whether a skip is waiting to be handled
+#[derive(Debug)]
+struct Interrupts {
+    last: Option<Instant>,
+    skip: bool,
+}
+
+impl Interrupts {
+    const fn new() -> Self {
+        Interrupts {
+            last: None,
+            skip: false,
+        }
+    }
+
+    fn interrupt(&mut self, now: Instant) -> Action {
+        let rapid = self
+            .last
+            .map(|last| now.duration_since(last) < ABORT_WINDOW)
+            .unwrap_or(false);
+
+        self.last = Some(now);
+        if rapid {
+            Action::Abort
+        } else {
+            self.skip = true;
+            Action::SkipProject
+        }
+    }
+}
+
+/// Record an interrupt, returning whether the current project should be skipped or
+/// the run stopped because it followed the previous interrupt within `ABORT_WINDOW`
+///
+/// This is meant to be called from a signal handler. Subprocesses started by the
+/// run are in the same process group as crater, so they receive the interrupt from
+/// the terminal and stop on their own.
+pub fn interrupted() -> Action {
+    STATE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .interrupt(Instant::now())
+}
+
+/// Returns true if skipping the current project was requested and not yet handled
+pub fn skip_requested() -> bool {
+    STATE.lock().unwrap_or_else(|e| e.into_inner()).skip
+}
+
+/// Returns true if skipping the current project was requested, marking it handled
+pub fn take_skip() -> bool {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    let skip = state.skip;
+    state.skip = false;
+    skip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Interrupts, ABORT_WINDOW};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_interrupt() {
+        let mut state = Interrupts::new();
+        let start = Instant::now();
+
+        assert_eq!(Action::SkipProject, state.interrupt(start));
+        assert!(state.skip);
+
+        state.skip = false;
+        let later = start + ABORT_WINDOW + Duration::from_secs(1);
+        assert_eq!(Action::SkipProject, state.interrupt(later));
+        assert!(state.skip);
+
+        state.skip = false;
+        assert_eq!(
+            Action::Abort,
+            state.interrupt(later + Duration::from_millis(500))
+        );
+        assert!(!state.skip);
+    }
+}
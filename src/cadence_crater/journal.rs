@@ -0,0 +1,197 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::report::ProjectOutcome;
+use crate::types::CraterError;
+use serde_json::{json, Value};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the journal written to the `--output-dir` of a run
+pub const JOURNAL_FILE_NAME: &str = "journal.jsonl";
+
+/// Path of the journal given either the journal itself or a directory containing
+/// one, such as the `--output-dir` of a previous run
+pub fn journal_path(path: &Path) -> PathBuf {
+    if path.is_dir() {
+        path.join(JOURNAL_FILE_NAME)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Progress of a run, recording the outcomes of each project as it completes
+///
+/// Each outcome is written as a single line of JSON with the position of the
+/// project in the run (starting at 1), its repository, and the outcome itself.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    out: File,
+}
+
+impl Journal {
+    /// Create a new, empty journal at the given path, replacing any existing file
+    /// and creating any missing parent directories
+    pub fn create(path: &Path) -> Result<Self, CraterError> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| {
+                CraterError::new_err(format!("unable to create directory {:?}", parent), e)
+            })?;
+        }
+
+        let out = File::create(path)
+            .map_err(|e| CraterError::new_err(format!("unable to create journal {:?}", path), e))?;
+
+        Ok(Journal {
+            path: path.to_path_buf(),
+            out,
+        })
+    }
+
+    /// Record the outcomes of the project at the given position in the run
+    ///
+    /// All outcomes of the project are written at once and synced to disk so that
+    /// the journal is complete up to the last project even if the run is interrupted.
+    pub fn record(
+        &mut self,
+        index: usize,
+        repo: &str,
+        outcomes: &[ProjectOutcome],
+    ) -> Result<(), CraterError> {
+        let mut buf = Vec::new();
+        for outcome in outcomes {
+            let line = json!({"index": index, "repo": repo, "outcome": outcome.to_json()});
+            buf.extend_from_slice(line.to_string().as_bytes());
+            buf.push(b'\n');
+        }
+
+        self.out
+            .write_all(&buf)
+            .and_then(|_| self.out.sync_data())
+            .map_err(|e| {
+                CraterError::new_err(format!("unable to write journal {:?}", self.path), e)
+            })
+    }
+}
+
+/// Outcomes of projects completed by a previous run, read from its journal
+#[derive(Debug, Default)]
+pub struct Completed {
+    outcomes: Vec<(usize, String, ProjectOutcome)>,
+}
+
+impl Completed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the journal at the given path or in the given directory
+    ///
+    /// Lines that can't be parsed, such as one left incomplete by an interrupted
+    /// run, are ignored and their projects will be run again.
+    pub fn read(path: &Path) -> Result<Self, CraterError> {
+        let path = journal_path(path);
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| CraterError::new_err(format!("unable to read journal {:?}", path), e))?;
+
+        Ok(Completed {
+            outcomes: contents.lines().filter_map(parse_line).collect(),
+        })
+    }
+
+    /// Returns true if the project at the given position in the run was completed
+    pub fn contains(&self, index: usize, repo: &str) -> bool {
+        self.outcomes.iter().any(|(i, r, _)| *i == index && r == repo)
+    }
+
+    /// Remove and return the outcomes of the project at the given position in the
+    /// run, empty if it wasn't completed or was a different repository
+    pub fn take(&mut self, index: usize, repo: &str) -> Vec<ProjectOutcome> {
+        let mut taken = Vec::new();
+        let mut i = 0;
+
+        while i < self.outcomes.len() {
+            if self.outcomes[i].0 == index && self.outcomes[i].1 == repo {
+                taken.push(self.outcomes.remove(i).2);
+            } else {
+                i += 1;
+            }
+        }
+
+        taken
+    }
+}
+
+/// Parse a single line of a journal
+fn parse_line(line: &str) -> Option<(usize, String, ProjectOutcome)> {
+    let value: Value = serde_json::from_str(line).ok()?;
+    let index = value.get("index")?.as_u64()? as usize;
+    let repo = value.get("repo")?.as_str()?.to_owned();
+    let outcome = ProjectOutcome::from_json(value.get("outcome")?)?;
+
+    Some((index, repo, outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Completed, Journal};
+    use crate::report::ProjectOutcome;
+    use crate::testing::ScratchDir;
+    use crate::types::CraterError;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn test_journal_resume() {
+        let dir = ScratchDir::new("journal");
+        let path = dir.join("journal.jsonl");
+
+        let mut journal = Journal::create(&path).unwrap();
+        journal
+            .record(
+                1,
+                "https://example.com/a.git",
+                &[ProjectOutcome::new("a", Ok(()))],
+            )
+            .unwrap();
+        journal
+            .record(
+                2,
+                "https://example.com/b.git",
+                &[
+                    ProjectOutcome::new("b", Ok(())).with_cadence("0.20.0"),
+                    ProjectOutcome::new("b", Err(CraterError::new("build failed")))
+                        .with_cadence("0.21.0"),
+                ],
+            )
+            .unwrap();
+
+        // Simulate a line left incomplete by an interrupted run
+        let mut fd = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(fd, r#"{{"index": 3, "repo": "https://exa"#).unwrap();
+
+        let mut completed = Completed::read(&dir).unwrap();
+        let a = completed.take(1, "https://example.com/a.git");
+        let b = completed.take(2, "https://example.com/b.git");
+        let c = completed.take(3, "https://example.com/c.git");
+        let moved = completed.take(1, "https://example.com/b.git");
+
+        assert_eq!(1, a.len());
+        assert_eq!(2, b.len());
+        assert_eq!(
+            Some("build failed"),
+            b[1].error().map(|e| e.to_string()).as_deref()
+        );
+        assert!(c.is_empty());
+        assert!(moved.is_empty());
+    }
+}
@@ -8,7 +8,18 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+pub mod archive;
+pub mod cleanup;
 pub mod cmd;
+pub mod interrupt;
+pub mod journal;
+pub mod pipeline;
+pub mod registry;
+pub mod report;
+pub mod runner;
+#[cfg(test)]
+mod testing;
 pub mod toml;
 pub mod types;
+pub mod usage;
 pub mod vcs;
@@ -0,0 +1,173 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::types::CraterError;
+use crate::vcs::{Fetched, Source};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Project to fetch ahead of time: its position in the run, where it's fetched
+/// from, and the directory it's fetched into
+pub type Fetch = (usize, Box<dyn Source>, PathBuf);
+
+/// Result of a fetch sent back from a worker thread
+type Done = (usize, Box<dyn Source>, Result<Fetched, CraterError>);
+
+/// Project fetched by a `ClonePipeline` along with the source it was fetched from,
+/// which is asked about the fetch afterwards (such as whether it was too large)
+#[derive(Debug)]
+pub struct Prefetched {
+    pub source: Box<dyn Source>,
+    pub res: Result<Fetched, CraterError>,
+}
+
+/// Clone stage of a run that fetches projects on background threads while the
+/// projects fetched before them are built and tested
+///
+/// Up to `jobs` projects are fetched at once, in the order they were given. Results
+/// are taken in order of position with `take`, waiting for a project if it's still
+/// being fetched. Dropping the pipeline stops fetching new projects and waits for
+/// the fetches already started to finish.
+#[derive(Debug)]
+pub struct ClonePipeline {
+    queued: HashSet<usize>,
+    done: HashMap<usize, Prefetched>,
+    results: Receiver<Done>,
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ClonePipeline {
+    /// Start fetching each of the projects using up to `jobs` threads
+    pub fn start(jobs: usize, fetches: Vec<Fetch>) -> Self {
+        let queued = fetches.iter().map(|(i, _, _)| *i).collect();
+        let queue = Arc::new(Mutex::new(fetches.into_iter().collect::<VecDeque<Fetch>>()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, results) = mpsc::channel();
+
+        let workers = (0..jobs.max(1))
+            .map(|_| {
+                let queue = Arc::clone(&queue);
+                let stop = Arc::clone(&stop);
+                let tx = tx.clone();
+
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+                        let (i, source, into) = match next {
+                            Some(fetch) => fetch,
+                            None => break,
+                        };
+
+                        let res = source.fetch(&into);
+                        if tx.send((i, source, res)).is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        ClonePipeline {
+            queued,
+            done: HashMap::new(),
+            results,
+            stop,
+            workers,
+        }
+    }
+
+    /// Take the project at the given position, waiting for it to be fetched, or `None`
+    /// if it wasn't given to the pipeline or the pipeline was stopped before fetching it
+    pub fn take(&mut self, index: usize) -> Option<Prefetched> {
+        if !self.queued.remove(&index) {
+            return None;
+        }
+
+        loop {
+            if let Some(prefetched) = self.done.remove(&index) {
+                return Some(prefetched);
+            }
+
+            // Every worker has exited once the channel is closed, so the project
+            // was never fetched
+            match self.results.recv() {
+                Ok((i, source, res)) => {
+                    self.done.insert(i, Prefetched { source, res });
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Stop fetching projects that haven't been started yet, such as when the run
+    /// is out of time and won't build them
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ClonePipeline {
+    fn drop(&mut self) {
+        self.stop();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClonePipeline, Fetch};
+    use crate::testing::ScratchDir;
+    use crate::vcs::{FetchKind, LocalDirSource, Source};
+    use std::error::Error;
+    use std::fs;
+
+    #[test]
+    fn test_clone_pipeline() {
+        let dir = ScratchDir::new("pipeline");
+        let dest = dir.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+
+        let mut fetches: Vec<Fetch> = Vec::new();
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            let project = dir.join(name);
+            fs::create_dir_all(&project).unwrap();
+            fs::write(project.join("Cargo.toml"), "[package]\n").unwrap();
+            let source: Box<dyn Source> = Box::new(LocalDirSource::new(project, false));
+            fetches.push((i, source, dest.clone()));
+        }
+
+        let missing: Box<dyn Source> = Box::new(LocalDirSource::new(dir.join("d"), false));
+        fetches.push((3, missing, dest.clone()));
+
+        let mut pipeline = ClonePipeline::start(2, fetches);
+        let c = pipeline.take(2).unwrap();
+        let a = pipeline.take(0).unwrap();
+        let again = pipeline.take(0);
+        let failed = pipeline.take(3).unwrap();
+        let missing = pipeline.take(5);
+        drop(pipeline);
+
+        let copied = dest.join("c").join("Cargo.toml").is_file();
+
+        assert_eq!(FetchKind::Copied, c.res.unwrap().kind());
+        assert_eq!(dest.join("a"), a.res.unwrap().path());
+        assert!(again.is_none());
+        assert!(failed.res.unwrap_err().source().is_some());
+        assert!(missing.is_none());
+        assert!(copied);
+    }
+}
@@ -0,0 +1,98 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::types::CraterError;
+use serde_json::Value;
+
+/// Base URL of the crates.io API
+const CRATES_IO_API: &str = "https://crates.io/api/v1";
+
+/// User agent sent with every request, as required by the crates.io crawler policy
+const USER_AGENT: &str = concat!("cadence-crater/", env!("CARGO_PKG_VERSION"));
+
+/// Look up crate metadata using the crates.io API
+#[derive(Debug)]
+pub struct CratesIo {
+    base: String,
+    agent: ureq::Agent,
+}
+
+impl CratesIo {
+    pub fn new() -> Self {
+        Self::with_base(CRATES_IO_API)
+    }
+
+    /// Create a new `CratesIo` that uses the API at the given base URL
+    pub fn with_base<S: Into<String>>(base: S) -> Self {
+        CratesIo {
+            base: base.into(),
+            agent: ureq::AgentBuilder::new()
+                .user_agent(USER_AGENT)
+                .try_proxy_from_env(true)
+                .build(),
+        }
+    }
+
+    /// Get the source repository URL of the named crate
+    ///
+    /// Errors will be returned if
+    /// * The request fails or the crate doesn't exist
+    /// * The response isn't valid JSON
+    /// * The crate doesn't have a repository URL set
+    pub fn repository(&self, name: &str) -> Result<String, CraterError> {
+        let url = format!("{}/crates/{}", self.base, name);
+        let body: Value = self
+            .agent
+            .get(&url)
+            .call()
+            .map_err(|e| CraterError::new_err(format!("unable to look up crate {}", name), e))?
+            .into_json()
+            .map_err(|e| {
+                CraterError::new_err(format!("unable to parse response for crate {}", name), e)
+            })?;
+
+        repository_from(&body)
+            .ok_or_else(|| CraterError::new(format!("crate {} has no repository URL", name)))
+    }
+}
+
+impl Default for CratesIo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Extract the repository URL from a crates.io crate response
+fn repository_from(body: &Value) -> Option<String> {
+    body.get("crate")
+        .and_then(|c| c.get("repository"))
+        .and_then(|r| r.as_str())
+        .map(|r| r.trim().trim_end_matches('/'))
+        .filter(|r| !r.is_empty())
+        .map(|r| r.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::repository_from;
+    use serde_json::json;
+
+    #[test]
+    fn test_repository_from() {
+        let body = json!({"crate": {"name": "cadence", "repository": "https://github.com/56quarters/cadence/"}});
+        let missing = json!({"crate": {"name": "cadence", "repository": null}});
+
+        assert_eq!(
+            Some("https://github.com/56quarters/cadence".to_owned()),
+            repository_from(&body)
+        );
+        assert_eq!(None, repository_from(&missing));
+    }
+}
@@ -0,0 +1,1631 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::runner::{CompilerError, CompilerErrors};
+use crate::types::CraterError;
+use crate::vcs::FetchKind;
+use console::Style;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Name of the test suite used when writing JUnit reports
+const JUNIT_SUITE: &str = "cadence-crater";
+
+/// Phases of processing a project in the order they're run, used to restore the
+/// order of timings read from JSON
+const PHASE_ORDER: &[&str] = &[
+    "clone",
+    "baseline",
+    "patch",
+    "pre-build",
+    "update",
+    "build",
+    "test",
+];
+
+/// Overall status of a project, taking the baseline into account if there was one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Succeeded with the local Cadence version
+    Passed,
+    /// Failed with the local Cadence version (and the baseline, if any, succeeded)
+    Failed,
+    /// Failed both with the local Cadence version and the baseline
+    AlreadyFailing,
+    /// Wasn't started, such as when the time budget of the run was used up
+    Skipped,
+}
+
+impl Status {
+    /// Style of the status when shown in a terminal
+    fn style(&self) -> Style {
+        match self {
+            Status::Passed => Style::new().green(),
+            Status::Failed => Style::new().red().bold(),
+            Status::AlreadyFailing => Style::new().yellow(),
+            Status::Skipped => Style::new().dim(),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Passed => "passed",
+            Status::Failed => "failed",
+            Status::AlreadyFailing => "already_failing",
+            Status::Skipped => "skipped",
+        }
+    }
+}
+
+/// How long each phase of processing a project took, in the order they were run
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    phases: Vec<(String, Duration)>,
+}
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `f`, recording how long it took as the named phase
+    pub fn time<T, F: FnOnce() -> T>(&mut self, phase: &str, f: F) -> T {
+        let start = Instant::now();
+        let res = f();
+        self.phases.push((phase.to_owned(), start.elapsed()));
+        res
+    }
+
+    pub fn phases(&self) -> &[(String, Duration)] {
+        &self.phases
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phases.is_empty()
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (phase, duration)) in self.phases.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+
+            write!(f, "{} {:.2}s", phase, duration.as_secs_f64())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Problem found while processing a project that doesn't cause it to fail, such
+/// as a patch that has no effect or a missing subproject that was skipped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    message: String,
+}
+
+impl Warning {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Warning {
+            message: message.into(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Result of processing a single project
+#[derive(Debug)]
+pub struct ProjectOutcome {
+    name: String,
+    cadence: Option<String>,
+    target: Option<String>,
+    criterion: Option<String>,
+    error: Option<CraterError>,
+    baseline: Option<Result<(), String>>,
+    skipped: Vec<String>,
+    warnings: Vec<Warning>,
+    commands: Vec<String>,
+    patched_files: Vec<PathBuf>,
+    manifest_diff: String,
+    cargo_log: String,
+    fetched: Option<FetchKind>,
+    sha: Option<String>,
+    usage: BTreeMap<String, usize>,
+    tags: Vec<String>,
+    not_started: Option<String>,
+    timings: Timings,
+}
+
+impl ProjectOutcome {
+    /// Create a new `ProjectOutcome` for the named project from the result of processing it
+    pub fn new<S: Into<String>>(name: S, res: Result<(), CraterError>) -> Self {
+        ProjectOutcome {
+            name: name.into(),
+            cadence: None,
+            target: None,
+            criterion: None,
+            error: res.err(),
+            baseline: None,
+            skipped: Vec::new(),
+            warnings: Vec::new(),
+            commands: Vec::new(),
+            patched_files: Vec::new(),
+            manifest_diff: String::new(),
+            cargo_log: String::new(),
+            fetched: None,
+            sha: None,
+            usage: BTreeMap::new(),
+            tags: Vec::new(),
+            not_started: None,
+            timings: Timings::new(),
+        }
+    }
+
+    /// Create a new `ProjectOutcome` for the named project that wasn't started for
+    /// the given reason
+    pub fn not_started<S: Into<String>, R: Into<String>>(name: S, reason: R) -> Self {
+        ProjectOutcome {
+            not_started: Some(reason.into()),
+            ..Self::new(name, Ok(()))
+        }
+    }
+
+    /// Set the local Cadence version this outcome is for when testing multiple versions
+    pub fn with_cadence<S: Into<String>>(mut self, cadence: S) -> Self {
+        self.cadence = Some(cadence.into());
+        self
+    }
+
+    /// Set the target triple the project was built for, when not built for the host
+    pub fn with_target<S: Into<String>>(mut self, target: S) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Set what the project had to do to succeed, `build` or `test`, when configured
+    pub fn with_criterion<S: Into<String>>(mut self, criterion: S) -> Self {
+        self.criterion = Some(criterion.into());
+        self
+    }
+
+    /// Set the result (or error message) of building and testing the project without
+    /// the local Cadence version
+    pub fn with_baseline(mut self, res: Result<(), String>) -> Self {
+        self.baseline = Some(res);
+        self
+    }
+
+    /// Set the workspace members that were patched but not built or tested
+    pub fn with_skipped(mut self, skipped: Vec<String>) -> Self {
+        self.skipped = skipped;
+        self
+    }
+
+    /// Set the problems found while processing the project that didn't cause it to fail
+    pub fn with_warnings(mut self, warnings: Vec<Warning>) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    /// Set the command lines used to build and test the project, to rerun them by hand
+    pub fn with_commands(mut self, commands: Vec<String>) -> Self {
+        self.commands = commands;
+        self
+    }
+
+    /// Set the files written when patching the project, such as each Cargo.toml
+    pub fn with_patched_files(mut self, files: Vec<PathBuf>) -> Self {
+        self.patched_files = files;
+        self
+    }
+
+    /// Set the unified diff of every file changed when patching the project
+    pub fn with_manifest_diff<S: Into<String>>(mut self, diff: S) -> Self {
+        self.manifest_diff = diff.into();
+        self
+    }
+
+    /// Set the full output of every cargo command run for the project
+    pub fn with_cargo_log<S: Into<String>>(mut self, log: S) -> Self {
+        self.cargo_log = log.into();
+        self
+    }
+
+    /// Set whether the project was cloned, reused from an existing clone, or copied
+    pub fn with_fetched(mut self, fetched: FetchKind) -> Self {
+        self.fetched = Some(fetched);
+        self
+    }
+
+    /// Set the commit of the project that was tested, if it's a git checkout
+    pub fn with_sha<S: Into<String>>(mut self, sha: S) -> Self {
+        self.sha = Some(sha.into());
+        self
+    }
+
+    /// Set the number of references to each Cadence item in the sources of the project,
+    /// keyed by its path within Cadence
+    pub fn with_usage(mut self, usage: BTreeMap<String, usize>) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    /// Set the tags of the project from the configuration, to group results by
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Set how long each phase of processing the project took
+    pub fn with_timings(mut self, timings: Timings) -> Self {
+        self.timings = timings;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cadence(&self) -> Option<&str> {
+        self.cadence.as_deref()
+    }
+
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    pub fn criterion(&self) -> Option<&str> {
+        self.criterion.as_deref()
+    }
+
+    /// Name of the project and the Cadence version it was tested with, if set
+    pub fn label(&self) -> String {
+        match &self.cadence {
+            Some(c) => format!("{} @ {}", self.name, c),
+            None => self.name.clone(),
+        }
+    }
+
+    pub fn error(&self) -> Option<&CraterError> {
+        self.error.as_ref()
+    }
+
+    /// Errors emitted by the compiler if the project failed to build, empty otherwise
+    pub fn compiler_errors(&self) -> &[CompilerError] {
+        let mut cur = self.error.as_ref().map(|e| e as &(dyn Error + 'static));
+
+        while let Some(e) = cur {
+            if let Some(errors) = e.downcast_ref::<CompilerErrors>() {
+                return errors.errors();
+            }
+
+            cur = e.source();
+        }
+
+        &[]
+    }
+
+    pub fn baseline(&self) -> Option<&Result<(), String>> {
+        self.baseline.as_ref()
+    }
+
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    pub fn commands(&self) -> &[String] {
+        &self.commands
+    }
+
+    pub fn patched_files(&self) -> &[PathBuf] {
+        &self.patched_files
+    }
+
+    /// Unified diff of the files changed when patching, empty if nothing was patched
+    /// or this outcome was read from a previous report
+    pub fn manifest_diff(&self) -> &str {
+        &self.manifest_diff
+    }
+
+    /// Full output of every cargo command run, empty if cargo wasn't run or this
+    /// outcome was read from a previous report
+    pub fn cargo_log(&self) -> &str {
+        &self.cargo_log
+    }
+
+    pub fn fetched(&self) -> Option<FetchKind> {
+        self.fetched
+    }
+
+    pub fn sha(&self) -> Option<&str> {
+        self.sha.as_deref()
+    }
+
+    pub fn usage(&self) -> &BTreeMap<String, usize> {
+        &self.usage
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Reason the project wasn't started, if it wasn't
+    pub fn not_started_reason(&self) -> Option<&str> {
+        self.not_started.as_deref()
+    }
+
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    pub fn status(&self) -> Status {
+        if self.not_started.is_some() {
+            return Status::Skipped;
+        }
+
+        match (&self.error, &self.baseline) {
+            (None, _) => Status::Passed,
+            (Some(_), Some(Err(_))) => Status::AlreadyFailing,
+            (Some(_), _) => Status::Failed,
+        }
+    }
+
+    /// Returns true unless the project failed (in a way the baseline, if any, didn't)
+    ///
+    /// Projects that weren't started haven't failed.
+    pub fn is_success(&self) -> bool {
+        self.status() != Status::Failed
+    }
+
+    /// Full outcome as JSON, as written to `outcome.json` by `RunReport::write_dir`
+    pub fn to_json(&self) -> Value {
+        let compiler_errors: Vec<Value> = self
+            .compiler_errors()
+            .iter()
+            .map(|e| {
+                json!({
+                    "package": e.package(),
+                    "message": e.message(),
+                    "file": e.file(),
+                    "line": e.line(),
+                    "column": e.column(),
+                })
+            })
+            .collect();
+
+        let baseline = self.baseline.as_ref().map(|b| match b {
+            Ok(_) => json!({"status": "passed"}),
+            Err(e) => json!({"status": "failed", "error": e}),
+        });
+
+        let timings: serde_json::Map<String, Value> = self
+            .timings
+            .phases()
+            .iter()
+            .map(|(phase, duration)| (phase.clone(), json!(duration.as_secs_f64())))
+            .collect();
+
+        json!({
+            "name": self.name,
+            "cadence": self.cadence,
+            "target": self.target,
+            "criterion": self.criterion,
+            "status": self.status().as_str(),
+            "error": self.error.as_ref().map(|e| e.to_string()),
+            "compiler_errors": compiler_errors,
+            "baseline": baseline,
+            "skipped": self.skipped,
+            "warnings": self.warnings.iter().map(|w| w.message()).collect::<Vec<&str>>(),
+            "commands": self.commands,
+            "patched_files": self.patched_files,
+            "fetched": self.fetched.map(|f| f.as_str()),
+            "sha": self.sha,
+            "cadence_usage": self.usage,
+            "tags": self.tags,
+            "not_started": self.not_started,
+            "timings": timings,
+        })
+    }
+
+    /// Recreate an outcome from the JSON written by `to_json`, such as when resuming a run
+    ///
+    /// Errors are restored as their message only, except for compiler errors which keep
+    /// their structure. `None` is returned if the JSON isn't an outcome.
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let name = value.get("name")?.as_str()?;
+        let compiler_errors: Vec<CompilerError> = match value.get("compiler_errors") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => Vec::new(),
+        };
+
+        let error = value.get("error").and_then(|e| e.as_str()).map(|msg| {
+            if compiler_errors.is_empty() {
+                return CraterError::new(msg);
+            }
+
+            let errors = CompilerErrors::new(compiler_errors);
+            let suffix = format!(": {}", errors);
+            CraterError::new_err(msg.strip_suffix(&suffix).unwrap_or(msg), errors)
+        });
+
+        let baseline = value.get("baseline").filter(|b| !b.is_null()).map(|b| {
+            match b.get("status").and_then(|s| s.as_str()) {
+                Some("passed") => Ok(()),
+                _ => Err(b
+                    .get("error")
+                    .and_then(|e| e.as_str())
+                    .unwrap_or("")
+                    .to_owned()),
+            }
+        });
+
+        let skipped: Vec<String> = match value.get("skipped") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => Vec::new(),
+        };
+
+        let warnings: Vec<String> = match value.get("warnings") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => Vec::new(),
+        };
+
+        let commands: Vec<String> = match value.get("commands") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => Vec::new(),
+        };
+
+        let patched_files: Vec<PathBuf> = match value.get("patched_files") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => Vec::new(),
+        };
+
+        let usage: BTreeMap<String, usize> = match value.get("cadence_usage") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => BTreeMap::new(),
+        };
+
+        let tags: Vec<String> = match value.get("tags") {
+            Some(v) => serde_json::from_value(v.clone()).ok()?,
+            None => Vec::new(),
+        };
+
+        let mut phases: Vec<(String, Duration)> = value
+            .get("timings")
+            .and_then(|t| t.as_object())
+            .map(|t| {
+                t.iter()
+                    .filter_map(|(phase, secs)| {
+                        secs.as_f64()
+                            .filter(|s| *s >= 0.0)
+                            .map(|s| (phase.clone(), Duration::from_secs_f64(s)))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        phases.sort_by_key(|(phase, _)| {
+            PHASE_ORDER
+                .iter()
+                .position(|p| p == phase)
+                .unwrap_or(PHASE_ORDER.len())
+        });
+
+        Some(ProjectOutcome {
+            name: name.to_owned(),
+            cadence: value
+                .get("cadence")
+                .and_then(|c| c.as_str())
+                .map(|c| c.to_owned()),
+            target: value
+                .get("target")
+                .and_then(|t| t.as_str())
+                .map(|t| t.to_owned()),
+            criterion: value
+                .get("criterion")
+                .and_then(|c| c.as_str())
+                .map(|c| c.to_owned()),
+            error,
+            baseline,
+            skipped,
+            warnings: warnings.into_iter().map(Warning::new).collect(),
+            commands,
+            patched_files,
+            manifest_diff: String::new(),
+            cargo_log: String::new(),
+            fetched: value
+                .get("fetched")
+                .and_then(|f| f.as_str())
+                .and_then(FetchKind::from_name),
+            sha: value
+                .get("sha")
+                .and_then(|s| s.as_str())
+                .map(|s| s.to_owned()),
+            usage,
+            tags,
+            not_started: value
+                .get("not_started")
+                .and_then(|r| r.as_str())
+                .map(|r| r.to_owned()),
+            timings: Timings { phases },
+        })
+    }
+}
+
+/// First line of the error of a project or why it was skipped, if either
+fn details(project: &ProjectOutcome) -> String {
+    let details = match (project.not_started_reason(), project.error()) {
+        (Some(reason), _) => reason.to_owned(),
+        (None, Some(e)) => e.to_string(),
+        (None, None) => String::new(),
+    };
+
+    details.lines().next().unwrap_or_default().to_owned()
+}
+
+/// Escape a value for a cell of a Markdown table
+fn markdown_cell(value: &str) -> String {
+    value.replace('|', "\\|")
+}
+
+/// Quote a value for a CSV field if it contains a comma, quote, or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Maximum number of Cadence items listed for each project in the summary
+const USAGE_SHOWN: usize = 10;
+
+/// Describe the Cadence items used most by a project, with the number of references
+/// to each, such as `StatsdClient (3), prelude::* (1)`
+fn usage_summary(usage: &BTreeMap<String, usize>) -> String {
+    let mut items: Vec<(&String, &usize)> = usage.iter().collect();
+    items.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    let mut shown: Vec<String> = items
+        .iter()
+        .take(USAGE_SHOWN)
+        .map(|(item, count)| format!("{} ({})", item, count))
+        .collect();
+    if items.len() > USAGE_SHOWN {
+        shown.push(format!("and {} more", items.len() - USAGE_SHOWN));
+    }
+
+    shown.join(", ")
+}
+
+/// Local Cadence checkout or published version that projects were tested with
+#[derive(Debug, PartialEq, Eq)]
+struct TestedCadence {
+    version: String,
+    path: Option<String>,
+    sha: Option<String>,
+}
+
+/// Outcomes of every project processed during a run
+#[derive(Debug, Default)]
+pub struct RunReport {
+    cadences: Vec<TestedCadence>,
+    projects: Vec<ProjectOutcome>,
+    only_failures: bool,
+    unpatched: bool,
+    color: bool,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave projects that passed out of the human readable summary, they're still
+    /// counted and included in every other report
+    pub fn with_only_failures(mut self, only_failures: bool) -> Self {
+        self.only_failures = only_failures;
+        self
+    }
+
+    /// Note that projects were built and tested as they are, without being patched to
+    /// use any Cadence version
+    pub fn with_unpatched(mut self, unpatched: bool) -> Self {
+        self.unpatched = unpatched;
+        self
+    }
+
+    /// Color the status of each project and warnings in the human readable summary
+    /// and matrix (green for passed, red for failed, yellow for warnings)
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Apply the style if colors are enabled for the report
+    fn paint<D: fmt::Display>(&self, style: Style, text: D) -> String {
+        style.force_styling(self.color).apply_to(text).to_string()
+    }
+
+    /// Record a local Cadence version, its canonical path, and the git commit it's
+    /// at (if it's a git checkout) that projects are tested with. Versions published
+    /// to crates.io don't have a path.
+    pub fn add_cadence(&mut self, version: String, path: Option<String>, sha: Option<String>) {
+        self.cadences.push(TestedCadence { version, path, sha });
+    }
+
+    /// Write the local Cadence versions being tested, once at the start of a run
+    pub fn write_header<W: Write>(&self, mut out: W) -> io::Result<()> {
+        if self.unpatched {
+            writeln!(out, "testing projects as they are without patching Cadence")?;
+        }
+
+        for cadence in self.cadences.iter() {
+            match (&cadence.path, &cadence.sha) {
+                (Some(path), Some(sha)) => writeln!(
+                    out,
+                    "testing with Cadence {} at {} (commit {})",
+                    cadence.version, path, sha
+                )?,
+                (Some(path), None) => {
+                    writeln!(out, "testing with Cadence {} at {}", cadence.version, path)?
+                }
+                (None, _) => writeln!(
+                    out,
+                    "testing with Cadence {} from crates.io",
+                    cadence.version
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, outcome: ProjectOutcome) {
+        self.projects.push(outcome);
+    }
+
+    pub fn projects(&self) -> &[ProjectOutcome] {
+        &self.projects
+    }
+
+    /// Get the number of projects that were not processed successfully
+    pub fn failures(&self) -> usize {
+        self.projects.iter().filter(|p| !p.is_success()).count()
+    }
+
+    /// Write a human readable summary of each project outcome
+    pub fn write_summary<W: Write>(&self, mut out: W) -> io::Result<()> {
+        for project in self.shown() {
+            let status = project.status();
+            let marker = |text: &str| self.paint(status.style(), format!("{:<6}", text));
+
+            match (status, project.error()) {
+                (Status::Skipped, _) => writeln!(
+                    out,
+                    "{} {}: {}",
+                    marker("SKIP"),
+                    project.label(),
+                    project.not_started_reason().unwrap_or("")
+                )?,
+                (Status::Passed, _) | (_, None) => {
+                    writeln!(out, "{} {}", marker("ok"), project.label())?
+                }
+                (Status::Failed, Some(e)) => {
+                    writeln!(out, "{} {}: {}", marker("FAIL"), project.label(), e)?
+                }
+                (Status::AlreadyFailing, Some(e)) => {
+                    writeln!(out, "{} {}: {}", marker("BROKEN"), project.label(), e)?
+                }
+            }
+
+            for err in project.compiler_errors() {
+                writeln!(out, "         {}", err)?;
+            }
+
+            if !project.skipped().is_empty() {
+                writeln!(out, "         skipped: {}", project.skipped().join(", "))?;
+            }
+
+            if let Some(target) = project.target() {
+                writeln!(out, "         target: {}", target)?;
+            }
+
+            if let Some(criterion) = project.criterion() {
+                writeln!(out, "         criterion: {}", criterion)?;
+            }
+
+            if project.status() != Status::Passed && project.status() != Status::Skipped {
+                // Checkouts left by previous runs may be stale and explain a failure
+                if project.fetched() == Some(FetchKind::Reused) {
+                    writeln!(out, "         checkout: {}", FetchKind::Reused)?;
+                }
+
+                for command in project.commands() {
+                    writeln!(out, "         command: {}", command)?;
+                }
+
+                // What a project uses from Cadence can explain why it broke
+                if !project.usage().is_empty() {
+                    writeln!(out, "         uses: {}", usage_summary(project.usage()))?;
+                }
+            }
+
+            if !project.timings().is_empty() {
+                writeln!(out, "         time: {}", project.timings())?;
+            }
+        }
+
+        for project in self.projects.iter() {
+            for warning in project.warnings() {
+                let prefix = self.paint(Style::new().yellow(), "warning:");
+                writeln!(out, "{} {}: {}", prefix, project.label(), warning)?;
+            }
+        }
+
+        for totals in self.tag_totals() {
+            writeln!(out, "{}", totals)?;
+        }
+
+        if self.unpatched {
+            writeln!(out, "no patch was applied to any project")?;
+        }
+
+        writeln!(out, "{}", self.totals())
+    }
+
+    /// Number of projects with each tag that passed and failed, such as `tag core: 2
+    /// of 3 passed, 1 failed`, in order of tag
+    fn tag_totals(&self) -> Vec<String> {
+        let mut tags: BTreeMap<&str, (usize, usize, usize)> = BTreeMap::new();
+        for project in self.projects.iter() {
+            for tag in project.tags() {
+                let (total, passed, failed) = tags.entry(tag.as_str()).or_insert((0, 0, 0));
+                *total += 1;
+                match project.status() {
+                    Status::Passed => *passed += 1,
+                    Status::Failed => *failed += 1,
+                    _ => {}
+                }
+            }
+        }
+
+        tags.into_iter()
+            .map(|(tag, (total, passed, failed))| {
+                format!("tag {}: {} of {} passed, {} failed", tag, passed, total, failed)
+            })
+            .collect()
+    }
+
+    /// Write the summary as Markdown, with a table of the projects suitable for a
+    /// GitHub comment
+    ///
+    /// The error of each project that failed is shortened to its first line and a
+    /// column for the Cadence version is added when testing more than one.
+    pub fn write_markdown<W: Write>(&self, mut out: W) -> io::Result<()> {
+        if self.unpatched {
+            writeln!(out, "No patch was applied to any project.\n")?;
+        }
+
+        writeln!(out, "**{}**\n", self.totals())?;
+
+        let with_cadence = self.projects.iter().any(|p| p.cadence().is_some());
+        if with_cadence {
+            writeln!(out, "| Project | Cadence | Status | Details |")?;
+            writeln!(out, "| --- | --- | --- | --- |")?;
+        } else {
+            writeln!(out, "| Project | Status | Details |")?;
+            writeln!(out, "| --- | --- | --- |")?;
+        }
+
+        for project in self.shown() {
+            let mut details = details(project);
+            if !project.warnings().is_empty() {
+                details.push_str(&format!(" ({} warning(s))", project.warnings().len()));
+            }
+
+            write!(out, "| {} |", markdown_cell(project.name()))?;
+            if with_cadence {
+                write!(out, " {} |", markdown_cell(project.cadence().unwrap_or("")))?;
+            }
+
+            writeln!(
+                out,
+                " {} | {} |",
+                project.status().as_str().replace('_', " "),
+                markdown_cell(details.trim())
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the summary as CSV, with a header and a row for each project
+    ///
+    /// The columns are the project, the Cadence version when testing more than one,
+    /// the status, the number of warnings, the total time in seconds, and the error.
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "project,cadence,status,warnings,seconds,error")?;
+
+        for project in self.shown() {
+            let total: Duration = project.timings().phases().iter().map(|(_, d)| *d).sum();
+
+            writeln!(
+                out,
+                "{},{},{},{},{:.2},{}",
+                csv_field(project.name()),
+                csv_field(project.cadence().unwrap_or("")),
+                project.status().as_str(),
+                project.warnings().len(),
+                total.as_secs_f64(),
+                csv_field(&details(project)),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the summary as JSON: the same totals as `summary.json` written by
+    /// `write_dir` with the full outcome of each project
+    pub fn write_json<W: Write>(&self, mut out: W) -> io::Result<()> {
+        let projects = self.shown().map(|p| p.to_json()).collect();
+        serde_json::to_writer_pretty(&mut out, &self.summary_json(projects))?;
+        writeln!(out)
+    }
+
+    /// Projects shown in the summary, leaving out those that passed when only
+    /// failures are reported
+    fn shown(&self) -> impl Iterator<Item = &ProjectOutcome> {
+        self.projects
+            .iter()
+            .filter(move |p| !self.only_failures || p.status() != Status::Passed)
+    }
+
+    /// Number of projects with each status, such as `2 passed, 1 failed, 0 already
+    /// failing without the patch`
+    fn totals(&self) -> String {
+        let mut totals = format!(
+            "{} passed, {} failed, {} already failing without the patch",
+            self.count(Status::Passed),
+            self.failures(),
+            self.count(Status::AlreadyFailing)
+        );
+
+        let skipped = self.count(Status::Skipped);
+        if skipped > 0 {
+            totals.push_str(&format!(", {} skipped", skipped));
+        }
+
+        totals
+    }
+
+    /// Totals and Cadence versions of the run as JSON, with the given entries for
+    /// each project
+    fn summary_json(&self, projects: Vec<Value>) -> Value {
+        let cadences: Vec<Value> = self
+            .cadences
+            .iter()
+            .map(|c| json!({"version": c.version, "path": c.path, "sha": c.sha}))
+            .collect();
+
+        json!({
+            "cadences": cadences,
+            "patched": !self.unpatched,
+            "passed": self.count(Status::Passed),
+            "failed": self.count(Status::Failed),
+            "already_failing": self.count(Status::AlreadyFailing),
+            "skipped": self.count(Status::Skipped),
+            "warnings": self.projects.iter().map(|p| p.warnings().len()).sum::<usize>(),
+            "projects": projects,
+        })
+    }
+
+    /// Get the number of projects with the given status
+    fn count(&self, status: Status) -> usize {
+        self.projects
+            .iter()
+            .filter(|p| p.status() == status)
+            .count()
+    }
+
+    /// Write a table of the status of each project with each of the given Cadence versions
+    ///
+    /// Projects that failed before they could be tested with any Cadence version are
+    /// shown with the same status for every version.
+    pub fn write_matrix<W: Write>(&self, mut out: W, cadences: &[String]) -> io::Result<()> {
+        let mut names: Vec<&str> = Vec::new();
+        for project in self.projects.iter() {
+            if !names.contains(&project.name()) {
+                names.push(project.name());
+            }
+        }
+
+        for (i, cadence) in cadences.iter().enumerate() {
+            writeln!(out, "[{}] {}", i + 1, cadence)?;
+        }
+
+        write!(out, "{:<8}", "")?;
+        for i in 0..cadences.len() {
+            write!(out, "{:<8}", format!("[{}]", i + 1))?;
+        }
+        writeln!(out)?;
+
+        for name in names {
+            for cadence in cadences.iter() {
+                let status = self
+                    .projects
+                    .iter()
+                    .filter(|p| p.name() == name)
+                    .find(|p| p.cadence().map(|c| c == cadence).unwrap_or(true))
+                    .map(|p| {
+                        let text = match p.status() {
+                            Status::Passed => "ok",
+                            Status::Failed => "FAIL",
+                            Status::AlreadyFailing => "BROKEN",
+                            Status::Skipped => "SKIP",
+                        };
+
+                        self.paint(p.status().style(), format!("{:<8}", text))
+                    })
+                    .unwrap_or_else(|| format!("{:<8}", "-"));
+
+                write!(out, "{}", status)?;
+            }
+
+            writeln!(out, "{}", name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a JUnit XML report with a test case for each project
+    pub fn write_junit<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            out,
+            r#"<testsuite name="{}" tests="{}" failures="{}">"#,
+            JUNIT_SUITE,
+            self.projects.len(),
+            self.failures()
+        )?;
+
+        for project in self.projects.iter() {
+            let name = xml_escape(&project.label());
+
+            match (project.status(), project.error()) {
+                (Status::Skipped, _) => {
+                    writeln!(
+                        out,
+                        r#"  <testcase classname="{}" name="{}">"#,
+                        JUNIT_SUITE, name
+                    )?;
+                    writeln!(
+                        out,
+                        r#"    <skipped message="{}"/>"#,
+                        xml_escape(project.not_started_reason().unwrap_or(""))
+                    )?;
+                    writeln!(out, "  </testcase>")?;
+                }
+                (Status::Passed, _) | (_, None) => writeln!(
+                    out,
+                    r#"  <testcase classname="{}" name="{}"/>"#,
+                    JUNIT_SUITE, name
+                )?,
+                (Status::AlreadyFailing, Some(_)) => {
+                    writeln!(
+                        out,
+                        r#"  <testcase classname="{}" name="{}">"#,
+                        JUNIT_SUITE, name
+                    )?;
+                    writeln!(out, r#"    <skipped message="failing without the patch"/>"#)?;
+                    writeln!(out, "  </testcase>")?;
+                }
+                (Status::Failed, Some(e)) => {
+                    let mut body = e.to_string();
+                    let message = body.lines().next().unwrap_or("").to_owned();
+                    for err in project.compiler_errors() {
+                        body.push('\n');
+                        body.push_str(&err.to_string());
+                    }
+
+                    writeln!(
+                        out,
+                        r#"  <testcase classname="{}" name="{}">"#,
+                        JUNIT_SUITE, name
+                    )?;
+                    writeln!(
+                        out,
+                        r#"    <failure message="{}">{}</failure>"#,
+                        xml_escape(&message),
+                        xml_escape(&body)
+                    )?;
+                    writeln!(out, "  </testcase>")?;
+                }
+            }
+        }
+
+        writeln!(out, "</testsuite>")
+    }
+
+    /// Write the complete report for archiving to the given directory
+    ///
+    /// The directory is created if needed and has the layout:
+    ///
+    /// * `summary.json`: the version, path, and git commit of each local Cadence,
+    ///   counts of each status and of warnings and, for every project, its name, Cadence version,
+    ///   status, and the subdirectory with its details
+    /// * `projects/<NNN>-<label>/outcome.json`: the full outcome of a project including
+    ///   any error, compiler errors, warnings, and baseline result. `NNN` is the position of the
+    ///   project in the run starting at 001 and `<label>` is the project name and
+    ///   Cadence version with anything but ASCII letters, digits, `.`, and `_` replaced
+    /// * `projects/<NNN>-<label>/error.log`: the full error, only present on failure
+    /// * `projects/<NNN>-<label>/manifest.diff`: unified diff of every file written when
+    ///   patching, only present if the project was patched
+    /// * `projects/<NNN>-<label>/cargo.log`: full output of every cargo command run for
+    ///   the project, only present if cargo was run
+    pub fn write_dir(&self, dir: &Path) -> io::Result<()> {
+        let projects_dir = dir.join("projects");
+        fs::create_dir_all(&projects_dir)?;
+
+        let mut entries = Vec::with_capacity(self.projects.len());
+
+        for (i, project) in self.projects.iter().enumerate() {
+            let name = format!("{:03}-{}", i + 1, dir_label(&project.label()));
+            let project_dir = projects_dir.join(&name);
+            fs::create_dir_all(&project_dir)?;
+
+            let outcome = serde_json::to_vec_pretty(&project.to_json())?;
+            fs::write(project_dir.join("outcome.json"), outcome)?;
+
+            if let Some(e) = project.error() {
+                fs::write(project_dir.join("error.log"), format!("{}\n", e))?;
+            }
+
+            if !project.manifest_diff().is_empty() {
+                fs::write(project_dir.join("manifest.diff"), project.manifest_diff())?;
+            }
+
+            if !project.cargo_log().is_empty() {
+                fs::write(project_dir.join("cargo.log"), project.cargo_log())?;
+            }
+
+            entries.push(json!({
+                "name": project.name(),
+                "cadence": project.cadence(),
+                "status": project.status().as_str(),
+                "dir": format!("projects/{}", name),
+            }));
+        }
+
+        fs::write(
+            dir.join("summary.json"),
+            serde_json::to_vec_pretty(&self.summary_json(entries))?,
+        )
+    }
+}
+
+/// Report written to an `--output-dir` by a previous run, read to carry over the
+/// outcomes of projects that haven't changed since
+#[derive(Debug, Default)]
+pub struct PreviousRun {
+    cadences: Vec<TestedCadence>,
+    patched: bool,
+    outcomes: Vec<Value>,
+}
+
+impl PreviousRun {
+    /// Read the report written to the given directory by `RunReport::write_dir`
+    ///
+    /// A directory without a report, such as before the first run, has no outcomes.
+    /// Outcomes that can't be read are left out and their projects will be run again.
+    pub fn read(dir: &Path) -> Result<Self, CraterError> {
+        let path = dir.join("summary.json");
+        let summary: Value = match fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).map_err(|e| {
+                CraterError::new_err(format!("unable to parse previous report {:?}", path), e)
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(CraterError::new_err(
+                    format!("unable to read previous report {:?}", path),
+                    e,
+                ))
+            }
+        };
+
+        let text = |v: &Value, key: &str| v.get(key).and_then(|s| s.as_str()).map(String::from);
+        let cadences = summary
+            .get("cadences")
+            .and_then(|c| c.as_array())
+            .map(|cadences| {
+                cadences
+                    .iter()
+                    .map(|c| TestedCadence {
+                        version: text(c, "version").unwrap_or_default(),
+                        path: text(c, "path"),
+                        sha: text(c, "sha"),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let outcomes = summary
+            .get("projects")
+            .and_then(|p| p.as_array())
+            .map(|projects| {
+                projects
+                    .iter()
+                    .filter_map(|p| text(p, "dir"))
+                    .filter_map(|d| fs::read(dir.join(d).join("outcome.json")).ok())
+                    .filter_map(|contents| serde_json::from_slice(&contents).ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(PreviousRun {
+            cadences,
+            patched: summary
+                .get("patched")
+                .and_then(|p| p.as_bool())
+                .unwrap_or(true),
+            outcomes,
+        })
+    }
+
+    /// Returns true if the previous run tested projects the same way as the given
+    /// report: with the same Cadence versions at the same paths and commits, and
+    /// either patching every project or none of them
+    pub fn same_cadences(&self, report: &RunReport) -> bool {
+        self.cadences == report.cadences && self.patched != report.unpatched
+    }
+
+    /// Outcomes of the named project if the previous run tested it at the given commit
+    /// and none of the `expected` number of outcomes (one per Cadence version) failed,
+    /// empty otherwise
+    pub fn outcomes(&self, name: &str, sha: &str, expected: usize) -> Vec<ProjectOutcome> {
+        let outcomes: Vec<ProjectOutcome> = self
+            .outcomes
+            .iter()
+            .filter_map(ProjectOutcome::from_json)
+            .filter(|o| o.name() == name && o.sha() == Some(sha))
+            .collect();
+
+        let passed = outcomes
+            .iter()
+            .all(|o| o.is_success() && o.status() != Status::Skipped);
+        if outcomes.len() != expected || !passed {
+            return Vec::new();
+        }
+
+        outcomes
+    }
+}
+
+/// Make a label safe to use as a directory name
+fn dir_label(label: &str) -> String {
+    let mut out = String::with_capacity(label.len());
+
+    for c in label.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            out.push(c);
+        } else if !out.ends_with('-') {
+            out.push('-');
+        }
+    }
+
+    out.trim_matches('-').to_owned()
+}
+
+/// Escape characters that aren't allowed in XML attributes or text
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dir_label, usage_summary, PreviousRun, ProjectOutcome, RunReport, Status, Warning,
+    };
+    use crate::runner::{CompilerError, CompilerErrors};
+    use crate::testing::ScratchDir;
+    use crate::types::CraterError;
+    use crate::vcs::FetchKind;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_write_junit() {
+        let mut report = RunReport::new();
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+        report.add(ProjectOutcome::new(
+            "https://example.com/bad.git",
+            Err(CraterError::new("unable to parse <Cargo.toml> & such")),
+        ));
+
+        let mut buf = Vec::new();
+        report.write_junit(&mut buf).unwrap();
+        let xml = String::from_utf8(buf).unwrap();
+
+        assert!(xml.contains(r#"<testsuite name="cadence-crater" tests="2" failures="1">"#));
+        assert!(xml.contains(
+            r#"<testcase classname="cadence-crater" name="https://example.com/ok.git"/>"#
+        ));
+        assert!(xml.contains(
+            r#"<failure message="unable to parse &lt;Cargo.toml&gt; &amp; such">unable to parse &lt;Cargo.toml&gt; &amp; such</failure>"#
+        ));
+    }
+
+    #[test]
+    fn test_write_summary_only_failures() {
+        let mut report = RunReport::new().with_only_failures(true);
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+        report.add(ProjectOutcome::new(
+            "https://example.com/bad.git",
+            Err(CraterError::new("build failed")),
+        ));
+
+        let mut buf = Vec::new();
+        report.write_summary(&mut buf).unwrap();
+        let summary = String::from_utf8(buf).unwrap();
+
+        assert!(!summary.contains("ok.git"));
+        assert!(summary.contains("FAIL   https://example.com/bad.git: build failed"));
+        assert!(summary.contains("1 passed, 1 failed"));
+    }
+
+    #[test]
+    fn test_write_summary_color() {
+        let mut report = RunReport::new().with_color(true);
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+        report.add(
+            ProjectOutcome::new(
+                "https://example.com/bad.git",
+                Err(CraterError::new("build failed")),
+            )
+            .with_warnings(vec![Warning::new("patch has no effect")]),
+        );
+
+        let mut buf = Vec::new();
+        report.write_summary(&mut buf).unwrap();
+        let summary = String::from_utf8(buf).unwrap();
+
+        assert!(summary.contains("\u{1b}[32mok    \u{1b}[0m https://example.com/ok.git"));
+        assert!(summary.contains("\u{1b}[31m\u{1b}[1mFAIL  \u{1b}[0m https://example.com/bad.git"));
+        assert!(summary.contains("\u{1b}[33mwarning:\u{1b}[0m https://example.com/bad.git"));
+    }
+
+    #[test]
+    fn test_write_summary_not_started() {
+        let mut report = RunReport::new();
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+        report.add(ProjectOutcome::not_started(
+            "https://example.com/late.git",
+            "time budget",
+        ));
+
+        let mut buf = Vec::new();
+        report.write_summary(&mut buf).unwrap();
+        let summary = String::from_utf8(buf).unwrap();
+        let restored = ProjectOutcome::from_json(&report.projects()[1].to_json()).unwrap();
+
+        assert!(summary.contains("SKIP   https://example.com/late.git: time budget"));
+        assert!(
+            summary.contains("1 passed, 0 failed, 0 already failing without the patch, 1 skipped")
+        );
+        assert_eq!(0, report.failures());
+        assert_eq!(Status::Skipped, restored.status());
+    }
+
+    #[test]
+    fn test_write_summary_unpatched() {
+        let mut report = RunReport::new().with_unpatched(true);
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+
+        let mut header = Vec::new();
+        report.write_header(&mut header).unwrap();
+        let mut summary = Vec::new();
+        report.write_summary(&mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+
+        assert_eq!(
+            "testing projects as they are without patching Cadence\n",
+            String::from_utf8(header).unwrap()
+        );
+        assert!(summary.contains("no patch was applied to any project\n1 passed"));
+    }
+
+    /// Report with a passing project, a failing one, and one skipped for time
+    fn mixed_report() -> RunReport {
+        let mut report = RunReport::new();
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+        report.add(
+            ProjectOutcome::new(
+                "https://example.com/a|b.git",
+                Err(CraterError::new("`cargo build` failed, badly\nsecond line")),
+            )
+            .with_warnings(vec![Warning::new("subproject 'baz' not found")]),
+        );
+        report.add(ProjectOutcome::not_started(
+            "https://example.com/late.git",
+            "time budget",
+        ));
+        report
+    }
+
+    #[test]
+    fn test_write_markdown() {
+        let mut out = Vec::new();
+        mixed_report().write_markdown(&mut out).unwrap();
+
+        assert_eq!(
+            "**1 passed, 1 failed, 0 already failing without the patch, 1 skipped**\n\n\
+             | Project | Status | Details |\n\
+             | --- | --- | --- |\n\
+             | https://example.com/ok.git | passed |  |\n\
+             | https://example.com/a\\|b.git | failed | `cargo build` failed, badly (1 warning(s)) |\n\
+             | https://example.com/late.git | skipped | time budget |\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let mut report = mixed_report().with_only_failures(true);
+        report.add(ProjectOutcome::new("c", Ok(())).with_cadence("0.21.0"));
+        let mut out = Vec::new();
+        report.write_csv(&mut out).unwrap();
+
+        assert_eq!(
+            "project,cadence,status,warnings,seconds,error\n\
+             https://example.com/a|b.git,,failed,1,0.00,\"`cargo build` failed, badly\"\n\
+             https://example.com/late.git,,skipped,0,0.00,time budget\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_write_json() {
+        let mut out = Vec::new();
+        mixed_report().write_json(&mut out).unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        assert_eq!(json!(1), summary["failed"]);
+        assert_eq!(json!(1), summary["skipped"]);
+        assert_eq!(3, summary["projects"].as_array().unwrap().len());
+        assert_eq!(json!("failed"), summary["projects"][1]["status"]);
+    }
+
+    #[test]
+    fn test_status_with_baseline() {
+        let passed = ProjectOutcome::new("a", Ok(())).with_baseline(Err("err".to_owned()));
+        let failed = ProjectOutcome::new("b", Err(CraterError::new("err"))).with_baseline(Ok(()));
+        let broken = ProjectOutcome::new("c", Err(CraterError::new("err")))
+            .with_baseline(Err("err".to_owned()));
+
+        assert_eq!(Status::Passed, passed.status());
+        assert_eq!(Status::Failed, failed.status());
+        assert_eq!(Status::AlreadyFailing, broken.status());
+        assert!(broken.is_success());
+    }
+
+    #[test]
+    fn test_outcome_json_round_trip() {
+        let errors: Vec<CompilerError> = serde_json::from_value(json!([{
+            "package": "foo",
+            "message": "cannot find value `x` in this scope",
+            "file": "src/lib.rs",
+            "line": 3,
+            "column": 5,
+        }]))
+        .unwrap();
+
+        let outcome = ProjectOutcome::new(
+            "https://example.com/foo.git",
+            Err(CraterError::new_err(
+                "`cargo build` failed",
+                CompilerErrors::new(errors),
+            )),
+        )
+        .with_cadence("0.21.0")
+        .with_criterion("build")
+        .with_baseline(Ok(()))
+        .with_skipped(vec!["bar".to_owned()])
+        .with_warnings(vec![Warning::new("subproject 'baz' not found")])
+        .with_commands(vec!["cd /tmp/foo && cargo build --workspace".to_owned()])
+        .with_patched_files(vec![PathBuf::from("/tmp/foo/Cargo.toml")])
+        .with_fetched(FetchKind::Reused)
+        .with_sha("abc123")
+        .with_usage(vec![("StatsdClient".to_owned(), 2)].into_iter().collect());
+
+        let restored = ProjectOutcome::from_json(&outcome.to_json()).unwrap();
+
+        assert_eq!(outcome.to_json(), restored.to_json());
+        assert_eq!(Status::Failed, restored.status());
+        assert_eq!(1, restored.compiler_errors().len());
+        assert_eq!(1, restored.warnings().len());
+        assert_eq!(1, restored.commands().len());
+        assert_eq!(
+            &[PathBuf::from("/tmp/foo/Cargo.toml")],
+            restored.patched_files()
+        );
+        assert_eq!(Some(FetchKind::Reused), restored.fetched());
+        assert_eq!(Some("build"), restored.criterion());
+        assert_eq!(Some(&2), restored.usage().get("StatsdClient"));
+        assert!(ProjectOutcome::from_json(&json!({"status": "passed"})).is_none());
+    }
+
+    #[test]
+    fn test_write_summary_usage() {
+        let usage: BTreeMap<String, usize> =
+            vec![("prelude::*".to_owned(), 1), ("StatsdClient".to_owned(), 3)]
+                .into_iter()
+                .collect();
+
+        let mut report = RunReport::new();
+        report.add(ProjectOutcome::new("a", Ok(())).with_usage(usage.clone()));
+        report.add(ProjectOutcome::new("b", Err(CraterError::new("err"))).with_usage(usage));
+
+        let mut out = Vec::new();
+        report.write_summary(&mut out).unwrap();
+        let summary = String::from_utf8(out).unwrap();
+
+        // Only failures list what they use since that's what could explain them
+        assert_eq!(1, summary.matches("uses:").count());
+        assert!(
+            summary.contains("FAIL   b: err\n         uses: StatsdClient (3), prelude::* (1)\n")
+        );
+
+        let many: BTreeMap<String, usize> = (0..12).map(|i| (format!("item{:02}", i), 1)).collect();
+        assert!(usage_summary(&many).ends_with("item09 (1), and 2 more"));
+    }
+
+    #[test]
+    fn test_write_summary_tags() {
+        let tags = |t: &[&str]| t.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+
+        let mut report = RunReport::new();
+        report.add(ProjectOutcome::new("a", Ok(())).with_tags(tags(&["core", "async"])));
+        report.add(
+            ProjectOutcome::new("b", Err(CraterError::new("err"))).with_tags(tags(&["core"])),
+        );
+        report.add(ProjectOutcome::new("c", Ok(())));
+
+        let mut out = Vec::new();
+        report.write_summary(&mut out).unwrap();
+        let summary = String::from_utf8(out).unwrap();
+
+        assert!(summary.contains(
+            "tag async: 1 of 1 passed, 0 failed\ntag core: 1 of 2 passed, 1 failed\n"
+        ));
+    }
+
+    #[test]
+    fn test_dir_label() {
+        assert_eq!(
+            "https-github.com-56quarters-cadence.git-0.25.0-home-cadence",
+            dir_label("https://github.com/56quarters/cadence.git @ 0.25.0 (/home/cadence)")
+        );
+    }
+
+    #[test]
+    fn test_previous_run() {
+        let dir = ScratchDir::new("previous");
+        let empty = PreviousRun::read(&dir.join("missing")).unwrap();
+
+        let mut report = RunReport::new();
+        report.add_cadence("0.21.0".to_owned(), Some("/src/cadence".to_owned()), None);
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())).with_sha("abc"));
+        report.add(
+            ProjectOutcome::new(
+                "https://example.com/bad.git",
+                Err(CraterError::new("build failed")),
+            )
+            .with_sha("def"),
+        );
+        report.write_dir(&dir).unwrap();
+
+        let previous = PreviousRun::read(&dir).unwrap();
+
+        let mut changed = RunReport::new();
+        changed.add_cadence("0.22.0".to_owned(), Some("/src/cadence".to_owned()), None);
+        let carried = previous.outcomes("https://example.com/ok.git", "abc", 1);
+
+        assert!(!empty.same_cadences(&report));
+        assert!(previous.same_cadences(&report));
+        assert!(!previous.same_cadences(&changed));
+        assert!(!previous.same_cadences(&RunReport::new().with_unpatched(true)));
+        assert_eq!(1, carried.len());
+        assert_eq!(Some("abc"), carried[0].sha());
+        assert!(previous
+            .outcomes("https://example.com/ok.git", "abd", 1)
+            .is_empty());
+        assert!(previous
+            .outcomes("https://example.com/ok.git", "abc", 2)
+            .is_empty());
+        assert!(previous
+            .outcomes("https://example.com/bad.git", "def", 1)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_write_dir() {
+        use std::fs;
+
+        let dir = ScratchDir::new("report");
+        let mut report = RunReport::new();
+        report.add(ProjectOutcome::new("https://example.com/ok.git", Ok(())));
+        report.add(
+            ProjectOutcome::new(
+                "https://example.com/bad.git",
+                Err(CraterError::new("build failed")),
+            )
+            .with_manifest_diff("--- a/Cargo.toml\n+++ b/Cargo.toml\n")
+            .with_cargo_log("$ cargo build\nerror: could not compile\n"),
+        );
+
+        report.write_dir(&dir).unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.join("summary.json")).unwrap()).unwrap();
+        let error =
+            fs::read_to_string(dir.join("projects/002-https-example.com-bad.git/error.log"));
+        let diff =
+            fs::read_to_string(dir.join("projects/002-https-example.com-bad.git/manifest.diff"));
+        let log = fs::read_to_string(dir.join("projects/002-https-example.com-bad.git/cargo.log"));
+        let ok_log = dir.join("projects/001-https-example.com-ok.git/cargo.log");
+        let ok_error = dir.join("projects/001-https-example.com-ok.git/error.log");
+        let ok_outcome = dir.join("projects/001-https-example.com-ok.git/outcome.json");
+        let (ok_error, ok_outcome) = (ok_error.exists(), ok_outcome.exists());
+
+        assert_eq!(true, summary["patched"]);
+        assert_eq!(1, summary["passed"]);
+        assert_eq!(1, summary["failed"]);
+        assert_eq!("failed", summary["projects"][1]["status"]);
+        assert_eq!("build failed\n", error.unwrap());
+        assert_eq!("--- a/Cargo.toml\n+++ b/Cargo.toml\n", diff.unwrap());
+        assert_eq!("$ cargo build\nerror: could not compile\n", log.unwrap());
+        assert!(ok_outcome);
+        assert!(!ok_error);
+        assert!(!ok_log.exists());
+    }
+}
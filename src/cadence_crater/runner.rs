@@ -0,0 +1,1073 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::types::CraterError;
+use cargo_metadata::diagnostic::DiagnosticLevel;
+use cargo_metadata::Message;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Number of lines of output from a failed cargo command to include in errors
+const OUTPUT_TAIL_LINES: usize = 20;
+
+/// Maximum number of lines of test output from a failed cargo command to include in
+/// errors, enough for the panic messages of several failed tests
+const FAILURES_MAX_LINES: usize = 100;
+
+/// Error reported by the compiler while building a project, located at the
+/// primary span of the diagnostic if it had one
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct CompilerError {
+    package: String,
+    message: String,
+    file: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl CompilerError {
+    /// Name of the package being compiled when the error was emitted
+    pub fn package(&self) -> &str {
+        &self.package
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    pub fn column(&self) -> Option<usize> {
+        self.column
+    }
+}
+
+impl fmt::Display for CompilerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line, self.column) {
+            (Some(file), Some(line), Some(col)) => write!(
+                f,
+                "{}: {}:{}:{}: {}",
+                self.package, file, line, col, self.message
+            ),
+            _ => write!(f, "{}: {}", self.package, self.message),
+        }
+    }
+}
+
+/// Every compiler error emitted by a failed cargo command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompilerErrors(Vec<CompilerError>);
+
+impl CompilerErrors {
+    pub fn new(errors: Vec<CompilerError>) -> Self {
+        CompilerErrors(errors)
+    }
+
+    pub fn errors(&self) -> &[CompilerError] {
+        &self.0
+    }
+}
+
+impl fmt::Display for CompilerErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.len() {
+            1 => write!(f, "1 compiler error"),
+            n => write!(f, "{} compiler errors", n),
+        }
+    }
+}
+
+impl Error for CompilerErrors {}
+
+/// Single target of a project to build and test, written like the cargo options
+/// that select it such as `--lib` or `--example foo`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Lib,
+    Bin(String),
+    Example(String),
+    Test(String),
+    Bench(String),
+}
+
+impl Target {
+    /// Arguments passed to cargo to select this target
+    fn args(&self) -> Vec<String> {
+        match self {
+            Target::Lib => vec!["--lib".to_owned()],
+            Target::Bin(name) => vec!["--bin".to_owned(), name.clone()],
+            Target::Example(name) => vec!["--example".to_owned(), name.clone()],
+            Target::Test(name) => vec!["--test".to_owned(), name.clone()],
+            Target::Bench(name) => vec!["--bench".to_owned(), name.clone()],
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = CraterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let target = match parts.as_slice() {
+            ["--lib"] => Some(Target::Lib),
+            ["--bin", name] => Some(Target::Bin((*name).to_owned())),
+            ["--example", name] => Some(Target::Example((*name).to_owned())),
+            ["--test", name] => Some(Target::Test((*name).to_owned())),
+            ["--bench", name] => Some(Target::Bench((*name).to_owned())),
+            _ => None,
+        };
+
+        target.ok_or_else(|| {
+            CraterError::new(format!(
+                "invalid target '{}', expected one of --lib, --bin NAME, --example NAME, \
+                 --test NAME, or --bench NAME",
+                s
+            ))
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.args().join(" "))
+    }
+}
+
+/// Full output of every cargo command run by a runner, shared by its clones
+///
+/// Compiler messages are written as they're rendered by cargo rather than as JSON.
+#[derive(Debug, Default, Clone)]
+pub struct CargoLog(Arc<Mutex<String>>);
+
+impl CargoLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get everything logged so far
+    pub fn contents(&self) -> String {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Add the command line, output, and exit status of a cargo command to the log
+    fn record(&self, command: &str, output: &Output) {
+        let mut log = self.0.lock().unwrap();
+        log.push_str(&format!("$ {}\n", command));
+        log.push_str(&rendered_output(&output.stdout));
+        log.push_str(&String::from_utf8_lossy(&output.stderr));
+        if !log.ends_with('\n') {
+            log.push('\n');
+        }
+
+        if !output.status.success() {
+            log.push_str(&format!("{}\n", output.status));
+        }
+
+        log.push('\n');
+    }
+}
+
+/// Build and test a project with cargo
+#[derive(Debug, Default, Clone)]
+pub struct CargoRunner {
+    deny_warnings: bool,
+    jobs: Option<usize>,
+    exclude: Vec<String>,
+    offline: bool,
+    locked: bool,
+    env: BTreeMap<String, String>,
+    targets: Vec<Target>,
+    target_triple: Option<String>,
+    build_only: bool,
+    retries: usize,
+    test_command: Option<Vec<String>>,
+    log: Option<CargoLog>,
+}
+
+impl CargoRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Treat compiler warnings in the project as errors when building and testing
+    ///
+    /// This adds `-D warnings` to any `RUSTFLAGS` already set by `with_env` or in the
+    /// environment.
+    pub fn with_deny_warnings(mut self, deny_warnings: bool) -> Self {
+        self.deny_warnings = deny_warnings;
+        self
+    }
+
+    /// Limit the number of parallel jobs used by cargo when building and testing
+    ///
+    /// This is passed to cargo as `-j` when set, otherwise cargo uses the number of CPUs.
+    pub fn with_jobs(mut self, jobs: Option<usize>) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Run cargo with `--offline` so that it fails instead of accessing the network
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Build and test with `--locked` so that cargo fails instead of changing the
+    /// `Cargo.lock` of the project
+    pub fn with_locked(mut self, locked: bool) -> Self {
+        self.locked = locked;
+        self
+    }
+
+    /// Record the full output of every cargo command in the given log
+    pub fn with_log(mut self, log: CargoLog) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Set environment variables for every cargo command, overriding any variables
+    /// with the same name inherited from the environment of crater itself
+    pub fn with_env(mut self, env: BTreeMap<String, String>) -> Self {
+        self.env = env;
+        self
+    }
+
+    /// Only build and test the given targets of the project instead of every target
+    pub fn with_targets(mut self, targets: Vec<Target>) -> Self {
+        self.targets = targets;
+        self
+    }
+
+    /// Build and test for the given target triple, passed to cargo as `--target`,
+    /// instead of the host
+    pub fn with_target_triple(mut self, triple: Option<String>) -> Self {
+        self.target_triple = triple;
+        self
+    }
+
+    /// Only build the project without running its tests, such as when it's built for
+    /// a target triple that can't run on this host
+    pub fn with_build_only(mut self, build_only: bool) -> Self {
+        self.build_only = build_only;
+        self
+    }
+
+    /// Build again up to `retries` times when a build fails without any compiler errors
+    ///
+    /// Failures without compiler errors in the JSON output, such as a rustc process
+    /// that was killed or a file lock that couldn't be taken, are usually transient.
+    /// Failures with compiler errors are never retried.
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn retries(&self) -> usize {
+        self.retries
+    }
+
+    /// Returns true if `test` doesn't run anything since the project is only built
+    pub fn is_build_only(&self) -> bool {
+        self.build_only
+    }
+
+    /// Run tests with the given cargo subcommand and arguments, such as `nextest run`,
+    /// instead of `cargo test`
+    ///
+    /// The workspace, target, job, exclude, and offline options are added after the
+    /// given arguments just like they are for `cargo test`. Compiler errors aren't
+    /// parsed from the output since `--message-format=json` isn't added.
+    pub fn with_test_command(mut self, command: Option<Vec<String>>) -> Self {
+        self.test_command = command;
+        self
+    }
+
+    /// Skip building and testing the named packages of a workspace
+    ///
+    /// Each package is passed to cargo with `--exclude`.
+    pub fn with_exclude(mut self, exclude: Vec<String>) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Build every crate in the project or workspace rooted at `dir`
+    ///
+    /// If warnings are denied and the build fails, the project is built again
+    /// allowing warnings to determine if the failure was only caused by warnings.
+    ///
+    /// When targets are set, each target is built separately instead.
+    pub fn build(&self, dir: &Path) -> Result<(), CraterError> {
+        self.build_retrying(dir, |_, _| {})
+    }
+
+    /// Build like `build`, calling `on_retry` with the number of the retry and the
+    /// error of the failed attempt before each build that's retried
+    pub fn build_retrying<F>(&self, dir: &Path, on_retry: F) -> Result<(), CraterError>
+    where
+        F: Fn(usize, &CraterError),
+    {
+        self.each_target(|target| {
+            let mut retry = 0;
+            loop {
+                match self.build_target(dir, target) {
+                    Err(e) if retry < self.retries && !has_compiler_errors(&e) => {
+                        retry += 1;
+                        on_retry(retry, &e);
+                    }
+                    res => return res,
+                }
+            }
+        })
+    }
+
+    fn build_target(&self, dir: &Path, target: Option<&Target>) -> Result<(), CraterError> {
+        let args = self.build_args(target);
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+        match self.cargo(dir, &args, self.deny_warnings) {
+            Ok(_) => Ok(()),
+            Err(e) if self.deny_warnings && self.cargo(dir, &args, false).is_ok() => {
+                Err(CraterError::new_err(
+                    format!(
+                        "`cargo {}` in {:?} failed only because of denied warnings",
+                        args.join(" "),
+                        dir
+                    ),
+                    e,
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Build only the crate rooted at `dir`, rather than every crate of the workspace
+    /// it belongs to, such as to check that a local Cadence checkout builds
+    pub fn build_crate(&self, dir: &Path) -> Result<(), CraterError> {
+        let mut args = vec!["build".to_owned(), "--message-format=json".to_owned()];
+        if let Some(jobs) = self.jobs {
+            args.push("-j".to_owned());
+            args.push(jobs.to_string());
+        }
+
+        if self.offline {
+            args.push("--offline".to_owned());
+        }
+
+        let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+        self.cargo(dir, &args, false).map(|_| ())
+    }
+
+    /// Run tests for every crate in the project or workspace rooted at `dir`
+    ///
+    /// When targets are set, the tests of each target are run separately instead.
+    /// When a test command is set, an error is returned without running anything if
+    /// its subcommand isn't installed or an alias in the project. Nothing is run when
+    /// the project is only built.
+    pub fn test(&self, dir: &Path) -> Result<(), CraterError> {
+        if self.build_only {
+            return Ok(());
+        }
+
+        if let Some(name) = self.test_command.as_ref().and_then(|c| c.first()) {
+            if !self.subcommands(dir)?.contains(name) {
+                return Err(CraterError::new(format!(
+                    "test command `cargo {}` isn't available in {:?}, it's not listed by \
+                     `cargo --list`",
+                    name, dir
+                )));
+            }
+        }
+
+        self.each_target(|target| {
+            let args = self.test_args(target);
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+
+            self.cargo(dir, &args, self.deny_warnings).map(|_| ())
+        })
+    }
+
+    /// Command lines that `build` and `test` run for the project rooted at `dir`, in
+    /// the order they're run
+    ///
+    /// Each includes the directory it's run in and any environment variables set by
+    /// crater so that it can be copied into a shell to reproduce a failure by hand.
+    pub fn commands(&self, dir: &Path) -> Vec<String> {
+        let mut targets: Vec<Option<&Target>> = self.targets.iter().map(Some).collect();
+        if targets.is_empty() {
+            targets.push(None);
+        }
+
+        let build = targets.iter().map(|target| self.build_args(*target));
+        let test = targets
+            .iter()
+            .filter(|_| !self.build_only)
+            .map(|target| self.test_args(*target));
+
+        build
+            .chain(test)
+            .map(|args| {
+                let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+                self.command_line(dir, &args, self.deny_warnings)
+            })
+            .collect()
+    }
+
+    /// Arguments for `cargo build` of every crate in a workspace, or only the given target
+    fn build_args(&self, target: Option<&Target>) -> Vec<String> {
+        self.compile_args(vec!["build".to_owned()], true, target)
+    }
+
+    /// Arguments for the test command of every crate in a workspace, or only the
+    /// given target
+    fn test_args(&self, target: Option<&Target>) -> Vec<String> {
+        match &self.test_command {
+            Some(command) => self.compile_args(command.clone(), false, target),
+            None => self.compile_args(vec!["test".to_owned()], true, target),
+        }
+    }
+
+    /// Run `f` for each target, or once with no target if there aren't any, stopping
+    /// at the first target that fails
+    fn each_target<F>(&self, f: F) -> Result<(), CraterError>
+    where
+        F: Fn(Option<&Target>) -> Result<(), CraterError>,
+    {
+        if self.targets.is_empty() {
+            return f(None);
+        }
+
+        for target in self.targets.iter() {
+            f(Some(target))
+                .map_err(|e| CraterError::new_err(format!("target {} failed", target), e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Arguments for a cargo command that compiles every crate in a workspace, or
+    /// only the given target, optionally with JSON messages to parse compiler errors from
+    fn compile_args(
+        &self,
+        command: Vec<String>,
+        json: bool,
+        target: Option<&Target>,
+    ) -> Vec<String> {
+        let mut args = command;
+        args.push("--workspace".to_owned());
+        if json {
+            args.push("--message-format=json".to_owned());
+        }
+
+        if let Some(target) = target {
+            args.extend(target.args());
+        }
+
+        if let Some(triple) = &self.target_triple {
+            args.push("--target".to_owned());
+            args.push(triple.clone());
+        }
+
+        if let Some(jobs) = self.jobs {
+            args.push("-j".to_owned());
+            args.push(jobs.to_string());
+        }
+
+        for package in self.exclude.iter() {
+            args.push("--exclude".to_owned());
+            args.push(package.clone());
+        }
+
+        if self.offline {
+            args.push("--offline".to_owned());
+        }
+
+        if self.locked {
+            args.push("--locked".to_owned());
+        }
+
+        args
+    }
+
+    /// Update every dependency of the project or workspace rooted at `dir` to the
+    /// latest compatible version with `cargo update`
+    ///
+    /// Returns true if the `Cargo.lock` of the workspace `dir` belongs to changed.
+    pub fn update(&self, dir: &Path) -> Result<bool, CraterError> {
+        let lockfile = self.lock_file(dir)?;
+        let before = fs::read(&lockfile).ok();
+
+        let mut args = vec!["update"];
+        if self.offline {
+            args.push("--offline");
+        }
+
+        self.cargo(dir, &args, false)?;
+        Ok(fs::read(&lockfile).ok() != before)
+    }
+
+    /// Get the name of every cargo subcommand available in `dir`, including aliases
+    /// and installed third party subcommands
+    fn subcommands(&self, dir: &Path) -> Result<Vec<String>, CraterError> {
+        let stdout = self.cargo(dir, &["--list"], false)?;
+
+        Ok(String::from_utf8_lossy(&stdout)
+            .lines()
+            .filter(|line| line.starts_with(char::is_whitespace))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(|name| name.trim_end_matches(',').to_owned())
+            .collect())
+    }
+
+    /// Get the path of the `Cargo.lock` of the workspace that `dir` belongs to, which
+    /// may not exist yet
+    pub fn lock_file(&self, dir: &Path) -> Result<PathBuf, CraterError> {
+        Ok(self.workspace_root(dir)?.join("Cargo.lock"))
+    }
+
+    /// Get the root directory of the workspace that `dir` belongs to
+    pub fn workspace_root(&self, dir: &Path) -> Result<PathBuf, CraterError> {
+        let args = ["locate-project", "--workspace", "--message-format", "plain"];
+        let stdout = self.cargo(dir, &args, false)?;
+        let manifest = PathBuf::from(String::from_utf8_lossy(&stdout).trim());
+
+        manifest
+            .parent()
+            .map(|p| p.to_path_buf())
+            .ok_or_else(|| CraterError::new(format!("unable to find workspace root of {:?}", dir)))
+    }
+
+    /// Get the manifest path of each package with the given name in the resolved
+    /// dependency graph of the project or workspace rooted at `dir`
+    pub fn package_manifests(&self, dir: &Path, name: &str) -> Result<Vec<PathBuf>, CraterError> {
+        let mut args = vec!["metadata", "--format-version", "1"];
+        if self.offline {
+            args.push("--offline");
+        }
+
+        let stdout = self.cargo(dir, &args, false)?;
+        let metadata: Value = serde_json::from_slice(&stdout).map_err(|e| {
+            CraterError::new_err(format!("unable to parse cargo metadata for {:?}", dir), e)
+        })?;
+
+        Ok(metadata
+            .get("packages")
+            .and_then(|p| p.as_array())
+            .map(|packages| {
+                packages
+                    .iter()
+                    .filter(|p| p.get("name").and_then(|n| n.as_str()) == Some(name))
+                    .filter_map(|p| p.get("manifest_path").and_then(|m| m.as_str()))
+                    .map(PathBuf::from)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Environment variables set for a cargo command in addition to those inherited
+    fn cargo_env(&self, deny_warnings: bool) -> BTreeMap<String, String> {
+        let mut vars = self.env.clone();
+
+        if deny_warnings {
+            let existing = self
+                .env
+                .get("RUSTFLAGS")
+                .cloned()
+                .or_else(|| env::var("RUSTFLAGS").ok());
+
+            let flags = match existing {
+                Some(existing) if !existing.trim().is_empty() => {
+                    format!("{} -D warnings", existing)
+                }
+                _ => "-D warnings".to_owned(),
+            };
+
+            vars.insert("RUSTFLAGS".to_owned(), flags);
+        }
+
+        vars
+    }
+
+    /// Shell command line equivalent to running cargo with the given arguments in `dir`
+    fn command_line(&self, dir: &Path, args: &[&str], deny_warnings: bool) -> String {
+        let mut parts = vec![
+            "cd".to_owned(),
+            shell_quote(&dir.to_string_lossy()),
+            "&&".to_owned(),
+        ];
+
+        for (name, value) in self.cargo_env(deny_warnings) {
+            parts.push(format!("{}={}", name, shell_quote(&value)));
+        }
+
+        parts.push("cargo".to_owned());
+        parts.extend(args.iter().map(|a| shell_quote(a)));
+        parts.join(" ")
+    }
+
+    fn cargo(
+        &self,
+        dir: &Path,
+        args: &[&str],
+        deny_warnings: bool,
+    ) -> Result<Vec<u8>, CraterError> {
+        let mut cmd = Command::new("cargo");
+        cmd.args(args)
+            .current_dir(dir)
+            .envs(self.cargo_env(deny_warnings));
+
+        let output = cmd.output().map_err(|e| {
+            CraterError::new_err(
+                format!("unable to run `cargo {}` in {:?}", args.join(" "), dir),
+                e,
+            )
+        })?;
+
+        if let Some(log) = &self.log {
+            log.record(&self.command_line(dir, args, deny_warnings), &output);
+        }
+
+        if output.status.success() {
+            return Ok(output.stdout);
+        }
+
+        let errors = compiler_errors(&output.stdout);
+        if !errors.is_empty() {
+            return Err(CraterError::new_err(
+                format!(
+                    "`cargo {}` in {:?} failed with {}",
+                    args.join(" "),
+                    dir,
+                    output.status
+                ),
+                CompilerErrors(errors),
+            ));
+        }
+
+        // Tests report their failures on stdout, with only a summary on stderr
+        let stdout = stdout_tail(&output.stdout);
+        let tail = if stdout.is_empty() {
+            output_tail(&output.stderr)
+        } else {
+            format!("{}\n{}", stdout, output_tail(&output.stderr))
+        };
+
+        Err(CraterError::new(format!(
+            "`cargo {}` in {:?} failed with {}:\n{}",
+            args.join(" "),
+            dir,
+            output.status,
+            tail
+        )))
+    }
+}
+
+/// Returns true if the error, or any error that caused it, has compiler errors from
+/// the JSON output of cargo
+fn has_compiler_errors(err: &CraterError) -> bool {
+    let mut cur: Option<&(dyn Error + 'static)> = Some(err);
+
+    while let Some(e) = cur {
+        if e.downcast_ref::<CompilerErrors>().is_some() {
+            return true;
+        }
+
+        cur = e.source();
+    }
+
+    false
+}
+
+/// Get the target triple of the host from `rustc -vV`, such as `x86_64-unknown-linux-gnu`
+pub fn host_triple() -> Result<String, CraterError> {
+    let output = Command::new("rustc")
+        .arg("-vV")
+        .output()
+        .map_err(|e| CraterError::new_err("unable to run `rustc -vV`", e))?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host:"))
+        .map(|host| host.trim().to_owned())
+        .filter(|host| output.status.success() && !host.is_empty())
+        .ok_or_else(|| {
+            CraterError::new("unable to determine the host target triple from `rustc -vV`")
+        })
+}
+
+/// Check that a test command is a cargo subcommand and its arguments, such as
+/// `["nextest", "run"]`, rather than a full command line or options alone
+pub fn check_test_command(command: &[String]) -> Result<(), CraterError> {
+    let name = match command.first() {
+        Some(name) => name,
+        None => return Err(CraterError::new("test command can't be empty")),
+    };
+
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if name == "cargo" {
+        Err(CraterError::new(
+            "test command should leave out `cargo`, e.g. [\"nextest\", \"run\"]",
+        ))
+    } else if !valid {
+        Err(CraterError::new(format!(
+            "invalid test command '{}', expected a cargo subcommand such as test or nextest",
+            name
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Run a command with the system shell in the given directory
+///
+/// The command is run with `sh -c` (or `cmd /C` on Windows) so it may use pipes,
+/// redirects, and other shell features. Errors are returned if the command can't
+/// be started or exits unsuccessfully. The given environment variables are set in
+/// addition to those inherited from the environment of crater itself.
+pub fn run_shell(
+    dir: &Path,
+    command: &str,
+    env: &BTreeMap<String, String>,
+) -> Result<(), CraterError> {
+    let mut cmd = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+
+    let output = cmd
+        .arg(command)
+        .current_dir(dir)
+        .envs(env)
+        .output()
+        .map_err(|e| {
+            CraterError::new_err(format!("unable to run `{}` in {:?}", command, dir), e)
+        })?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    Err(CraterError::new(format!(
+        "`{}` in {:?} failed with {}:\n{}",
+        command,
+        dir,
+        output.status,
+        output_tail(&output.stderr)
+    )))
+}
+
+/// Get the last lines of output from a command to include in an error
+fn output_tail(output: &[u8]) -> String {
+    let output = String::from_utf8_lossy(output);
+    let lines: Vec<&str> = output.lines().collect();
+    lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..].join("\n")
+}
+
+/// Get the last lines written to stdout by a cargo command that aren't JSON messages,
+/// starting from the `failures:` section written by the test harness if there is one
+fn stdout_tail(stdout: &[u8]) -> String {
+    let stdout = String::from_utf8_lossy(stdout);
+    let lines: Vec<&str> = stdout
+        .lines()
+        .filter(|l| !l.starts_with("{\"reason\":"))
+        .collect();
+
+    let start = lines
+        .iter()
+        .position(|l| *l == "failures:")
+        .unwrap_or_else(|| lines.len().saturating_sub(OUTPUT_TAIL_LINES))
+        .max(lines.len().saturating_sub(FAILURES_MAX_LINES));
+    lines[start..].join("\n")
+}
+
+/// Get what a cargo command wrote to stdout with compiler messages rendered as cargo
+/// would show them and any other JSON, such as artifact messages, left out
+fn rendered_output(stdout: &[u8]) -> String {
+    let stdout = String::from_utf8_lossy(stdout);
+    let mut out = String::new();
+
+    for line in stdout.lines() {
+        if !line.starts_with('{') {
+            out.push_str(line);
+            out.push('\n');
+        } else if let Ok(Message::CompilerMessage(msg)) = serde_json::from_str(line) {
+            if let Some(rendered) = msg.message.rendered {
+                out.push_str(&rendered);
+            }
+        }
+    }
+
+    out
+}
+
+/// Quote a word for a POSIX shell if it contains anything other than characters
+/// that are never special to the shell
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_=./:,@+%".contains(c);
+
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_owned()
+    } else {
+        format!("'{}'", word.replace('\'', r#"'\''"#))
+    }
+}
+
+/// Extract errors from the `compiler-message` entries written by cargo when run
+/// with `--message-format=json`, ignoring any lines that aren't JSON messages
+fn compiler_errors(stdout: &[u8]) -> Vec<CompilerError> {
+    let mut out: Vec<CompilerError> = Vec::new();
+
+    for msg in Message::parse_stream(stdout).flatten() {
+        let msg = match msg {
+            Message::CompilerMessage(m) => m,
+            _ => continue,
+        };
+
+        if !matches!(
+            msg.message.level,
+            DiagnosticLevel::Error | DiagnosticLevel::Ice
+        ) {
+            continue;
+        }
+
+        let span = msg.message.spans.iter().find(|s| s.is_primary);
+        let err = CompilerError {
+            package: msg.target.name.clone(),
+            message: msg.message.message.clone(),
+            file: span.map(|s| s.file_name.clone()),
+            line: span.map(|s| s.line_start),
+            column: span.map(|s| s.column_start),
+        };
+
+        // The same error is reported for each target (lib, tests, etc.) that
+        // includes the file so only keep the first one.
+        if !out.contains(&err) {
+            out.push(err);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_test_command, compiler_errors, has_compiler_errors, rendered_output, stdout_tail,
+        CargoLog, CargoRunner, CompilerErrors, Target,
+    };
+    use crate::testing::ScratchDir;
+    use crate::types::CraterError;
+    use std::cell::Cell;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    const OUTPUT: &str = r#"{"reason":"compiler-artifact","package_id":"cadence 0.25.0 (registry+https://github.com/rust-lang/crates.io-index)","manifest_path":"/cadence/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"cadence","src_path":"/cadence/src/lib.rs","edition":"2018","doc":true,"doctest":true,"test":true},"profile":{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false},"features":[],"filenames":[],"executable":null,"fresh":true}
+{"reason":"compiler-message","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"app","src_path":"/app/src/lib.rs","edition":"2018","doc":true,"doctest":true,"test":true},"message":{"message":"unused variable: `x`","code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[],"children":[],"rendered":"warning: unused variable"}}
+{"reason":"compiler-message","package_id":"app 0.1.0 (path+file:///app)","manifest_path":"/app/Cargo.toml","target":{"kind":["lib"],"crate_types":["lib"],"name":"app","src_path":"/app/src/lib.rs","edition":"2018","doc":true,"doctest":true,"test":true},"message":{"message":"cannot find type `NopMetricSink` in crate `cadence`","code":{"code":"E0412","explanation":null},"level":"error","spans":[{"file_name":"src/lib.rs","byte_start":10,"byte_end":23,"line_start":3,"line_end":3,"column_start":14,"column_end":27,"is_primary":true,"text":[],"label":"not found in `cadence`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error[E0412]: cannot find type"}}
+running 1 test
+{"reason":"build-finished","success":false}
+"#;
+
+    #[test]
+    fn test_compiler_errors() {
+        let errors = compiler_errors(OUTPUT.as_bytes());
+
+        assert_eq!(1, errors.len());
+        assert_eq!("app", errors[0].package());
+        assert_eq!(Some("src/lib.rs"), errors[0].file());
+        assert_eq!(Some(3), errors[0].line());
+        assert_eq!(Some(14), errors[0].column());
+        assert_eq!(
+            "app: src/lib.rs:3:14: cannot find type `NopMetricSink` in crate `cadence`",
+            errors[0].to_string()
+        );
+    }
+
+    #[test]
+    fn test_has_compiler_errors() {
+        let errors = CompilerErrors::new(compiler_errors(OUTPUT.as_bytes()));
+        let compile = CraterError::new_err("`cargo build` failed", errors);
+        let wrapped = CraterError::new_err("target --lib failed", compile);
+
+        assert!(has_compiler_errors(&wrapped));
+        assert!(!has_compiler_errors(&CraterError::new("`cargo build` failed")));
+    }
+
+    #[test]
+    fn test_stdout_tail() {
+        let test = "
+running 2 tests
+test tests::ok ... ok
+test tests::broken ... FAILED
+
+failures:
+
+---- tests::broken stdout ----
+thread 'tests::broken' panicked at 'assertion failed: false', src/lib.rs:9:9
+
+failures:
+    tests::broken
+
+test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out";
+
+        let tail = stdout_tail(test.as_bytes());
+        assert!(tail.starts_with("failures:\n\n---- tests::broken stdout ----\n"));
+        assert!(tail.ends_with(
+            "test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out"
+        ));
+
+        assert_eq!("running 1 test", stdout_tail(OUTPUT.as_bytes()));
+        assert_eq!("", stdout_tail(b""));
+    }
+
+    #[test]
+    fn test_rendered_output() {
+        assert_eq!(
+            "warning: unused variableerror[E0412]: cannot find typerunning 1 test\n",
+            rendered_output(OUTPUT.as_bytes())
+        );
+        assert_eq!("", rendered_output(b"{\"packages\":[]}"));
+    }
+
+    #[test]
+    fn test_build_retrying() {
+        // Without a Cargo.toml cargo fails before compiling anything
+        let dir = ScratchDir::new("retry");
+
+        let retries = Cell::new(0);
+        let res = CargoRunner::new()
+            .with_retries(2)
+            .build_retrying(&dir, |retry, _| retries.set(retry));
+
+        assert!(res.is_err());
+        assert_eq!(2, retries.get());
+    }
+
+    #[test]
+    fn test_cargo_log() {
+        let dir = ScratchDir::new("log");
+        let log = CargoLog::new();
+
+        let res = CargoRunner::new()
+            .with_retries(1)
+            .with_log(log.clone())
+            .build(&dir);
+        let contents = log.contents();
+
+        assert!(res.is_err());
+        assert_eq!(2, contents.matches("$ cd ").count());
+        assert!(contents.contains("cargo build --workspace --message-format=json\n"));
+        assert!(contents.contains("Cargo.toml"));
+    }
+
+    #[test]
+    fn test_target_from_str() {
+        assert_eq!(Target::Lib, "--lib".parse().unwrap());
+        assert_eq!(
+            Target::Example("metrics".to_owned()),
+            " --example  metrics ".parse().unwrap()
+        );
+        assert_eq!(
+            "--bin server",
+            "--bin server".parse::<Target>().unwrap().to_string()
+        );
+        assert!("--example".parse::<Target>().is_err());
+        assert!("--bin a b".parse::<Target>().is_err());
+        assert!("example:metrics".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut env = BTreeMap::new();
+        env.insert("RUSTFLAGS".to_owned(), "--cfg crater".to_owned());
+        let runner = CargoRunner::new()
+            .with_deny_warnings(true)
+            .with_env(env)
+            .with_targets(vec![Target::Lib, Target::Example("it's".to_owned())]);
+
+        assert_eq!(
+            vec![
+                "cd '/tmp/my project' && RUSTFLAGS='--cfg crater -D warnings' cargo build --workspace --message-format=json --lib",
+                "cd '/tmp/my project' && RUSTFLAGS='--cfg crater -D warnings' cargo build --workspace --message-format=json --example 'it'\\''s'",
+                "cd '/tmp/my project' && RUSTFLAGS='--cfg crater -D warnings' cargo test --workspace --message-format=json --lib",
+                "cd '/tmp/my project' && RUSTFLAGS='--cfg crater -D warnings' cargo test --workspace --message-format=json --example 'it'\\''s'",
+            ],
+            runner.commands(Path::new("/tmp/my project"))
+        );
+    }
+
+    #[test]
+    fn test_commands_test_command() {
+        let runner = CargoRunner::new()
+            .with_jobs(Some(2))
+            .with_locked(true)
+            .with_test_command(Some(vec!["nextest".to_owned(), "run".to_owned()]));
+
+        assert_eq!(
+            vec![
+                "cd /app && cargo build --workspace --message-format=json -j 2 --locked",
+                "cd /app && cargo nextest run --workspace -j 2 --locked",
+            ],
+            runner.commands(Path::new("/app"))
+        );
+    }
+
+    #[test]
+    fn test_commands_build_only() {
+        let runner = CargoRunner::new()
+            .with_target_triple(Some("aarch64-unknown-linux-gnu".to_owned()))
+            .with_build_only(true);
+
+        assert_eq!(
+            vec![
+                "cd /app && cargo build --workspace --message-format=json --target aarch64-unknown-linux-gnu",
+            ],
+            runner.commands(Path::new("/app"))
+        );
+    }
+
+    #[test]
+    fn test_check_test_command() {
+        let command = |c: &[&str]| c.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+
+        assert!(check_test_command(&command(&["test"])).is_ok());
+        assert!(check_test_command(&command(&["nextest", "run"])).is_ok());
+        assert!(check_test_command(&command(&["hack", "test", "--each-feature"])).is_ok());
+        assert!(check_test_command(&command(&[])).is_err());
+        assert!(check_test_command(&command(&["cargo", "test"])).is_err());
+        assert!(check_test_command(&command(&["--release"])).is_err());
+        assert!(check_test_command(&command(&["test && rm -rf /"])).is_err());
+    }
+}
@@ -8,11 +8,14 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::cleanup::{self, Kind};
 use crate::types::CraterError;
+use similar::TextDiff;
 use std::fmt;
 use std::fs;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use toml::value::{Table, Value};
 
 macro_rules! toml_map (
@@ -34,19 +37,30 @@ pub struct LocalVersion {
 }
 
 impl LocalVersion {
-    /// Create a new `LocalVersion` given a path to a `cadence` crate Cargo.toml
-    pub fn new<P: Into<PathBuf>>(cargo_toml: P) -> Self {
-        LocalVersion {
-            cargo_toml: cargo_toml.into(),
-        }
+    /// Create a new `LocalVersion` given a path to a `cadence` crate Cargo.toml or
+    /// the directory containing it, like the `--manifest-path` option of cargo
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let cargo_toml = if path.is_dir() {
+            path.join("Cargo.toml")
+        } else {
+            path
+        };
+
+        LocalVersion { cargo_toml }
     }
 
     /// Determine the version of the local Cadence crate or return an error
     ///
+    /// If the version is inherited from a workspace (`version.workspace = true`) or
+    /// isn't set in the package, the `[workspace.package]` version of the same
+    /// Cargo.toml or the nearest parent workspace Cargo.toml is used instead.
+    ///
     /// Errors will be returned if
     /// * The Cargo.toml file cannot be read
     /// * The Cargo.toml file isn't syntactically valid
-    /// * The the "version" key is missing from the Cargo.toml file
+    /// * The the "version" key is missing from the Cargo.toml file and workspace
+    /// * The Cargo.toml file is a virtual workspace manifest without a `[package]`
     pub fn version(&self) -> Result<String, CraterError> {
         let root = match load_cargo_toml(&self.cargo_toml) {
             Err(e) => {
@@ -61,28 +75,102 @@ impl LocalVersion {
             Ok(v) => v,
         };
 
-        root.as_table()
-            .and_then(|v| v.get("package"))
-            .and_then(|v| v.as_table())
-            .and_then(|v| v.get("version"))
-            .and_then(|v| v.as_str())
-            .map(|s| s.to_owned())
-            .ok_or_else(|| {
-                CraterError::new(format!(
-                    "unable to determine Cadence version from {:?}",
-                    &self.cargo_toml
-                ))
+        if root.get("package").is_none() && root.get("workspace").is_some() {
+            let hint = match self.cadence_member(&root) {
+                Some(member) => format!(", likely {:?}", member),
+                None => String::new(),
+            };
+
+            return Err(CraterError::new(format!(
+                "{:?} is a virtual workspace manifest, use the Cargo.toml of the Cadence \
+                 crate in the workspace instead{}",
+                &self.cargo_toml, hint
+            )));
+        }
+
+        if let Some(version) = package_version(&root) {
+            return Ok(version.to_owned());
+        }
+
+        self.workspace_version(&root)?.ok_or_else(|| {
+            CraterError::new(format!(
+                "unable to determine Cadence version from {:?} or a parent workspace",
+                &self.cargo_toml
+            ))
+        })
+    }
+
+    /// Find the `[workspace.package]` version in the Cargo.toml of the crate or the
+    /// nearest parent directory that defines a workspace
+    fn workspace_version(&self, root: &Value) -> Result<Option<String>, CraterError> {
+        if root.get("workspace").is_some() {
+            return Ok(workspace_package_version(root).map(|v| v.to_owned()));
+        }
+
+        let dir = self.crate_dir().canonicalize().ok();
+        let mut cur = dir.as_deref().and_then(|d| d.parent());
+        while let Some(parent) = cur {
+            let manifest = parent.join("Cargo.toml");
+            if manifest.is_file() {
+                let value = load_cargo_toml(&manifest)?;
+                if value.get("workspace").is_some() {
+                    return Ok(workspace_package_version(&value).map(|v| v.to_owned()));
+                }
+            }
+
+            cur = parent.parent();
+        }
+
+        Ok(None)
+    }
+
+    /// Find the Cargo.toml of the `cadence` crate among the members of a workspace
+    fn cadence_member(&self, root: &Value) -> Option<PathBuf> {
+        let members = root
+            .get("workspace")
+            .and_then(|v| v.get("members"))
+            .and_then(|v| v.as_array())?;
+
+        let dir = self.crate_dir();
+        members
+            .iter()
+            .filter_map(|m| m.as_str())
+            .flat_map(|m| {
+                let pattern = format!("{}/{}", glob::Pattern::escape(&dir.to_string_lossy()), m);
+                glob::glob(&pattern)
+                    .map(|paths| paths.flatten().collect::<Vec<PathBuf>>())
+                    .unwrap_or_default()
+            })
+            .map(|member| member.join("Cargo.toml"))
+            .find(|manifest| {
+                load_cargo_toml(manifest)
+                    .ok()
+                    .and_then(|v| {
+                        v.get("package")
+                            .and_then(|p| p.get("name"))
+                            .and_then(|n| n.as_str())
+                            .map(|n| n == CADENCE_CRATE)
+                    })
+                    .unwrap_or(false)
             })
     }
 
+    /// Directory containing the Cargo.toml of the crate
+    fn crate_dir(&self) -> &Path {
+        match self.cargo_toml.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        }
+    }
+
     /// Get the path to a local Cadence crate as a string or return an error
     ///
     /// Errors will be returned if
     /// * The canonical path to the crate could not be determined
     pub fn path(&self) -> Result<String, CraterError> {
-        self.cargo_toml
-            .parent()
-            .and_then(|p| p.canonicalize().ok())
+        self.crate_dir()
+            .canonicalize()
+            .ok()
             .and_then(|p| p.to_str().map(|s| s.to_owned()))
             .ok_or_else(|| {
                 CraterError::new(format!(
@@ -93,11 +181,65 @@ impl LocalVersion {
     }
 }
 
+/// Get the literal `package.version` from a parsed Cargo.toml, if set
+fn package_version(root: &Value) -> Option<&str> {
+    root.get("package")
+        .and_then(|v| v.get("version"))
+        .and_then(|v| v.as_str())
+}
+
+/// Get the `workspace.package.version` from a parsed Cargo.toml, if set
+fn workspace_package_version(root: &Value) -> Option<&str> {
+    root.get("workspace")
+        .and_then(|v| v.get("package"))
+        .and_then(|v| v.get("version"))
+        .and_then(|v| v.as_str())
+}
+
+/// Source of the Cadence crate written to the `[patch]` section of a project
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchSource {
+    /// A local checkout of Cadence
+    Path(String),
+    /// A git repository of Cadence, optionally using a specific branch
+    Git { url: String, branch: Option<String> },
+    /// The version published to crates.io, only changing the version required
+    /// without adding a `[patch]` entry
+    Registry,
+}
+
+impl PatchSource {
+    fn to_value(&self) -> Option<Value> {
+        match self {
+            PatchSource::Path(path) => Some(Value::Table(
+                toml_map!["path" => Value::String(path.clone())],
+            )),
+            PatchSource::Git { url, branch: None } => {
+                Some(Value::Table(toml_map!["git" => Value::String(url.clone())]))
+            }
+            PatchSource::Git {
+                url,
+                branch: Some(branch),
+            } => Some(Value::Table(toml_map![
+                "git" => Value::String(url.clone()),
+                "branch" => Value::String(branch.clone())
+            ])),
+            PatchSource::Registry => None,
+        }
+    }
+}
+
 /// Patch a project's Cargo.toml to use a local Cadence crate
 #[derive(Debug)]
 pub struct LocalOverride {
     root: PathBuf,
     crates: Vec<PathBuf>,
+    suffix: Option<String>,
+    sections: Vec<DependencySection>,
+    mode: OverrideMode,
+    transitive_only: bool,
+    fsync: bool,
+    discover_members: bool,
 }
 
 impl LocalOverride {
@@ -107,7 +249,84 @@ impl LocalOverride {
     /// the Cargo.toml of each crate contained in the workspace to be patched in the
     /// `crates` vector
     pub fn new(root: PathBuf, crates: Vec<PathBuf>) -> Self {
-        LocalOverride { root, crates }
+        LocalOverride {
+            root,
+            crates,
+            suffix: None,
+            sections: DependencySection::ALL.to_vec(),
+            mode: OverrideMode::Patch,
+            transitive_only: false,
+            fsync: true,
+            discover_members: false,
+        }
+    }
+
+    /// Only change the versions required in the given dependency sections instead
+    /// of all of them, leaving dependencies in other sections as they are
+    pub fn with_sections(mut self, sections: Vec<DependencySection>) -> Self {
+        self.sections = sections;
+        self
+    }
+
+    /// Override the source of each crate using the given mode instead of adding it
+    /// to the `[patch]` section of the root
+    pub fn with_mode(mut self, mode: OverrideMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Only add each crate to the `[patch]` section of the root without changing the
+    /// versions required in any dependency section, for projects that only depend on
+    /// the crates transitively
+    ///
+    /// This takes precedence over `with_sections` and `with_mode` since the `[patch]`
+    /// section is the only way to change transitive dependencies.
+    pub fn with_transitive_only(mut self, transitive_only: bool) -> Self {
+        self.transitive_only = transitive_only;
+        self
+    }
+
+    /// Patch the members listed in the `[workspace]` of the root when it's a virtual
+    /// manifest and no sub-crates were given, instead of returning an error
+    ///
+    /// A virtual manifest has no `[package]` and so no dependencies of its own: the
+    /// `[patch]` section is added to it but the versions required are changed in
+    /// its members.
+    pub fn with_discover_members(mut self, discover: bool) -> Self {
+        self.discover_members = discover;
+        self
+    }
+
+    /// Sync each patched Cargo.toml to disk before replacing the original, true by default
+    ///
+    /// Skipping the sync makes patching faster, especially on slow disks, but patched
+    /// files may be lost or left empty if the system crashes. This only matters when
+    /// projects outlive the run rather than being thrown away after it.
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Write each patched Cargo.toml to a sibling file with the given suffix appended
+    /// to its name (e.g. `Cargo.toml.crater`) instead of replacing the original
+    ///
+    /// Cargo only reads manifests named `Cargo.toml` so the patched files are only
+    /// useful for inspecting exactly what would have been changed.
+    pub fn with_suffix(mut self, suffix: Option<String>) -> Self {
+        self.suffix = suffix;
+        self
+    }
+
+    /// Path that the patched contents of the given Cargo.toml are written to
+    fn output_path(&self, path: &Path) -> PathBuf {
+        match &self.suffix {
+            Some(suffix) => {
+                let mut name = path.as_os_str().to_owned();
+                name.push(suffix);
+                PathBuf::from(name)
+            }
+            None => path.to_path_buf(),
+        }
     }
 
     /// Patch the root and each sub-crate to use the provided local Cadence version
@@ -116,81 +335,770 @@ impl LocalOverride {
     /// * If the local Cadence Cargo.toml can't be read or parsed
     /// * If the project Cargo.toml can't be read or parsed
     /// * If the project Cargo.toml can't be written after being modified
-    pub fn patch(&self, version: &str, path: &str) -> Result<(), CraterError> {
-        let mut root = load_cargo_toml(&self.root)?;
-        let root_table = root.as_table_mut().unwrap();
+    ///
+    /// Either every Cargo.toml is patched or, if any of them can't be written, the
+    /// ones already written are restored. The path of every file written is returned,
+    /// including sibling files written when a suffix is set.
+    pub fn patch(&self, version: &str, path: &str) -> Result<Vec<PathBuf>, CraterError> {
+        self.patch_with(version, &PatchSource::Path(path.to_owned()))
+    }
 
-        // patch the source for Cadence in the root Cargo.toml
-        override_source(root_table, path);
+    /// Patch the root and each sub-crate to use the provided Cadence version and source
+    ///
+    /// Patching may fail, and returns the files written, like `patch`.
+    pub fn patch_with(
+        &self,
+        version: &str,
+        source: &PatchSource,
+    ) -> Result<Vec<PathBuf>, CraterError> {
+        self.patch_crates(&[CratePatch::new(CADENCE_CRATE, version, source.clone())])
+    }
 
-        if self.crates.is_empty() {
-            // there are no subprojects so just update the version required in the root
-            override_version(root_table, version);
-        } else {
-            // open each Cargo.toml for the subprojects and update the version required
-            for crate_path in self.crates.iter() {
-                let mut crate_root = load_cargo_toml(crate_path)?;
-                let crate_root_table = crate_root.as_table_mut().unwrap();
-                override_version(crate_root_table, version);
-                write_cargo_toml(crate_path, crate_root)?;
+    /// Patch the root and each sub-crate to use the provided version and source of
+    /// each crate at once
+    ///
+    /// Patching may fail for the same reasons as `patch` or if the patches are
+    /// self-referential, as checked by `check_patches`. The files written are returned
+    /// like `patch`.
+    pub fn patch_crates(&self, patches: &[CratePatch]) -> Result<Vec<PathBuf>, CraterError> {
+        Ok(self
+            .patch_crates_files(patches)?
+            .into_iter()
+            .map(|f| f.path)
+            .collect())
+    }
+
+    /// Patch the root and each sub-crate like `patch_crates`, returning the contents
+    /// of every file written before and after it was patched
+    pub fn patch_crates_files(
+        &self,
+        patches: &[CratePatch],
+    ) -> Result<Vec<PatchedFile>, CraterError> {
+        self.check_patches(patches)?;
+        if self.suffix.as_deref() == Some("") {
+            return Err(CraterError::new("patch suffix can't be empty"));
+        }
+
+        let (root_original, mut root) = read_cargo_toml(&self.root)?;
+        let crates = self.crates_to_patch(&root)?;
+        let (originals, mut members): (Vec<String>, Vec<Value>) = crates
+            .iter()
+            .map(read_cargo_toml)
+            .collect::<Result<Vec<(String, Value)>, CraterError>>()?
+            .into_iter()
+            .unzip();
+
+        match self.mode {
+            _ if self.transitive_only => apply_overrides_in(&mut root, &mut members, patches, &[]),
+            OverrideMode::Patch => {
+                apply_overrides_in(&mut root, &mut members, patches, &self.sections)
+            }
+            OverrideMode::Direct => {
+                apply_direct_in(&mut root, &mut members, patches, &self.sections)
+            }
+        }
+
+        // Serialize everything before writing anything and write every file or none
+        // of them so a failure doesn't leave the project partially patched.
+        let mut writes = Vec::with_capacity(members.len() + 1);
+        for (crate_path, crate_root) in crates.iter().zip(members.iter()) {
+            writes.push((
+                self.output_path(crate_path),
+                serialize_toml(crate_path, crate_root)?,
+            ));
+        }
+
+        writes.push((
+            self.output_path(&self.root),
+            serialize_toml(&self.root, &root)?,
+        ));
+        write_files(&writes, self.fsync)?;
+
+        Ok(writes
+            .into_iter()
+            .zip(originals.into_iter().chain(Some(root_original)))
+            .map(|((path, patched), original)| PatchedFile {
+                path,
+                original,
+                patched,
+            })
+            .collect())
+    }
+
+    /// Get the Cargo.toml of each sub-crate to change the versions required in, given
+    /// the parsed root Cargo.toml
+    ///
+    /// These are the sub-crates this was created with or, for a virtual manifest
+    /// without any, the workspace members when discovering them. Errors are returned
+    /// for a virtual manifest without sub-crates when not discovering members or when
+    /// it has none, since patching it alone wouldn't change any versions required.
+    fn crates_to_patch(&self, root: &Value) -> Result<Vec<PathBuf>, CraterError> {
+        if !self.crates.is_empty() || self.transitive_only || !is_virtual_manifest(root) {
+            return Ok(self.crates.clone());
+        }
+
+        if !self.discover_members {
+            return Err(CraterError::new(format!(
+                "{:?} is a virtual workspace manifest, the workspace members to patch \
+                 must be given as sub-crates",
+                self.root
+            )));
+        }
+
+        let members = workspace_members(&self.root, root)?;
+        if members.is_empty() {
+            return Err(CraterError::new(format!(
+                "{:?} is a virtual workspace manifest without any members to patch",
+                self.root
+            )));
+        }
+
+        Ok(members)
+    }
+
+    /// Get the `[patch]` sources (such as `crates-io`) of the root Cargo.toml that
+    /// already have an entry for the named crate
+    pub fn existing_patches(&self, name: &str) -> Result<Vec<String>, CraterError> {
+        let root = load_cargo_toml(&self.root)?;
+
+        Ok(root
+            .get("patch")
+            .and_then(|p| p.as_table())
+            .map(|sources| {
+                sources
+                    .iter()
+                    .filter(|(_, crates)| crates.get(name).is_some())
+                    .map(|(source, _)| source.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    /// Check that patches don't reference the project being patched or each other
+    ///
+    /// Cargo reports confusing errors for these, so they're detected before anything
+    /// is written. Errors are returned if
+    /// * A crate is patched more than once
+    /// * A local path is inside the project being patched
+    /// * Multiple crates are patched with the same local path
+    pub fn check_patches(&self, patches: &[CratePatch]) -> Result<(), CraterError> {
+        let project = self
+            .root
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+            .canonicalize()
+            .ok();
+
+        let mut seen: Vec<(&str, Option<PathBuf>)> = Vec::with_capacity(patches.len());
+
+        for patch in patches {
+            let path = match &patch.source {
+                PatchSource::Path(p) => Path::new(p).canonicalize().ok(),
+                PatchSource::Git { .. } | PatchSource::Registry => None,
+            };
+
+            if seen.iter().any(|(name, _)| *name == patch.name) {
+                return Err(CraterError::new(format!(
+                    "crate {} is patched more than once",
+                    patch.name
+                )));
+            }
+
+            if let (Some(path), Some(project)) = (&path, &project) {
+                if path.starts_with(project) {
+                    return Err(CraterError::new(format!(
+                        "patch for {} at {:?} is inside the project being patched at {:?}",
+                        patch.name, path, project
+                    )));
+                }
+            }
+
+            if let Some(path) = &path {
+                let other = seen.iter().find(|(_, p)| p.as_ref() == Some(path));
+
+                if let Some((other, _)) = other {
+                    return Err(CraterError::new(format!(
+                        "patches for {} and {} both use {:?}",
+                        other, patch.name, path
+                    )));
+                }
+            }
+
+            seen.push((&patch.name, path));
+        }
+
+        Ok(())
+    }
+}
+
+/// Name of the Cadence crate
+pub const CADENCE_CRATE: &str = "cadence";
+
+/// Cargo.toml written when patching a project, with its contents before and after
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchedFile {
+    path: PathBuf,
+    original: String,
+    patched: String,
+}
+
+impl PatchedFile {
+    /// Use the given contents as the original instead of those read before patching,
+    /// such as those from before an earlier patch of the same file
+    pub fn with_original(mut self, original: String) -> Self {
+        self.original = original;
+        self
+    }
+
+    /// Path the patched contents were written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn original(&self) -> &str {
+        &self.original
+    }
+
+    /// Unified diff of the changes made by patching, naming the file by its path
+    /// relative to `base`, or empty if nothing was changed
+    pub fn diff(&self, base: &Path) -> String {
+        let name = self.path.strip_prefix(base).unwrap_or(&self.path);
+        let name = name.to_string_lossy();
+
+        TextDiff::from_lines(&self.original, &self.patched)
+            .unified_diff()
+            .header(&format!("a/{}", name), &format!("b/{}", name))
+            .to_string()
+    }
+}
+
+/// Version and source of a crate to patch a project to use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CratePatch {
+    name: String,
+    version: String,
+    source: PatchSource,
+}
+
+impl CratePatch {
+    pub fn new<N, V>(name: N, version: V, source: PatchSource) -> Self
+    where
+        N: Into<String>,
+        V: Into<String>,
+    {
+        CratePatch {
+            name: name.into(),
+            version: version.into(),
+            source,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Section of a Cargo.toml that the versions of patched crates are changed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DependencySection {
+    /// `[dependencies]`
+    Normal,
+    /// `[dev-dependencies]`
+    Dev,
+    /// `[build-dependencies]`
+    Build,
+}
+
+impl DependencySection {
+    /// Every dependency section, in the order they're changed
+    pub const ALL: &'static [DependencySection] = &[
+        DependencySection::Normal,
+        DependencySection::Dev,
+        DependencySection::Build,
+    ];
+
+    /// Name of the table for the section in a Cargo.toml
+    pub fn table_name(&self) -> &'static str {
+        match self {
+            DependencySection::Normal => "dependencies",
+            DependencySection::Dev => "dev-dependencies",
+            DependencySection::Build => "build-dependencies",
+        }
+    }
+}
+
+impl FromStr for DependencySection {
+    type Err = CraterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DependencySection::ALL
+            .iter()
+            .find(|section| section.table_name() == s.trim())
+            .copied()
+            .ok_or_else(|| {
+                CraterError::new(format!(
+                    "invalid dependency section '{}', expected one of dependencies, \
+                     dev-dependencies, or build-dependencies",
+                    s
+                ))
+            })
+    }
+}
+
+impl fmt::Display for DependencySection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.table_name())
+    }
+}
+
+/// How the source of each patched crate is overridden in a project
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrideMode {
+    /// Add each crate to the `[patch]` section of the root Cargo.toml
+    Patch,
+    /// Rewrite each dependency on a crate to use its source directly
+    Direct,
+}
+
+impl OverrideMode {
+    /// Name of the mode as given on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverrideMode::Patch => "patch",
+            OverrideMode::Direct => "direct",
+        }
+    }
+}
+
+impl FromStr for OverrideMode {
+    type Err = CraterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "patch" => Ok(OverrideMode::Patch),
+            "direct" => Ok(OverrideMode::Direct),
+            _ => Err(CraterError::new(format!(
+                "invalid override mode '{}', expected one of patch or direct",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for OverrideMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Patch the parsed root and member Cargo.toml structures to use the version and
+/// source of each crate, changing the versions required in every dependency section
+///
+/// See `apply_overrides_in`.
+pub fn apply_overrides(root: &mut Value, members: &mut [Value], patches: &[CratePatch]) {
+    apply_overrides_in(root, members, patches, DependencySection::ALL)
+}
+
+/// Patch the `contents` of a single Cargo.toml to use the given version of a crate
+/// from a local `path`, returning the patched contents
+///
+/// This makes the same change as `LocalOverride` does to a project without sub-crates
+/// but without reading or writing any files: the crate is added to the `[patch]` section
+/// and the version required is changed in every dependency section. An error is returned
+/// if the contents aren't valid TOML.
+pub fn patch_manifest(
+    contents: &str,
+    name: &str,
+    version: &str,
+    path: &str,
+) -> Result<String, CraterError> {
+    let mut root = parse_cargo_toml(contents, "Cargo.toml contents")?;
+    let patch = CratePatch::new(name, version, PatchSource::Path(path.to_owned()));
+    apply_overrides(&mut root, &mut [], &[patch]);
+
+    toml::to_string(&root)
+        .map_err(|e| CraterError::new_err("unable to serialize patched Cargo.toml contents", e))
+}
+
+/// Patch the parsed root and member Cargo.toml structures to use the version and
+/// source of each crate, changing the versions required in the given sections
+///
+/// The source overrides are added to the `[patch]` section of the `root` for the
+/// source each crate is depended on from (see `override_source`), keeping any existing
+/// patches for other crates. When there are no `members`, the root is assumed to be a single
+/// crate and the versions required are updated there, otherwise the versions required
+/// are updated in each of the `members`. Versions in the `[workspace.dependencies]` of
+/// the `root` are updated too, for members that inherit them.
+///
+/// The `[patch]` applies to the whole dependency graph but only matches versions
+/// it's compatible with, so dependencies in other sections keep using the version they
+/// required before.
+pub fn apply_overrides_in(
+    root: &mut Value,
+    members: &mut [Value],
+    patches: &[CratePatch],
+    sections: &[DependencySection],
+) {
+    if let Some(root_table) = root.as_table_mut() {
+        // patch the source for each crate in the root Cargo.toml
+        override_source(root_table, members, patches);
+
+        // members inheriting a crate from the workspace require the version given here
+        if let Some(deps) = workspace_dependencies(root_table) {
+            for patch in patches {
+                if deps.contains_key(&patch.name) {
+                    set_version(deps, &patch.name, patch.version.as_str());
+                }
+            }
+        }
+
+        if members.is_empty() {
+            // there are no subprojects so just update the versions required in the root
+            for patch in patches {
+                override_versions(root_table, sections, &patch.name, &patch.version);
+            }
+        }
+    }
+
+    // update the versions required for each of the subprojects
+    for member in members.iter_mut() {
+        if let Some(member_table) = member.as_table_mut() {
+            for patch in patches {
+                override_versions(member_table, sections, &patch.name, &patch.version);
+            }
+        }
+    }
+}
+
+/// Rewrite dependencies on each crate in the parsed root and member Cargo.toml
+/// structures to use its version and source directly, in the given sections
+///
+/// Nothing is added to the `[patch]` section. Dependencies inherited from the
+/// workspace are left as they are and the `[workspace.dependencies]` entry of the
+/// `root` is rewritten instead. Like `apply_overrides_in`, the sections of the `root`
+/// are only rewritten when there are no `members`. Only existing dependencies are
+/// rewritten, so crates that aren't depended on in a section aren't added to it.
+pub fn apply_direct_in(
+    root: &mut Value,
+    members: &mut [Value],
+    patches: &[CratePatch],
+    sections: &[DependencySection],
+) {
+    if let Some(root_table) = root.as_table_mut() {
+        if let Some(deps) = workspace_dependencies(root_table) {
+            for patch in patches {
+                set_source(deps, patch);
+            }
+        }
+
+        if members.is_empty() {
+            override_sources(root_table, sections, patches);
+        }
+    }
+
+    for member in members.iter_mut() {
+        if let Some(member_table) = member.as_table_mut() {
+            override_sources(member_table, sections, patches);
+        }
+    }
+}
+
+/// Get the `[workspace.dependencies]` table of a root Cargo.toml structure, if it has one
+fn workspace_dependencies(root: &mut Table) -> Option<&mut Table> {
+    root.get_mut("workspace")
+        .and_then(|w| w.as_table_mut())
+        .and_then(|w| w.get_mut("dependencies"))
+        .and_then(|d| d.as_table_mut())
+}
+
+/// Keys of a dependency table that determine where the dependency comes from
+const SOURCE_KEYS: &[&str] = &["git", "branch", "tag", "rev", "path", "registry"];
+
+/// Rewrite dependencies on each crate in the given sections of the given Cargo.toml
+/// structure to use its version and source directly
+fn override_sources(table: &mut Table, sections: &[DependencySection], patches: &[CratePatch]) {
+    for section in sections {
+        let deps = table
+            .get_mut(section.table_name())
+            .and_then(|t| t.as_table_mut());
+
+        if let Some(deps) = deps {
+            for patch in patches {
+                set_source(deps, patch);
             }
         }
+    }
+}
+
+/// Replace the source and version of a crate in a table of dependencies, returning
+/// true if it was there to be replaced
+///
+/// Other settings such as features are kept. Dependencies inherited from the workspace
+/// are left as they are.
+fn set_source(deps: &mut Table, patch: &CratePatch) -> bool {
+    let mut dep = match deps.get(&patch.name) {
+        Some(Value::Table(dep)) if dep.contains_key("workspace") => return false,
+        Some(Value::Table(dep)) => dep.clone(),
+        Some(_) => Table::new(),
+        None => return false,
+    };
+
+    for key in SOURCE_KEYS {
+        dep.remove(*key);
+    }
 
-        write_cargo_toml(&self.root, root)
+    dep.insert("version".to_owned(), Value::String(patch.version.clone()));
+    if let Some(Value::Table(source)) = patch.source.to_value() {
+        dep.extend(source);
     }
+
+    deps.insert(patch.name.clone(), Value::Table(dep));
+    true
 }
 
-/// Change Cadence dependencies to a local checkout for the given Cargo.toml structure
-fn override_source<S: Into<String>>(table: &mut Table, path: S) {
-    table.insert(
-        "patch".to_owned(),
-        Value::Table(toml_map!["crates-io" => Value::Table(
-            toml_map!["cadence" => Value::Table(
-                toml_map!["path" => Value::String(path.into())]
-            )]
-        )]),
-    );
+/// Change dependencies on each crate to the given source for the given Cargo.toml structure
+///
+/// Each crate is patched under `[patch.crates-io]` unless the dependency on it in the
+/// Cargo.toml or any of the `members` comes from a git repository, in which case it's
+/// patched under `[patch."<git-url>"]` since a `crates-io` patch doesn't apply to git
+/// dependencies. Existing entries for other crates are kept while entries for the
+/// patched crates are replaced. Crates using the version published to crates.io have
+/// any existing entry removed instead.
+fn override_source(table: &mut Table, members: &[Value], patches: &[CratePatch]) {
+    let sources: Vec<Vec<String>> = patches
+        .iter()
+        .map(|patch| {
+            let tables = Some(&*table)
+                .into_iter()
+                .chain(members.iter().filter_map(|m| m.as_table()));
+            dependency_sources(tables, &patch.name)
+        })
+        .collect();
+
+    let patch_table = child_table(table, "patch");
+    for (patch, keys) in patches.iter().zip(sources.iter()) {
+        for key in keys {
+            let crates = child_table(patch_table, key);
+            match patch.source.to_value() {
+                Some(source) => crates.insert(patch.name.clone(), source),
+                None => crates.remove(&patch.name),
+            };
+        }
+    }
 }
 
-/// Change the version of Cadence required for the given Cargo.toml structure
-fn override_version<S: Into<String>>(table: &mut Table, version: S) -> bool {
-    table
-        .get_mut("dependencies")
+/// Sections of a Cargo.toml that may declare dependencies, as paths of keys
+const DEPENDENCY_SECTIONS: &[&[&str]] = &[
+    &["dependencies"],
+    &["dev-dependencies"],
+    &["build-dependencies"],
+    &["workspace", "dependencies"],
+];
+
+/// Get the `[patch]` key for each source that the named crate is depended on from in
+/// the given Cargo.toml structures: the git URL for git dependencies or `crates-io`
+/// otherwise, including when there's no dependency on the crate at all
+fn dependency_sources<'a, I>(tables: I, name: &str) -> Vec<String>
+where
+    I: Iterator<Item = &'a Table>,
+{
+    let mut sources: Vec<String> = Vec::new();
+
+    for table in tables {
+        for section in DEPENDENCY_SECTIONS {
+            let dep = section
+                .iter()
+                .try_fold(table, |t, key| t.get(*key).and_then(|v| v.as_table()))
+                .and_then(|deps| deps.get(name));
+
+            let source = match dep {
+                // Inherited dependencies use the source from `[workspace.dependencies]`
+                Some(Value::Table(dep)) if dep.contains_key("workspace") => continue,
+                Some(Value::Table(dep)) => match dep.get("git").and_then(|g| g.as_str()) {
+                    Some(url) => url.to_owned(),
+                    None => "crates-io".to_owned(),
+                },
+                Some(_) => "crates-io".to_owned(),
+                None => continue,
+            };
+
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    }
+
+    if sources.is_empty() {
+        sources.push("crates-io".to_owned());
+    }
+
+    sources
+}
+
+/// Get the table stored under `key`, replacing any missing or non-table value with
+/// an empty table
+fn child_table<'a>(table: &'a mut Table, key: &str) -> &'a mut Table {
+    if !matches!(table.get(key), Some(Value::Table(_))) {
+        table.insert(key.to_owned(), Value::Table(Table::new()));
+    }
+
+    match table.get_mut(key) {
+        Some(Value::Table(t)) => t,
+        _ => unreachable!("{} was just set to a table", key),
+    }
+}
+
+/// Change the version of the named crate required in each of the given sections of
+/// the given Cargo.toml structure
+///
+/// Only existing dependencies on the crate are changed, it's never added to a section.
+fn override_versions(table: &mut Table, sections: &[DependencySection], name: &str, version: &str) {
+    for section in sections {
+        override_version(table, *section, name, version);
+    }
+}
+
+/// Change the version of the named crate required in a section of the given Cargo.toml
+/// structure, returning true if the crate is a dependency in that section
+fn override_version<S: Into<String>>(
+    table: &mut Table,
+    section: DependencySection,
+    name: &str,
+    version: S,
+) -> bool {
+    let deps = table
+        .get_mut(section.table_name())
         .and_then(|t| t.as_table_mut())
-        .and_then(|t| t.insert("cadence".to_owned(), Value::String(version.into())))
-        .is_some()
+        .filter(|deps| deps.contains_key(name));
+
+    match deps {
+        Some(deps) => set_version(deps, name, version),
+        None => false,
+    }
+}
+
+/// Set the version of the named crate in a table of dependencies, returning true if
+/// it was already there
+///
+/// Dependencies inherited from the workspace are left as they are since cargo ignores
+/// a version given alongside `workspace = true`, their version is set in the
+/// `[workspace.dependencies]` of the root instead.
+fn set_version<S: Into<String>>(deps: &mut Table, name: &str, version: S) -> bool {
+    let version = Value::String(version.into());
+    match deps.get_mut(name) {
+        Some(Value::Table(dep)) if dep.contains_key("workspace") => false,
+        // Dependencies specified as a table may include features or other settings
+        // that need to be preserved so only the version is replaced.
+        Some(Value::Table(dep)) => dep.insert("version".to_owned(), version).is_some(),
+        _ => deps.insert(name.to_owned(), version).is_some(),
+    }
 }
 
-/// Serialize and write a TOML structure to the given file
-fn write_cargo_toml<P>(path: P, root: Value) -> Result<(), CraterError>
+/// Serialize a TOML structure to be written to the given file
+fn serialize_toml<P>(path: P, root: &Value) -> Result<String, CraterError>
 where
     P: AsRef<Path> + fmt::Debug,
 {
-    let contents = toml::to_string(&root).map_err(|e| {
+    toml::to_string(root).map_err(|e| {
         CraterError::new_err(
             format!("unable to serialize TOML for writing to {:?}", &path),
             e,
         )
-    })?;
+    })
+}
+
+/// Write the contents of each file, restoring the files already written if any
+/// of them can't be written so that either all or none of them are changed
+///
+/// Files that didn't exist before are removed when restoring. Each file is synced to
+/// disk before it replaces the original when `fsync` is set.
+fn write_files<P>(writes: &[(P, String)], fsync: bool) -> Result<(), CraterError>
+where
+    P: AsRef<Path> + fmt::Debug,
+{
+    let originals: Vec<Option<Vec<u8>>> = writes
+        .iter()
+        .map(|(path, _)| match fs::read(path.as_ref()) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(CraterError::new_err(
+                format!("unable to read {:?}", path),
+                e,
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+
+    for (i, (path, contents)) in writes.iter().enumerate() {
+        let err = match write_file(path, contents.as_bytes(), fsync) {
+            Ok(_) => continue,
+            Err(e) => e,
+        };
+
+        let unrestored: Vec<&P> = writes[..i]
+            .iter()
+            .zip(originals.iter())
+            .filter(|((path, _), original)| {
+                let res = match original {
+                    Some(contents) => write_file(path, contents, fsync),
+                    None => fs::remove_file(path.as_ref()).map_err(|e| {
+                        CraterError::new_err(format!("unable to remove {:?}", path), e)
+                    }),
+                };
+
+                res.is_err()
+            })
+            .map(|((path, _), _)| path)
+            .collect();
+
+        if unrestored.is_empty() {
+            return Err(err);
+        }
+
+        return Err(CraterError::new_err(
+            format!("unable to restore {:?} after failing to write", unrestored),
+            err,
+        ));
+    }
+
+    Ok(())
+}
 
+/// Write the given contents to a file by replacing it with a temporary file, synced
+/// to disk first when `fsync` is set
+fn write_file<P>(path: P, contents: &[u8], fsync: bool) -> Result<(), CraterError>
+where
+    P: AsRef<Path> + fmt::Debug,
+{
     // Wrap this section in a closure so we can use short-circuiting via the `?`
     // operator but only do a single `.map_err()` call to convert to a meaningful
     // crater error.
     let write_and_rename = move |p: &P| {
-        let tmp_path = p.as_ref().parent().unwrap().join(".cadence-rename");
+        let tmp_path = cleanup::temp_file_path(p.as_ref().parent().unwrap());
+        let _tracked = cleanup::track(Kind::TempFile, &tmp_path);
+        // Keep the permissions of the original file since the temporary file will
+        // be created with default permissions and then replace it.
+        let perms = fs::metadata(p).ok().map(|m| m.permissions());
         {
             let mut fd = fs::OpenOptions::new()
                 .read(false)
                 .write(true)
                 .create(true)
+                .truncate(true)
                 .open(&tmp_path)?;
 
-            fd.write_all(contents.as_bytes())?;
+            fd.write_all(contents)?;
             fd.flush()?;
-            fd.sync_all()?;
+            if fsync {
+                fd.sync_all()?;
+            }
         }
 
-        Ok(fs::rename(tmp_path, &p)?)
+        if let Some(perms) = perms {
+            fs::set_permissions(&tmp_path, perms)?;
+        }
+
+        fs::rename(tmp_path, p)
     };
 
     write_and_rename(&path).map_err(|e: io::Error| {
@@ -198,8 +1106,162 @@ where
     })
 }
 
+/// Returns true if the parsed Cargo.toml is a virtual manifest: the root of a
+/// workspace without a `[package]`
+pub fn is_virtual_manifest(root: &Value) -> bool {
+    root.get("workspace").map(Value::is_table).unwrap_or(false) && root.get("package").is_none()
+}
+
+/// Find the Cargo.toml of each member of the workspace defined by the given root
+/// Cargo.toml and its parsed contents
+///
+/// Members are the directories matching the `members` of the `[workspace]`, which
+/// may be glob patterns, that have a Cargo.toml and aren't under a path listed in
+/// its `exclude`. Errors are returned for invalid patterns.
+pub fn workspace_members(path: &Path, root: &Value) -> Result<Vec<PathBuf>, CraterError> {
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let workspace = root.get("workspace");
+    let strings = |key: &str| -> Vec<&str> {
+        workspace
+            .and_then(|w| w.get(key))
+            .and_then(|m| m.as_array())
+            .map(|m| m.iter().filter_map(|v| v.as_str()).collect())
+            .unwrap_or_default()
+    };
+
+    let exclude: Vec<PathBuf> = strings("exclude").iter().map(|e| dir.join(e)).collect();
+    let base = glob::Pattern::escape(&dir.to_string_lossy());
+    let mut manifests = Vec::new();
+
+    for member in strings("members") {
+        let pattern = format!("{}/{}", base, member);
+        let paths = glob::glob(&pattern).map_err(|e| {
+            CraterError::new_err(
+                format!(
+                    "invalid workspace member pattern {:?} in {:?}",
+                    member, path
+                ),
+                e,
+            )
+        })?;
+
+        for member_dir in paths.filter_map(Result::ok) {
+            let manifest = member_dir.join("Cargo.toml");
+            let excluded = exclude.iter().any(|e| member_dir.starts_with(e));
+            if !excluded && manifest.is_file() && !manifests.contains(&manifest) {
+                manifests.push(manifest);
+            }
+        }
+    }
+
+    Ok(manifests)
+}
+
+/// Get the name of the package defined by the given Cargo.toml
+///
+/// Errors will be returned if the Cargo.toml file cannot be read or parsed or
+/// doesn't define a package.
+pub fn package_name(cargo_toml: &Path) -> Result<String, CraterError> {
+    load_cargo_toml(cargo_toml)?
+        .get("package")
+        .and_then(|p| p.get("name"))
+        .and_then(|n| n.as_str())
+        .map(|n| n.to_owned())
+        .ok_or_else(|| CraterError::new(format!("no package name in {:?}", cargo_toml)))
+}
+
+/// Determine whether the project with the given root Cargo.toml depends on the named
+/// crate, directly or through the `Cargo.lock` next to it
+///
+/// Direct dependencies are looked for in every dependency section of the root and of
+/// each member of its workspace, including platform specific sections and dependencies
+/// renamed with `package`. `None` is returned when there's no direct dependency and no
+/// `Cargo.lock` to find a transitive one in. Errors are returned if any of the files
+/// can't be read or parsed.
+pub fn depends_on(cargo_toml: &Path, name: &str) -> Result<Option<bool>, CraterError> {
+    let root = load_cargo_toml(cargo_toml)?;
+    if has_dependency(&root, name) {
+        return Ok(Some(true));
+    }
+
+    for member in workspace_members(cargo_toml, &root)? {
+        if has_dependency(&load_cargo_toml(&member)?, name) {
+            return Ok(Some(true));
+        }
+    }
+
+    let lock_file = cargo_toml.with_file_name("Cargo.lock");
+    if !lock_file.is_file() {
+        return Ok(None);
+    }
+
+    let locked = load_cargo_toml(&lock_file)?
+        .get("package")
+        .and_then(|p| p.as_array())
+        .map(|packages| {
+            packages
+                .iter()
+                .any(|p| p.get("name").and_then(|n| n.as_str()) == Some(name))
+        })
+        .unwrap_or(false);
+
+    Ok(Some(locked))
+}
+
+/// Returns true if the parsed Cargo.toml declares a dependency on the named crate in
+/// any of its dependency sections
+fn has_dependency(root: &Value, name: &str) -> bool {
+    let mut targets = root
+        .get("target")
+        .and_then(|t| t.as_table())
+        .into_iter()
+        .flat_map(|t| t.values());
+
+    let declares = |table: &Value| {
+        DEPENDENCY_SECTIONS.iter().any(|section| {
+            section
+                .iter()
+                .try_fold(table, |t, key| t.get(*key))
+                .and_then(|deps| deps.as_table())
+                .map(|deps| {
+                    deps.iter().any(|(key, dep)| {
+                        let package = dep.get("package").and_then(|p| p.as_str());
+                        package.unwrap_or(key) == name
+                    })
+                })
+                .unwrap_or(false)
+        })
+    };
+
+    declares(root) || targets.any(declares)
+}
+
+/// Get the settings, such as `opt-level`, given in the `[profile.<name>]` section of
+/// the given Cargo.toml for the named profile
+///
+/// Errors will be returned if the Cargo.toml file cannot be read or parsed.
+pub fn profile_settings(cargo_toml: &Path, profile: &str) -> Result<Vec<String>, CraterError> {
+    Ok(profile_keys(&load_cargo_toml(cargo_toml)?, profile))
+}
+
+fn profile_keys(root: &Value, profile: &str) -> Vec<String> {
+    root.get("profile")
+        .and_then(|p| p.get(profile))
+        .and_then(|p| p.as_table())
+        .map(|t| t.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
 /// Load and parse the contents of a Cargo.toml file
 fn load_cargo_toml<P>(path: P) -> Result<Value, CraterError>
+where
+    P: AsRef<Path> + fmt::Debug,
+{
+    read_cargo_toml(path).map(|(_, root)| root)
+}
+
+/// Read a Cargo.toml, returning both its contents and the parsed structure
+fn read_cargo_toml<P>(path: P) -> Result<(String, Value), CraterError>
 where
     P: AsRef<Path> + fmt::Debug,
 {
@@ -209,11 +1271,1089 @@ where
         .and_then(|mut f| f.read_to_string(&mut buf))
         .map_err(|e| CraterError::new_err(format!("unable to read TOML file {:?}", &path), e))?;
 
-    match buf.parse() {
-        Ok(v) => Ok(v),
-        Err(e) => Err(CraterError::new_err(
-            format!("unable to parse TOML file {:?}", &path),
+    let root = parse_cargo_toml(&buf, &format!("TOML file {:?}", &path))?;
+    Ok((buf, root))
+}
+
+/// Parse the `contents` of a Cargo.toml, describing where any parse error is and
+/// using `what` to name the contents in the error
+fn parse_cargo_toml(contents: &str, what: &str) -> Result<Value, CraterError> {
+    contents.parse().map_err(|e| {
+        CraterError::new_err(
+            format!("unable to parse {}{}", what, error_location(contents, &e)),
             e,
-        )),
+        )
+    })
+}
+
+/// Describe where in `contents` a TOML parse error is, including the line it's on,
+/// or nothing if the error doesn't have a location
+fn error_location(contents: &str, err: &toml::de::Error) -> String {
+    /// Longest part of the line with the error to include
+    const MAX_SNIPPET: usize = 80;
+
+    let (line, col) = match err.line_col() {
+        Some(lc) => lc,
+        None => return String::new(),
+    };
+
+    let snippet: String = contents
+        .lines()
+        .nth(line)
+        .unwrap_or_default()
+        .trim()
+        .chars()
+        .take(MAX_SNIPPET)
+        .collect();
+
+    // Locations from the toml crate are zero-based but editors count from one
+    format!(" at line {}, column {}: `{}`", line + 1, col + 1, snippet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_direct_in, apply_overrides, apply_overrides_in, override_source, override_version,
+        patch_manifest, profile_keys, write_file, write_files, CratePatch, DependencySection,
+        LocalOverride, LocalVersion, OverrideMode, PatchSource,
+    };
+    use crate::testing::ScratchDir;
+    use std::fs;
+    use toml::value::{Table, Value};
+
+    const PLAIN_DEP: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[dependencies]
+cadence = "0.20"
+serde = "1.0"
+"#;
+
+    const EXISTING_PATCH: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[dependencies]
+cadence = "0.20"
+serde = "1.0"
+
+[patch.crates-io]
+cadence = { git = "https://github.com/56quarters/cadence.git" }
+serde = { path = "../serde" }
+
+[patch."https://example.com/registry"]
+internal = { path = "../internal" }
+"#;
+
+    const EXISTING_INLINE_PATCH: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[dependencies]
+cadence = "0.20"
+serde = "1.0"
+
+[patch]
+crates-io = { cadence = { git = "https://github.com/56quarters/cadence.git" }, serde = { path = "../serde" } }
+"https://example.com/registry" = { internal = { path = "../internal" } }
+"#;
+
+    const GIT_DEP: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[dependencies]
+cadence = { git = "https://example.com/cadence-fork.git", branch = "main" }
+"#;
+
+    const WORKSPACE_INHERITED_DEP: &str = r#"
+[package]
+name = "downstream-cli"
+version = "0.1.0"
+
+[dependencies]
+cadence = { workspace = true }
+"#;
+
+    const WORKSPACE_GIT_ROOT: &str = r#"
+[workspace]
+members = ["downstream", "downstream-cli"]
+
+[workspace.dependencies]
+cadence = { git = "https://github.com/56quarters/cadence", features = ["tokio"] }
+"#;
+
+    const TABLE_DEP: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[dependencies]
+cadence = { version = "0.20", default-features = false }
+"#;
+
+    const NO_DEPS: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+"#;
+
+    const DEV_DEP: &str = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[dependencies]
+cadence = "0.20"
+
+[dev-dependencies]
+cadence = "0.19"
+"#;
+
+    const WORKSPACE_ROOT: &str = r#"
+[workspace]
+members = ["member"]
+"#;
+
+    const WORKSPACE_MEMBER: &str = r#"
+[package]
+name = "member"
+version = "0.1.0"
+
+[dependencies]
+cadence = "0.20"
+"#;
+
+    fn parse(contents: &str) -> Table {
+        match contents.parse::<Value>().unwrap() {
+            Value::Table(t) => t,
+            v => panic!("unexpected TOML root {:?}", v),
+        }
+    }
+
+    fn local() -> PatchSource {
+        PatchSource::Path("/src/cadence".to_owned())
+    }
+
+    fn cadence(source: PatchSource) -> CratePatch {
+        CratePatch::new("cadence", "0.21.0", source)
+    }
+
+    fn get<'a>(table: &'a Table, keys: &[&str]) -> Option<&'a Value> {
+        let (last, rest) = keys.split_last().unwrap();
+        let mut current = table;
+
+        for key in rest {
+            current = current.get(*key)?.as_table()?;
+        }
+
+        current.get(*last)
+    }
+
+    #[test]
+    fn test_override_source() {
+        let mut table = parse(PLAIN_DEP);
+        override_source(&mut table, &[], &[cadence(local())]);
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(&table, &["patch", "crates-io", "cadence", "path"])
+        );
+    }
+
+    #[test]
+    fn test_apply_overrides_registry() {
+        let mut root = Value::Table(parse(EXISTING_PATCH));
+        apply_overrides(&mut root, &mut [], &[cadence(PatchSource::Registry)]);
+        let table = root.as_table().unwrap();
+
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(table, &["dependencies", "cadence"])
+        );
+        assert_eq!(None, get(table, &["patch", "crates-io", "cadence"]));
+        assert!(get(table, &["patch", "crates-io", "serde"]).is_some());
+    }
+
+    #[test]
+    fn test_override_source_keeps_other_patches() {
+        for contents in &[EXISTING_PATCH, EXISTING_INLINE_PATCH] {
+            let mut table = parse(contents);
+            override_source(&mut table, &[], &[cadence(local())]);
+
+            assert_eq!(
+                Some(&Value::String("/src/cadence".to_owned())),
+                get(&table, &["patch", "crates-io", "cadence", "path"])
+            );
+            assert_eq!(None, get(&table, &["patch", "crates-io", "cadence", "git"]));
+            assert_eq!(
+                Some(&Value::String("../serde".to_owned())),
+                get(&table, &["patch", "crates-io", "serde", "path"])
+            );
+            assert_eq!(
+                Some(&Value::String("../internal".to_owned())),
+                get(
+                    &table,
+                    &["patch", "https://example.com/registry", "internal", "path"]
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn test_override_source_git_dependency() {
+        let mut root = parse(WORKSPACE_GIT_ROOT);
+        let members = vec![
+            Value::Table(parse(GIT_DEP)),
+            Value::Table(parse(WORKSPACE_INHERITED_DEP)),
+        ];
+        override_source(&mut root, &members, &[cadence(local())]);
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(
+                &root,
+                &[
+                    "patch",
+                    "https://github.com/56quarters/cadence",
+                    "cadence",
+                    "path"
+                ]
+            )
+        );
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(
+                &root,
+                &[
+                    "patch",
+                    "https://example.com/cadence-fork.git",
+                    "cadence",
+                    "path"
+                ]
+            )
+        );
+        assert_eq!(None, get(&root, &["patch", "crates-io"]));
+    }
+
+    #[test]
+    fn test_override_source_git_branch() {
+        let mut table = parse(PLAIN_DEP);
+        let source = PatchSource::Git {
+            url: "https://github.com/56quarters/cadence.git".to_owned(),
+            branch: Some("compat".to_owned()),
+        };
+        override_source(&mut table, &[], &[cadence(source)]);
+
+        assert_eq!(
+            Some(&Value::String(
+                "https://github.com/56quarters/cadence.git".to_owned()
+            )),
+            get(&table, &["patch", "crates-io", "cadence", "git"])
+        );
+        assert_eq!(
+            Some(&Value::String("compat".to_owned())),
+            get(&table, &["patch", "crates-io", "cadence", "branch"])
+        );
+    }
+
+    #[test]
+    fn test_apply_overrides_multiple_crates() {
+        let mut root = Value::Table(parse(PLAIN_DEP));
+        let macros = CratePatch::new(
+            "cadence-macros",
+            "0.25.0",
+            PatchSource::Path("/src/cadence-macros".to_owned()),
+        );
+
+        apply_overrides(&mut root, &mut [], &[cadence(local()), macros]);
+        let root = root.as_table().unwrap();
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(root, &["patch", "crates-io", "cadence", "path"])
+        );
+        assert_eq!(
+            Some(&Value::String("/src/cadence-macros".to_owned())),
+            get(root, &["patch", "crates-io", "cadence-macros", "path"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(root, &["dependencies", "cadence"])
+        );
+        assert_eq!(None, get(root, &["dependencies", "cadence-macros"]));
+    }
+
+    #[test]
+    fn test_override_version_plain_dependency() {
+        let mut table = parse(PLAIN_DEP);
+
+        assert!(override_version(
+            &mut table,
+            DependencySection::Normal,
+            "cadence",
+            "0.21.0"
+        ));
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(&table, &["dependencies", "cadence"])
+        );
+        assert_eq!(
+            Some(&Value::String("1.0".to_owned())),
+            get(&table, &["dependencies", "serde"])
+        );
+    }
+
+    #[test]
+    fn test_override_version_table_dependency() {
+        let mut table = parse(TABLE_DEP);
+
+        assert!(override_version(
+            &mut table,
+            DependencySection::Normal,
+            "cadence",
+            "0.21.0"
+        ));
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(&table, &["dependencies", "cadence", "version"])
+        );
+        assert_eq!(
+            Some(&Value::Boolean(false)),
+            get(&table, &["dependencies", "cadence", "default-features"])
+        );
+    }
+
+    #[test]
+    fn test_override_version_no_dependencies() {
+        let mut table = parse(NO_DEPS);
+
+        assert!(!override_version(
+            &mut table,
+            DependencySection::Normal,
+            "cadence",
+            "0.21.0"
+        ));
+        assert_eq!(None, get(&table, &["dependencies"]));
+    }
+
+    #[test]
+    fn test_override_version_dev_dependencies() {
+        let mut table = parse(DEV_DEP);
+
+        assert!(override_version(
+            &mut table,
+            DependencySection::Normal,
+            "cadence",
+            "0.21.0"
+        ));
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(&table, &["dependencies", "cadence"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.19".to_owned())),
+            get(&table, &["dev-dependencies", "cadence"])
+        );
+    }
+
+    #[test]
+    fn test_apply_overrides_sections() {
+        let mut all = Value::Table(parse(DEV_DEP));
+        apply_overrides(&mut all, &mut [], &[cadence(local())]);
+
+        let mut normal = Value::Table(parse(DEV_DEP));
+        apply_overrides_in(
+            &mut normal,
+            &mut [],
+            &[cadence(local())],
+            &[DependencySection::Normal],
+        );
+
+        let mut build = Value::Table(parse(DEV_DEP));
+        apply_overrides_in(
+            &mut build,
+            &mut [],
+            &[cadence(local())],
+            &[DependencySection::Build],
+        );
+
+        let version = |v: &Value, section: &str| {
+            get(v.as_table().unwrap(), &[section, "cadence"])
+                .and_then(|c| c.as_str())
+                .map(|c| c.to_owned())
+        };
+
+        assert_eq!(Some("0.21.0".to_owned()), version(&all, "dependencies"));
+        assert_eq!(Some("0.21.0".to_owned()), version(&all, "dev-dependencies"));
+        assert_eq!(Some("0.21.0".to_owned()), version(&normal, "dependencies"));
+        assert_eq!(
+            Some("0.19".to_owned()),
+            version(&normal, "dev-dependencies")
+        );
+        assert_eq!(Some("0.20".to_owned()), version(&build, "dependencies"));
+        assert_eq!(None, version(&build, "build-dependencies"));
+    }
+
+    #[test]
+    fn test_dependency_section_from_str() {
+        assert_eq!(DependencySection::Dev, "dev-dependencies".parse().unwrap());
+        assert_eq!(
+            "build-dependencies",
+            " build-dependencies "
+                .parse::<DependencySection>()
+                .unwrap()
+                .to_string()
+        );
+        assert!("dev".parse::<DependencySection>().is_err());
+        assert!("patch".parse::<DependencySection>().is_err());
+    }
+
+    #[test]
+    fn test_override_workspace() {
+        let mut root = parse(WORKSPACE_ROOT);
+        let mut member = parse(WORKSPACE_MEMBER);
+
+        override_source(&mut root, &[], &[cadence(local())]);
+        assert!(override_version(
+            &mut member,
+            DependencySection::Normal,
+            "cadence",
+            "0.21.0"
+        ));
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(&root, &["patch", "crates-io", "cadence", "path"])
+        );
+        assert_eq!(None, get(&root, &["dependencies"]));
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(&member, &["dependencies", "cadence"])
+        );
+        assert_eq!(None, get(&member, &["patch"]));
+    }
+
+    #[test]
+    fn test_apply_overrides_single_crate() {
+        let mut root = Value::Table(parse(PLAIN_DEP));
+        apply_overrides(&mut root, &mut [], &[cadence(local())]);
+        let root = root.as_table().unwrap();
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(root, &["patch", "crates-io", "cadence", "path"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(root, &["dependencies", "cadence"])
+        );
+    }
+
+    #[test]
+    fn test_apply_direct_single_crate() {
+        let mut git = Value::Table(parse(GIT_DEP));
+        apply_direct_in(
+            &mut git,
+            &mut [],
+            &[cadence(local())],
+            DependencySection::ALL,
+        );
+        let git = git.as_table().unwrap();
+
+        assert_eq!(None, get(git, &["patch"]));
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(git, &["dependencies", "cadence", "path"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(git, &["dependencies", "cadence", "version"])
+        );
+        assert_eq!(None, get(git, &["dependencies", "cadence", "git"]));
+        assert_eq!(None, get(git, &["dependencies", "cadence", "branch"]));
+
+        let mut table = Value::Table(parse(TABLE_DEP));
+        apply_direct_in(
+            &mut table,
+            &mut [],
+            &[cadence(local())],
+            DependencySection::ALL,
+        );
+        assert_eq!(
+            Some(&Value::Boolean(false)),
+            get(
+                table.as_table().unwrap(),
+                &["dependencies", "cadence", "default-features"]
+            )
+        );
+
+        let mut none = Value::Table(parse(NO_DEPS));
+        apply_direct_in(
+            &mut none,
+            &mut [],
+            &[cadence(local())],
+            DependencySection::ALL,
+        );
+        assert_eq!(&Value::Table(parse(NO_DEPS)), &none);
+    }
+
+    #[test]
+    fn test_apply_overrides_workspace_inherited() {
+        let mut root = Value::Table(parse(WORKSPACE_GIT_ROOT));
+        let mut members = vec![
+            Value::Table(parse(WORKSPACE_INHERITED_DEP)),
+            Value::Table(parse(PLAIN_DEP)),
+        ];
+
+        apply_overrides_in(
+            &mut root,
+            &mut members,
+            &[cadence(local())],
+            DependencySection::ALL,
+        );
+        let root = root.as_table().unwrap();
+
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(root, &["workspace", "dependencies", "cadence", "version"])
+        );
+        assert_eq!(
+            Some(&Value::String(
+                "https://github.com/56quarters/cadence".to_owned()
+            )),
+            get(root, &["workspace", "dependencies", "cadence", "git"])
+        );
+        assert_eq!(
+            Some(&Value::Boolean(true)),
+            get(
+                members[0].as_table().unwrap(),
+                &["dependencies", "cadence", "workspace"]
+            )
+        );
+        assert_eq!(
+            None,
+            get(
+                members[0].as_table().unwrap(),
+                &["dependencies", "cadence", "version"]
+            )
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(members[1].as_table().unwrap(), &["dependencies", "cadence"])
+        );
+    }
+
+    #[test]
+    fn test_apply_direct_workspace() {
+        let mut root = Value::Table(parse(WORKSPACE_GIT_ROOT));
+        let mut members = vec![
+            Value::Table(parse(WORKSPACE_INHERITED_DEP)),
+            Value::Table(parse(DEV_DEP)),
+        ];
+
+        apply_direct_in(
+            &mut root,
+            &mut members,
+            &[cadence(local())],
+            &[DependencySection::Normal],
+        );
+        let root = root.as_table().unwrap();
+
+        assert_eq!(None, get(root, &["patch"]));
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(root, &["workspace", "dependencies", "cadence", "path"])
+        );
+        assert_eq!(
+            Some(&Value::Array(vec![Value::String("tokio".to_owned())])),
+            get(root, &["workspace", "dependencies", "cadence", "features"])
+        );
+        assert_eq!(
+            Some(&Value::Boolean(true)),
+            get(
+                members[0].as_table().unwrap(),
+                &["dependencies", "cadence", "workspace"]
+            )
+        );
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(
+                members[1].as_table().unwrap(),
+                &["dependencies", "cadence", "path"]
+            )
+        );
+        assert_eq!(
+            Some(&Value::String("0.19".to_owned())),
+            get(
+                members[1].as_table().unwrap(),
+                &["dev-dependencies", "cadence"]
+            )
+        );
+    }
+
+    #[test]
+    fn test_override_mode_from_str() {
+        assert_eq!(OverrideMode::Patch, "patch".parse().unwrap());
+        assert_eq!(OverrideMode::Direct, " direct".parse().unwrap());
+        assert_eq!("direct", OverrideMode::Direct.to_string());
+        assert!("path".parse::<OverrideMode>().is_err());
+    }
+
+    #[test]
+    fn test_apply_overrides_workspace() {
+        let mut root = Value::Table(parse(WORKSPACE_ROOT));
+        let mut members = vec![
+            Value::Table(parse(WORKSPACE_MEMBER)),
+            Value::Table(parse(TABLE_DEP)),
+        ];
+
+        apply_overrides(&mut root, &mut members, &[cadence(local())]);
+        let root = root.as_table().unwrap();
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(root, &["patch", "crates-io", "cadence", "path"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(members[0].as_table().unwrap(), &["dependencies", "cadence"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(
+                members[1].as_table().unwrap(),
+                &["dependencies", "cadence", "version"]
+            )
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_preserves_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = ScratchDir::new("perms");
+        let path = dir.join("Cargo.toml");
+        fs::write(&path, PLAIN_DEP).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_file(&path, PLAIN_DEP.as_bytes(), true).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+
+        assert_eq!(0o640, mode & 0o777);
+    }
+
+    #[test]
+    fn test_check_patches() {
+        let dir = ScratchDir::new("check");
+        let project = dir.join("project");
+        let vendored = project.join("vendor").join("cadence");
+        let extra = dir.join("extra");
+        fs::create_dir_all(&vendored).unwrap();
+        fs::create_dir_all(&extra).unwrap();
+
+        let path = |p: &std::path::Path| PatchSource::Path(p.to_str().unwrap().to_owned());
+        let patch = LocalOverride::new(project.join("Cargo.toml"), Vec::new());
+        let extra_patch = CratePatch::new("extra", "0.1.0", path(&extra));
+
+        let valid = patch.check_patches(&[
+            CratePatch::new("cadence", "0.21.0", path(&dir)),
+            extra_patch.clone(),
+        ]);
+        let inside = patch.check_patches(&[CratePatch::new("cadence", "0.21.0", path(&vendored))]);
+        let same_path = patch.check_patches(&[
+            CratePatch::new("cadence", "0.21.0", path(&extra)),
+            extra_patch.clone(),
+        ]);
+        let twice = patch.check_patches(&[extra_patch.clone(), extra_patch]);
+
+        assert!(valid.is_ok());
+        assert!(inside
+            .unwrap_err()
+            .to_string()
+            .contains("inside the project"));
+        assert!(same_path.unwrap_err().to_string().contains("both use"));
+        assert!(twice.unwrap_err().to_string().contains("more than once"));
+    }
+
+    #[test]
+    fn test_write_files_restores_on_failure() {
+        let dir = ScratchDir::new("atomic");
+        let root = dir.join("Cargo.toml");
+        let created = dir.join("new.toml");
+        let missing = dir.join("missing").join("Cargo.toml");
+        fs::write(&root, PLAIN_DEP).unwrap();
+
+        let res = write_files(
+            &[
+                (root.as_path(), NO_DEPS.to_owned()),
+                (created.as_path(), NO_DEPS.to_owned()),
+                (missing.as_path(), NO_DEPS.to_owned()),
+            ],
+            true,
+        );
+        let contents = fs::read_to_string(&root).unwrap();
+        let exists = created.exists();
+
+        assert!(res.is_err());
+        assert_eq!(PLAIN_DEP, contents);
+        assert!(!exists);
+    }
+
+    #[test]
+    fn test_existing_patches() {
+        let dir = ScratchDir::new("existing");
+        let root = dir.join("Cargo.toml");
+        fs::write(&root, EXISTING_PATCH).unwrap();
+
+        let patch = LocalOverride::new(root, vec![]);
+        let cadence = patch.existing_patches("cadence").unwrap();
+        let internal = patch.existing_patches("internal").unwrap();
+        let tokio = patch.existing_patches("tokio").unwrap();
+
+        assert_eq!(vec!["crates-io".to_owned()], cadence);
+        assert_eq!(vec!["https://example.com/registry".to_owned()], internal);
+        assert!(tokio.is_empty());
+    }
+
+    #[test]
+    fn test_patch_virtual_manifest() {
+        let dir = ScratchDir::new("virtual");
+        let root = dir.join("Cargo.toml");
+        for member in &["crates/a", "crates/b", "crates/skipped"] {
+            fs::create_dir_all(dir.join(member)).unwrap();
+            fs::write(dir.join(member).join("Cargo.toml"), WORKSPACE_MEMBER).unwrap();
+        }
+        fs::write(
+            &root,
+            "[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"crates/skipped\"]\n",
+        )
+        .unwrap();
+
+        let required = LocalOverride::new(root.clone(), vec![]).patch("0.21.0", "/tmp/cadence");
+        let transitive = LocalOverride::new(root.clone(), vec![])
+            .with_transitive_only(true)
+            .patch("0.21.0", "/tmp/cadence");
+        let mut written = LocalOverride::new(root.clone(), vec![])
+            .with_discover_members(true)
+            .patch("0.21.0", "/tmp/cadence")
+            .unwrap();
+        written.sort();
+        let member = fs::read_to_string(dir.join("crates/a/Cargo.toml")).unwrap();
+        let skipped = fs::read_to_string(dir.join("crates/skipped/Cargo.toml")).unwrap();
+
+        fs::write(&root, "[workspace]\nmembers = []\n").unwrap();
+        let empty = LocalOverride::new(root.clone(), vec![])
+            .with_discover_members(true)
+            .patch("0.21.0", "/tmp/cadence");
+
+        assert!(required
+            .unwrap_err()
+            .to_string()
+            .contains("is a virtual workspace manifest, the workspace members to patch"));
+        assert!(transitive.is_ok());
+        assert_eq!(
+            vec![
+                root,
+                dir.join("crates/a/Cargo.toml"),
+                dir.join("crates/b/Cargo.toml"),
+            ],
+            written
+        );
+        assert!(member.contains("cadence = \"0.21.0\""));
+        assert_eq!(WORKSPACE_MEMBER, skipped);
+        assert!(empty
+            .unwrap_err()
+            .to_string()
+            .contains("without any members to patch"));
+    }
+
+    #[test]
+    fn test_patch_without_fsync() {
+        let dir = ScratchDir::new("no-fsync");
+        let root = dir.join("Cargo.toml");
+        fs::write(&root, PLAIN_DEP).unwrap();
+
+        let written = LocalOverride::new(root.clone(), vec![])
+            .with_fsync(false)
+            .patch("0.21.0", "/tmp/cadence")
+            .unwrap();
+        let contents = fs::read_to_string(&root).unwrap();
+
+        assert_eq!(vec![root], written);
+        assert!(contents.contains("[patch.crates-io.cadence]"));
+    }
+
+    #[test]
+    fn test_load_cargo_toml_parse_error() {
+        use super::load_cargo_toml;
+
+        let dir = ScratchDir::new("parse");
+        let path = dir.join("Cargo.toml");
+        fs::write(&path, "[package]\nname = \"downstream\"\nversion 0.1.0\n").unwrap();
+
+        let err = load_cargo_toml(&path).unwrap_err();
+
+        let msg = err.to_string();
+        assert!(msg.starts_with(&format!(
+            "unable to parse TOML file {:?} at line 3, column 9: `version 0.1.0`",
+            path
+        )));
+    }
+
+    #[test]
+    fn test_patch_manifest() {
+        let patched = patch_manifest(PLAIN_DEP, "cadence", "0.21.0", "/src/cadence").unwrap();
+        let table = parse(&patched);
+
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(&table, &["patch", "crates-io", "cadence", "path"])
+        );
+        assert_eq!(
+            Some(&Value::String("0.21.0".to_owned())),
+            get(&table, &["dependencies", "cadence"])
+        );
+        assert_eq!(
+            Some(&Value::String("1.0".to_owned())),
+            get(&table, &["dependencies", "serde"])
+        );
+    }
+
+    #[test]
+    fn test_patch_manifest_parse_error() {
+        let contents = "[package]\nname = \"downstream\"\nversion 0.1.0\n";
+        let err = patch_manifest(contents, "cadence", "0.21.0", "/src/cadence").unwrap_err();
+
+        assert!(err.to_string().starts_with(
+            "unable to parse Cargo.toml contents at line 3, column 9: `version 0.1.0`"
+        ));
+    }
+
+    #[test]
+    fn test_depends_on() {
+        use super::depends_on;
+
+        let dir = ScratchDir::new("depends");
+        let root = dir.join("Cargo.toml");
+        fs::create_dir_all(dir.join("member")).unwrap();
+        let without = "[package]\nname = \"downstream\"\nversion = \"0.1.0\"\n";
+
+        fs::write(&root, PLAIN_DEP).unwrap();
+        let direct = depends_on(&root, "cadence").unwrap();
+
+        fs::write(
+            &root,
+            format!(
+                "{}\n[target.'cfg(unix)'.dependencies]\nmetrics = {{ package = \"cadence\", \
+                 version = \"0.20\" }}\n",
+                without
+            ),
+        )
+        .unwrap();
+        let renamed = depends_on(&root, "cadence").unwrap();
+
+        fs::write(&root, "[workspace]\nmembers = [\"member\"]\n").unwrap();
+        fs::write(dir.join("member").join("Cargo.toml"), WORKSPACE_MEMBER).unwrap();
+        let member = depends_on(&root, "cadence").unwrap();
+
+        fs::write(&root, without).unwrap();
+        let unknown = depends_on(&root, "cadence").unwrap();
+
+        let lock = dir.join("Cargo.lock");
+        fs::write(
+            &lock,
+            "[[package]]\nname = \"cadence\"\nversion = \"0.20.0\"\n",
+        )
+        .unwrap();
+        let locked = depends_on(&root, "cadence").unwrap();
+
+        fs::write(
+            &lock,
+            "[[package]]\nname = \"serde\"\nversion = \"1.0.0\"\n",
+        )
+        .unwrap();
+        let unlocked = depends_on(&root, "cadence").unwrap();
+
+        assert_eq!(Some(true), direct);
+        assert_eq!(Some(true), renamed);
+        assert_eq!(Some(true), member);
+        assert_eq!(None, unknown);
+        assert_eq!(Some(true), locked);
+        assert_eq!(Some(false), unlocked);
+    }
+
+    #[test]
+    fn test_profile_keys() {
+        let root: Value = r#"
+[package]
+name = "downstream"
+version = "0.1.0"
+
+[profile.dev]
+opt-level = 1
+
+[profile.dev.package.cadence]
+debug = false
+
+[profile.release]
+lto = true
+"#
+        .parse()
+        .unwrap();
+
+        assert_eq!(vec!["opt-level", "package"], profile_keys(&root, "dev"));
+        assert_eq!(vec!["lto"], profile_keys(&root, "release"));
+        assert!(profile_keys(&root, "test").is_empty());
+        assert!(profile_keys(&PLAIN_DEP.parse().unwrap(), "dev").is_empty());
+    }
+
+    #[test]
+    fn test_patch_with_suffix() {
+        let dir = ScratchDir::new("suffix");
+        let root = dir.join("Cargo.toml");
+        fs::write(&root, PLAIN_DEP).unwrap();
+
+        let patch = LocalOverride::new(root.clone(), vec![]);
+        let res = patch
+            .with_suffix(Some(".crater".to_owned()))
+            .patch_with("0.2.0", &PatchSource::Registry);
+        let original = fs::read_to_string(&root).unwrap();
+        let patched = fs::read_to_string(dir.join("Cargo.toml.crater")).unwrap();
+
+        assert_eq!(vec![dir.join("Cargo.toml.crater")], res.unwrap());
+        assert_eq!(PLAIN_DEP, original);
+        assert!(patched.contains(r#"cadence = "0.2.0""#));
+    }
+
+    #[test]
+    fn test_patch_crates_files() {
+        let dir = ScratchDir::new("files");
+        let root = dir.join("Cargo.toml");
+        fs::write(&root, PLAIN_DEP).unwrap();
+
+        let files = LocalOverride::new(root.clone(), vec![])
+            .with_mode(OverrideMode::Direct)
+            .patch_crates_files(&[cadence(PatchSource::Registry)])
+            .unwrap();
+        let diff = files[0].diff(&dir);
+
+        assert_eq!(1, files.len());
+        assert_eq!(root.as_path(), files[0].path());
+        assert!(diff.starts_with("--- a/Cargo.toml\n+++ b/Cargo.toml\n@@ "));
+        assert!(diff.contains("\n-cadence = \"0.20\"\n"));
+        assert!(diff.contains("\n+version = \"0.21.0\"\n"));
+    }
+
+    #[test]
+    fn test_patch_transitive_only() {
+        let dir = ScratchDir::new("transitive");
+        let root = dir.join("Cargo.toml");
+        fs::write(
+            &root,
+            "[package]\nname = \"downstream\"\nversion = \"0.1.0\"\n\n\
+             [dependencies]\nstatsd-wrapper = \"1.0\"\n",
+        )
+        .unwrap();
+
+        let res = LocalOverride::new(root.clone(), vec![])
+            .with_transitive_only(true)
+            .with_mode(OverrideMode::Direct)
+            .patch("0.21.0", "/src/cadence");
+        let patched = parse(&fs::read_to_string(&root).unwrap());
+
+        assert!(res.is_ok());
+        assert_eq!(
+            Some(&Value::String("/src/cadence".to_owned())),
+            get(&patched, &["patch", "crates-io", "cadence", "path"])
+        );
+        assert_eq!(None, get(&patched, &["dependencies", "cadence"]));
+        assert_eq!(
+            Some(&Value::String("1.0".to_owned())),
+            get(&patched, &["dependencies", "statsd-wrapper"])
+        );
+    }
+
+    #[test]
+    fn test_local_version_dir_or_manifest() {
+        let dir = ScratchDir::new("local");
+        fs::write(dir.join("Cargo.toml"), PLAIN_DEP).unwrap();
+
+        let from_dir = LocalVersion::new(dir.to_path_buf());
+        let from_manifest = LocalVersion::new(dir.join("Cargo.toml"));
+        let expected = dir.canonicalize().unwrap().to_str().unwrap().to_owned();
+
+        assert_eq!("0.1.0", from_dir.version().unwrap());
+        assert_eq!("0.1.0", from_manifest.version().unwrap());
+        assert_eq!(expected, from_dir.path().unwrap());
+        assert_eq!(expected, from_manifest.path().unwrap());
+    }
+
+    #[test]
+    fn test_local_version_workspace_inheritance() {
+        let dir = ScratchDir::new("inherit");
+        let member = dir.join("cadence");
+        fs::create_dir_all(&member).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"cadence\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+        )
+        .unwrap();
+        fs::write(
+            member.join("Cargo.toml"),
+            "[package]\nname = \"cadence\"\nversion.workspace = true\n",
+        )
+        .unwrap();
+
+        let inherited = LocalVersion::new(&member).version();
+
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"cadence\"]\n",
+        )
+        .unwrap();
+        let missing = LocalVersion::new(&member).version();
+
+        assert_eq!("1.2.3", inherited.unwrap());
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn test_local_version_virtual_manifest() {
+        let dir = ScratchDir::new("virtual-2");
+        fs::create_dir_all(dir.join("cadence")).unwrap();
+        fs::create_dir_all(dir.join("cadence-macros")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"cadence*\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("cadence").join("Cargo.toml"),
+            "[package]\nname = \"cadence\"\nversion.workspace = true\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("cadence-macros").join("Cargo.toml"),
+            "[package]\nname = \"cadence-macros\"\nversion.workspace = true\n",
+        )
+        .unwrap();
+
+        let err = LocalVersion::new(dir.to_path_buf())
+            .version()
+            .unwrap_err()
+            .to_string();
+
+        assert!(err.contains("virtual workspace manifest"));
+        assert!(err.contains(&format!(
+            "likely {:?}",
+            dir.join("cadence").join("Cargo.toml")
+        )));
     }
 }
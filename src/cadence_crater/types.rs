@@ -8,13 +8,14 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use git2::ErrorCode;
 use std::error::Error;
 use std::fmt;
 
 #[derive(Debug)]
 pub struct CraterError {
     msg: String,
-    cause: Option<Box<dyn Error>>,
+    cause: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl CraterError {
@@ -25,7 +26,7 @@ impl CraterError {
         }
     }
 
-    pub fn new_err<S>(msg: S, cause: impl Error + 'static) -> Self
+    pub fn new_err<S>(msg: S, cause: impl Error + Send + Sync + 'static) -> Self
     where
         S: Into<String>,
     {
@@ -34,6 +35,25 @@ impl CraterError {
             cause: Some(Box::new(cause)),
         }
     }
+
+    /// Get the code of the underlying git error if this error was caused by one
+    ///
+    /// This allows callers to distinguish between failures such as authentication
+    /// being required (`ErrorCode::Auth`) or a missing reference (`ErrorCode::NotFound`)
+    /// when cloning or checking out a project.
+    pub fn git_code(&self) -> Option<ErrorCode> {
+        let mut cur = self.source();
+
+        while let Some(e) = cur {
+            if let Some(git) = e.downcast_ref::<git2::Error>() {
+                return Some(git.code());
+            }
+
+            cur = e.source();
+        }
+
+        None
+    }
 }
 
 impl fmt::Display for CraterError {
@@ -46,6 +66,37 @@ impl fmt::Display for CraterError {
     }
 }
 
+/// Display an error followed by each error in its `source()` chain on a separate
+/// "caused by:" line
+///
+/// Unlike the `Display` implementation of `CraterError`, which includes its cause on
+/// the same line, each `CraterError` in the chain only displays its own message.
+pub struct ErrorChain<'a>(pub &'a (dyn Error + 'static));
+
+impl<'a> ErrorChain<'a> {
+    fn write_one(f: &mut fmt::Formatter<'_>, e: &(dyn Error + 'static)) -> fmt::Result {
+        match e.downcast_ref::<CraterError>() {
+            Some(crater) => write!(f, "{}", crater.msg),
+            None => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<'a> fmt::Display for ErrorChain<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Self::write_one(f, self.0)?;
+
+        let mut cur = self.0.source();
+        while let Some(e) = cur {
+            write!(f, "\n  caused by: ")?;
+            Self::write_one(f, e)?;
+            cur = e.source();
+        }
+
+        Ok(())
+    }
+}
+
 impl Error for CraterError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         if let Some(ref err) = self.cause {
@@ -55,3 +106,42 @@ impl Error for CraterError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CraterError, ErrorChain};
+    use git2::ErrorCode;
+    use std::io;
+
+    #[test]
+    fn test_git_code() {
+        let git = git2::Error::new(ErrorCode::Auth, git2::ErrorClass::Http, "auth required");
+        let err = CraterError::new_err(
+            "unable to clone project",
+            CraterError::new_err("unable to fetch", git),
+        );
+
+        assert_eq!(Some(ErrorCode::Auth), err.git_code());
+        assert_eq!(None, CraterError::new("no cause").git_code());
+    }
+
+    #[test]
+    fn test_error_chain() {
+        let err = CraterError::new_err(
+            "unable to patch project",
+            CraterError::new_err(
+                "unable to read TOML file",
+                io::Error::new(io::ErrorKind::NotFound, "no such file"),
+            ),
+        );
+
+        assert_eq!(
+            "unable to patch project\n  caused by: unable to read TOML file\n  caused by: no such file",
+            ErrorChain(&err).to_string()
+        );
+        assert_eq!(
+            "no cause",
+            ErrorChain(&CraterError::new("no cause")).to_string()
+        );
+    }
+}
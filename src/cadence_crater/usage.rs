@@ -0,0 +1,368 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Token of Rust source that matters for finding paths, anything else is `Other`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Ident(&'a str),
+    PathSep,
+    Open,
+    Close,
+    Comma,
+    Star,
+    Other,
+}
+
+/// Count the references to items of the named crate in the Rust sources under a
+/// directory, keyed by their path within the crate such as `StatsdClient` or
+/// `prelude::*`
+///
+/// This is a lexical scan rather than a full parse: paths starting with the crate
+/// name (including in `use` trees) are found outside of comments and string literals,
+/// but items used by their imported name aren't counted again and references made by
+/// macros aren't seen. `target` and hidden directories are skipped.
+pub fn crate_usage(root: &Path, krate: &str) -> io::Result<BTreeMap<String, usize>> {
+    let mut usage = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let kind = entry.file_type()?;
+
+            if kind.is_dir() {
+                if name != "target" && !name.to_string_lossy().starts_with('.') {
+                    dirs.push(entry.path());
+                }
+            } else if kind.is_file() && Path::new(&name).extension() == Some("rs".as_ref()) {
+                let contents = fs::read(entry.path())?;
+                for path in crate_paths(&String::from_utf8_lossy(&contents), krate) {
+                    *usage.entry(path).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Get the path within the named crate of each reference to it in some Rust source
+///
+/// Paths qualified by another module, like `crate::cadence::Foo`, refer to a module
+/// of the same name rather than the crate and are ignored.
+fn crate_paths(src: &str, krate: &str) -> Vec<String> {
+    let tokens = tokenize(src);
+    let mut paths = Vec::new();
+    let mut i = 0;
+
+    while i < tokens.len() {
+        let start = tokens[i] == Token::Ident(krate) && tokens.get(i + 1) == Some(&Token::PathSep);
+        let qualified = i >= 2
+            && tokens[i - 1] == Token::PathSep
+            && matches!(tokens[i - 2], Token::Ident(k) if k != "use");
+
+        if start && !qualified {
+            i += 2;
+            path_tree(&tokens, &mut i, String::new(), &mut paths);
+        } else {
+            i += 1;
+        }
+    }
+
+    paths
+}
+
+/// Add each path of a path or `use` tree starting at `tokens[*i]` to `paths`, each
+/// prefixed with `prefix`, and advance `i` past it
+fn path_tree(tokens: &[Token<'_>], i: &mut usize, prefix: String, paths: &mut Vec<String>) {
+    match tokens.get(*i) {
+        Some(Token::Open) => {
+            *i += 1;
+            loop {
+                match tokens.get(*i) {
+                    None => break,
+                    Some(Token::Close) => {
+                        *i += 1;
+                        break;
+                    }
+                    Some(Token::Comma) => *i += 1,
+                    Some(_) => {
+                        path_tree(tokens, i, prefix.clone(), paths);
+                        // Skip anything left of the tree, such as a rename with `as`
+                        while !matches!(
+                            tokens.get(*i),
+                            None | Some(Token::Comma) | Some(Token::Close)
+                        ) {
+                            *i += 1;
+                        }
+                    }
+                }
+            }
+        }
+        Some(Token::Star) => {
+            *i += 1;
+            paths.push(prefix + "*");
+        }
+        Some(Token::Ident("self")) => {
+            *i += 1;
+            if !prefix.is_empty() {
+                paths.push(prefix.trim_end_matches("::").to_owned());
+            }
+        }
+        Some(Token::Ident(name)) => {
+            *i += 1;
+            let path = format!("{}{}", prefix, name);
+            if tokens.get(*i) != Some(&Token::PathSep) {
+                paths.push(path);
+                return;
+            }
+
+            // Paths continued by something other than a name, like the generic
+            // arguments of `Foo::<T>`, end at the last name.
+            *i += 1;
+            let count = paths.len();
+            path_tree(tokens, i, path.clone() + "::", paths);
+            if paths.len() == count {
+                paths.push(path);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Split Rust source into tokens, skipping whitespace, comments, and literals
+fn tokenize(src: &str) -> Vec<Token<'_>> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let next = bytes.get(i + 1).copied();
+        match bytes[i] {
+            b'/' if next == Some(b'/') => {
+                i = find(bytes, i, b"\n").unwrap_or(bytes.len());
+            }
+            b'/' if next == Some(b'*') => {
+                let mut depth = 0;
+                while i < bytes.len() {
+                    if bytes[i..].starts_with(b"/*") {
+                        depth += 1;
+                        i += 2;
+                    } else if bytes[i..].starts_with(b"*/") {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            b'"' => i = skip_string(bytes, i + 1),
+            b'\'' => i = skip_char(src, i),
+            b':' if next == Some(b':') => {
+                tokens.push(Token::PathSep);
+                i += 2;
+            }
+            b'{' => {
+                tokens.push(Token::Open);
+                i += 1;
+            }
+            b'}' => {
+                tokens.push(Token::Close);
+                i += 1;
+            }
+            b',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            b'*' => {
+                tokens.push(Token::Star);
+                i += 1;
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                let start = i;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+
+                let word = &src[start..i];
+                let quote = bytes.get(i).copied();
+                match (word, quote) {
+                    ("r" | "br" | "cr", Some(b'"' | b'#')) => i = skip_raw_string(bytes, i),
+                    _ => tokens.push(Token::Ident(word)),
+                }
+            }
+            b if b.is_ascii_whitespace() => i += 1,
+            _ => {
+                tokens.push(Token::Other);
+                i += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Find the offset of a pattern in some bytes at or after `from`, which may be past
+/// the end of the bytes when a literal is cut off at the end of a file
+fn find(bytes: &[u8], from: usize, pattern: &[u8]) -> Option<usize> {
+    let from = from.min(bytes.len());
+    bytes[from..]
+        .windows(pattern.len())
+        .position(|w| w == pattern)
+        .map(|p| from + p)
+}
+
+/// Get the offset just past the end of a string literal whose contents start at `i`
+fn skip_string(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return i + 1,
+            _ => i += 1,
+        }
+    }
+
+    bytes.len()
+}
+
+/// Get the offset just past the end of a raw string literal whose `#`s or opening
+/// quote start at `i`, or just past the `#` of a raw identifier like `r#type`
+fn skip_raw_string(bytes: &[u8], mut i: usize) -> usize {
+    let hashes = bytes[i..].iter().take_while(|b| **b == b'#').count();
+    i += hashes;
+    if bytes.get(i) != Some(&b'"') {
+        return i.min(bytes.len());
+    }
+
+    let mut end = vec![b'#'; hashes + 1];
+    end[0] = b'"';
+    find(bytes, i + 1, &end)
+        .map(|e| e + end.len())
+        .unwrap_or(bytes.len())
+}
+
+/// Get the offset just past a character literal starting at `i`, or just past the
+/// quote of a lifetime or label
+fn skip_char(src: &str, i: usize) -> usize {
+    let bytes = src.as_bytes();
+    if bytes.get(i + 1) == Some(&b'\\') {
+        return find(bytes, i + 3, b"'")
+            .map(|e| e + 1)
+            .unwrap_or(bytes.len());
+    }
+
+    match src.get(i + 1..).and_then(|rest| rest.chars().next()) {
+        Some(c) if bytes.get(i + 1 + c.len_utf8()) == Some(&b'\'') => i + 2 + c.len_utf8(),
+        _ => i + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crate_paths, crate_usage};
+    use crate::testing::ScratchDir;
+    use std::fs;
+
+    #[test]
+    fn test_crate_paths_use_trees() {
+        let src = r#"
+            use cadence::prelude::*;
+            use cadence::{self, ext::{MetricValue, ToCounterValue}, StatsdClient as Client};
+            use cadence::metrics::{self};
+            use ::cadence::UdpMetricSink;
+        "#;
+
+        assert_eq!(
+            vec![
+                "prelude::*",
+                "ext::MetricValue",
+                "ext::ToCounterValue",
+                "StatsdClient",
+                "metrics",
+                "UdpMetricSink",
+            ],
+            crate_paths(src, "cadence")
+        );
+    }
+
+    #[test]
+    fn test_crate_paths_expressions() {
+        let src = r##"
+            // cadence::InComment
+            /* cadence::InBlock /* cadence::Nested */ */
+            fn f<'a>(s: &'a str) -> cadence::MetricResult<()> {
+                let c = '"';
+                let s = "cadence::InString \" cadence::Escaped";
+                let r = r#"cadence::InRaw"#;
+                let client = cadence::StatsdClient::builder("p", sink).build();
+                let v = cadence::Counter::<u64>::new();
+                crate::cadence::Local;
+                cadence_macros::statsd_count!("c", 1);
+            }
+        "##;
+
+        assert_eq!(
+            vec!["MetricResult", "StatsdClient::builder", "Counter",],
+            crate_paths(src, "cadence")
+        );
+    }
+
+    #[test]
+    fn test_crate_paths_truncated() {
+        let ends = [
+            "'\\",
+            "'\\n",
+            "'",
+            "\"cadence::A \\",
+            "r#\"cadence::A",
+            "r#",
+            "/* cadence::A",
+        ];
+
+        for end in ends.iter() {
+            let src = format!("use cadence::Counter;\n{}", end);
+            assert_eq!(vec!["Counter"], crate_paths(&src, "cadence"), "{:?}", end);
+        }
+    }
+
+    #[test]
+    fn test_crate_usage() {
+        let dir = ScratchDir::new("usage");
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(
+            dir.join("src").join("lib.rs"),
+            "use cadence::StatsdClient;\nfn f(c: &cadence::StatsdClient) {}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("src").join("main.rs"),
+            "use cadence::prelude::*;\n",
+        )
+        .unwrap();
+        fs::write(dir.join("target").join("gen.rs"), "use cadence::Ignored;\n").unwrap();
+
+        let usage = crate_usage(&dir, "cadence").unwrap();
+
+        assert_eq!(
+            vec![("StatsdClient".to_owned(), 2), ("prelude::*".to_owned(), 1)],
+            usage.into_iter().collect::<Vec<_>>()
+        );
+    }
+}
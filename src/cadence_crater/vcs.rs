@@ -8,54 +8,1315 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use crate::cleanup::{self, Kind};
 use crate::types::CraterError;
-use git2::Repository;
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{
+    BranchType, Direction, FetchOptions, ProxyOptions, Remote, RemoteCallbacks, Repository,
+    SubmoduleUpdateOptions,
+};
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How a project was made available by `Source::fetch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchKind {
+    /// Cloned from the repository
+    Cloned,
+    /// An existing clone in the destination was reused
+    Reused,
+    /// Copied from a clone in the cache
+    CacheHit,
+    /// Copied from a local directory
+    Copied,
+    /// A local directory used as-is
+    InPlace,
+    /// Downloaded and extracted from an archive
+    Downloaded,
+}
+
+impl FetchKind {
+    /// Name of the kind as used in reports
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FetchKind::Cloned => "cloned",
+            FetchKind::Reused => "reused",
+            FetchKind::CacheHit => "cache_hit",
+            FetchKind::Copied => "copied",
+            FetchKind::InPlace => "in_place",
+            FetchKind::Downloaded => "downloaded",
+        }
+    }
+
+    /// Get the kind with the given name as returned by `as_str`
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            FetchKind::Cloned,
+            FetchKind::Reused,
+            FetchKind::CacheHit,
+            FetchKind::Copied,
+            FetchKind::InPlace,
+            FetchKind::Downloaded,
+        ]
+        .iter()
+        .find(|k| k.as_str() == name)
+        .copied()
+    }
+}
+
+impl fmt::Display for FetchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FetchKind::Cloned => write!(f, "cloned"),
+            FetchKind::Reused => write!(f, "reused an existing clone"),
+            FetchKind::CacheHit => write!(f, "copied from the cache"),
+            FetchKind::Copied => write!(f, "copied from a local directory"),
+            FetchKind::InPlace => write!(f, "used a local directory in place"),
+            FetchKind::Downloaded => write!(f, "downloaded an archive"),
+        }
+    }
+}
+
+/// Checkout of a project made available by `Source::fetch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched {
+    path: PathBuf,
+    kind: FetchKind,
+}
+
+impl Fetched {
+    pub fn new(path: PathBuf, kind: FetchKind) -> Self {
+        Fetched { path, kind }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn kind(&self) -> FetchKind {
+        self.kind
+    }
+
+    pub fn into_path(self) -> PathBuf {
+        self.path
+    }
+}
+
+/// Location a project can be fetched from to be patched
+pub trait Source: fmt::Debug + Send {
+    /// Make the project available under the `into` directory, returning its path
+    /// and whether it was cloned, reused, or copied
+    fn fetch(&self, into: &Path) -> Result<Fetched, CraterError>;
+
+    /// Find the checkout of the project left under the `into` directory by an earlier
+    /// `fetch`, such as by `cadence-crater fetch`, instead of fetching it again
+    fn find_fetched(&self, into: &Path) -> Result<Fetched, CraterError>;
+
+    /// URL the project was cloned from by the last `fetch`, if it was cloned
+    fn cloned_from(&self) -> Option<String> {
+        None
+    }
+
+    /// Maximum size in bytes the last `fetch` was cancelled for exceeding, if it was
+    fn exceeded_max_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Why the Git LFS files of the project weren't fetched by the last `fetch`, if
+    /// it uses LFS and they weren't
+    fn missing_lfs(&self) -> Option<String> {
+        None
+    }
+}
 
 /// Clone a repository
 #[derive(Debug)]
 pub struct RemoteRepo {
     url: String,
+    rev: Option<String>,
+    proxy: Option<String>,
+    cache: Option<PathBuf>,
+    submodules: bool,
+    single_branch: bool,
+    offline: bool,
+    mirror_base: Option<String>,
+    max_size: Option<u64>,
+    cloned_from: RefCell<Option<String>>,
+    exceeded: Cell<bool>,
+    missing_rev: Cell<bool>,
+    missing_lfs: RefCell<Option<String>>,
 }
 
 impl RemoteRepo {
     pub fn new(url: String) -> Self {
-        RemoteRepo { url }
+        RemoteRepo {
+            url,
+            rev: None,
+            proxy: None,
+            cache: None,
+            submodules: false,
+            single_branch: false,
+            offline: false,
+            mirror_base: None,
+            max_size: None,
+            cloned_from: RefCell::new(None),
+            exceeded: Cell::new(false),
+            missing_rev: Cell::new(false),
+            missing_lfs: RefCell::new(None),
+        }
+    }
+
+    /// Check out the given branch, tag, or commit instead of the default branch
+    pub fn with_rev<S: Into<String>>(mut self, rev: S) -> Self {
+        self.rev = Some(rev.into());
+        self
+    }
+
+    /// Use the given proxy for cloning instead of the `HTTPS_PROXY` or `HTTP_PROXY`
+    /// environment variables or git configuration
+    pub fn with_proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Keep a pristine clone in the given cache directory to be reused across runs
+    ///
+    /// Cache entries are keyed by the repository URL and revision. Only repositories
+    /// checked out at a specific revision are cached since clones of a default branch
+    /// would otherwise never be updated.
+    pub fn with_cache<P: Into<PathBuf>>(mut self, cache: P) -> Self {
+        self.cache = Some(cache.into());
+        self
     }
 
-    pub fn download<P: AsRef<Path>>(&self, into: P) -> Result<PathBuf, CraterError> {
-        let full = into.as_ref().join(self.proj_name()?);
-        let _repo = Repository::clone(&self.url, &full)
+    /// Initialize and update git submodules (recursively) after cloning or reusing a clone
+    pub fn with_submodules(mut self, submodules: bool) -> Self {
+        self.submodules = submodules;
+        self
+    }
+
+    /// Only fetch the branch that's checked out when cloning, `rev` if it's set or the
+    /// default branch of the repository otherwise
+    ///
+    /// When set, `rev` must be the name of a branch rather than a tag or commit.
+    /// Existing clones are reused as they are.
+    pub fn with_single_branch(mut self, single_branch: bool) -> Self {
+        self.single_branch = single_branch;
+        self
+    }
+
+    /// Never access the network, only reusing existing clones or cache entries
+    ///
+    /// Downloading fails if the repository hasn't already been cloned or a revision
+    /// (or submodule) isn't available locally.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Try cloning from a mirror of the repository under the given base URL first,
+    /// falling back to the repository URL if that fails
+    ///
+    /// The mirror URL is the base followed by the host and path of the repository
+    /// URL, e.g. `https://git.example.com/mirror/github.com/56quarters/cadence.git`
+    /// for a base of `https://git.example.com/mirror`.
+    pub fn with_mirror_base<S: Into<String>>(mut self, base: S) -> Self {
+        self.mirror_base = Some(base.into());
+        self
+    }
+
+    /// Cancel cloning or fetching once more than the given number of bytes have been
+    /// received, removing anything left behind by a cancelled clone
+    ///
+    /// The limit applies to each clone or fetch separately, including those of
+    /// submodules, since the size of a repository isn't known until it's received.
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Clone the repository into a directory under `into` named after it, reusing
+    /// an existing clone or a clone from the cache if there is one
+    pub fn download<P: AsRef<Path>>(&self, into: P) -> Result<Fetched, CraterError> {
+        let full = into.as_ref().join(repo_dir_name(&self.url)?);
+
+        let cache = match (&self.cache, &self.rev) {
+            (Some(cache), Some(rev)) => cache.join(cache_key(&self.url, rev)?),
+            _ => {
+                let kind = self.clone_or_open(&full)?;
+                self.pull_lfs(&full);
+                return Ok(Fetched::new(full, kind));
+            }
+        };
+
+        // Remove cached checkouts that can't be opened or don't have a valid HEAD
+        // so that they get cloned again from scratch.
+        let valid = Repository::open(&cache)
+            .and_then(|r| r.head().map(|_| ()))
+            .is_ok();
+        if cache.exists() && !valid {
+            fs::remove_dir_all(&cache).map_err(|e| {
+                CraterError::new_err(
+                    format!("unable to remove corrupt cached repository {:?}", cache),
+                    e,
+                )
+            })?;
+        }
+
+        if self.offline && !cache.exists() {
+            return Err(CraterError::new(format!(
+                "no cached clone of {} at {} in {:?} while offline",
+                self.url,
+                self.rev.as_deref().unwrap_or_default(),
+                cache
+            )));
+        }
+
+        let kind = match self.clone_or_open(&cache)? {
+            FetchKind::Reused => FetchKind::CacheHit,
+            kind => kind,
+        };
+        self.pull_lfs(&cache);
+
+        let copy = || {
+            if full.exists() {
+                fs::remove_dir_all(&full)?;
+            }
+
+            let _tracked = cleanup::track(Kind::PartialClone, &full);
+
+            copy_dir(&cache, &full)
+        };
+
+        copy().map_err(|e: io::Error| {
+            CraterError::new_err(
+                format!("unable to copy cached repository {:?} to {:?}", cache, full),
+                e,
+            )
+        })?;
+
+        Ok(Fetched::new(full, kind))
+    }
+
+    /// Fetch updates from the `origin` remote into an existing clone at the given path,
+    /// leaving HEAD and the working tree untouched
+    ///
+    /// Returns the names of the refs that were created or updated by the fetch, such
+    /// as `refs/remotes/origin/main`. Errors are returned if the path isn't a clone
+    /// with an `origin` remote, the fetch fails, or the repository is offline.
+    pub fn fetch_only(&self, repo_path: &Path) -> Result<Vec<String>, CraterError> {
+        if self.offline {
+            return Err(CraterError::new(format!(
+                "unable to fetch {} into {:?} while offline",
+                self.url, repo_path
+            )));
+        }
+
+        Repository::open(repo_path)
+            .and_then(|r| self.fetch_origin(&r))
+            .map_err(|e| {
+                CraterError::new_err(
+                    format!("unable to fetch {} into {:?}", self.url, repo_path),
+                    e,
+                )
+            })
+    }
+
+    /// Fetch from the `origin` remote of the repository, returning the updated refs
+    fn fetch_origin(&self, repo: &Repository) -> Result<Vec<String>, git2::Error> {
+        let updated = RefCell::new(Vec::new());
+        let mut callbacks = self.callbacks();
+        callbacks.update_tips(|name, _old, _new| {
+            updated.borrow_mut().push(name.to_owned());
+            true
+        });
+
+        let mut opts = self.fetch_options();
+        opts.remote_callbacks(callbacks);
+        repo.find_remote("origin")?
+            .fetch(&[] as &[&str], Some(&mut opts), None)?;
+
+        drop(opts);
+        Ok(updated.into_inner())
+    }
+
+    /// Clone the repository to the given path or reuse an existing clone there,
+    /// returning which of them was done
+    fn clone_or_open(&self, path: &Path) -> Result<FetchKind, CraterError> {
+        if self.offline && !path.exists() {
+            return Err(CraterError::new(format!(
+                "no existing clone of {} at {:?} while offline",
+                self.url, path
+            )));
+        }
+
+        let _tracked = if path.exists() {
+            None
+        } else {
+            Some(cleanup::track(Kind::PartialClone, path))
+        };
+
+        let fresh = _tracked.is_some();
+        let mut kind = FetchKind::Cloned;
+        self.missing_rev.set(false);
+        self.clone(path)
             .or_else(|e| {
                 if e.code() == git2::ErrorCode::Exists {
-                    Repository::open(&full)
+                    kind = FetchKind::Reused;
+                    Repository::open(path).and_then(|r| {
+                        // Discard changes made to reused checkouts by previous runs
+                        // (such as patching) so each run starts from a clean tree.
+                        r.checkout_head(Some(CheckoutBuilder::new().force()))?;
+
+                        // Clones left checked out at a `rev` by previous runs have a
+                        // detached HEAD, go back to the default branch when no `rev`
+                        // is requested instead of testing whatever commit that was.
+                        if self.rev.is_none() && r.head_detached()? {
+                            checkout_default_branch(&r)?;
+                        }
+
+                        Ok(r)
+                    })
                 } else {
                     Err(e)
                 }
             })
+            .and_then(|r| {
+                if let Some(rev) = &self.rev {
+                    self.checkout_rev(&r, rev)?;
+                }
+
+                if self.submodules {
+                    self.update_submodules(&r)?;
+                }
+
+                Ok(())
+            })
             .map_err(|e| {
-                CraterError::new_err(
-                    format!(
-                        "unable to clone or open repository {} at {:?}",
-                        self.url, full
-                    ),
-                    e,
-                )
+                let max = match self.max_size {
+                    Some(max) if self.exceeded.get() => max,
+                    _ if self.missing_rev.get() => return self.missing_rev_error(path, e),
+                    _ => {
+                        return CraterError::new_err(
+                            format!(
+                                "unable to clone or open repository {} at {:?}",
+                                self.url, path
+                            ),
+                            e,
+                        )
+                    }
+                };
+
+                // Only remove clones started here, an existing clone that couldn't
+                // be updated is left as it was.
+                if fresh && path.exists() {
+                    let _ = fs::remove_dir_all(path);
+                }
+
+                CraterError::new(format!(
+                    "repository {} is larger than the maximum size of {} bytes",
+                    self.url, max
+                ))
             })?;
 
-        Ok(full)
+        Ok(kind)
     }
 
-    fn proj_name(&self) -> Result<String, CraterError> {
-        PathBuf::from(&self.url)
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .map(|s| s.to_owned())
+    /// Clone the repository to the given path, trying the mirror first if there is one
+    fn clone(&self, path: &Path) -> Result<Repository, git2::Error> {
+        let mirror = self
+            .mirror_base
+            .as_ref()
+            .and_then(|base| mirror_url(base, &self.url));
+
+        if let Some(mirror) = mirror {
+            match self.clone_from(&mirror, path) {
+                Ok(r) => {
+                    self.cloned_from.replace(Some(mirror));
+                    return Ok(r);
+                }
+                Err(e) if e.code() == git2::ErrorCode::Exists => return Err(e),
+                // The repository would be just as large when cloned from its own URL
+                Err(e) if self.exceeded.get() => return Err(e),
+                Err(_) => {
+                    // Remove anything left behind by the failed clone so that
+                    // cloning from the repository URL can start from scratch.
+                    if path.exists() {
+                        let _ = fs::remove_dir_all(path);
+                    }
+                }
+            }
+        }
+
+        let repo = self.clone_from(&self.url, path)?;
+        self.cloned_from.replace(Some(self.url.clone()));
+        Ok(repo)
+    }
+
+    /// Clone the repository from the given URL, which may be a mirror, to the given path
+    fn clone_from(&self, url: &str, path: &Path) -> Result<Repository, git2::Error> {
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(self.fetch_options());
+
+        if self.single_branch {
+            let branch = match &self.rev {
+                Some(rev) => rev.clone(),
+                None => self.default_branch(url)?,
+            };
+
+            let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch);
+            builder.branch(&branch);
+            builder
+                .remote_create(move |repo, name, url| repo.remote_with_fetch(name, url, &refspec));
+        }
+
+        let res = builder.clone(url, path);
+
+        // Cloning a single branch fails up front when `rev` isn't a branch
+        let missing = matches!(&res, Err(e) if e.code() == git2::ErrorCode::NotFound);
+        self.missing_rev
+            .set(missing && self.single_branch && self.rev.is_some());
+        res
+    }
+
+    /// Get the name of the default branch of the repository at the given URL
+    /// without cloning it
+    fn default_branch(&self, url: &str) -> Result<String, git2::Error> {
+        let mut remote = Remote::create_detached(url)?;
+        let conn = remote.connect_auth(
+            Direction::Fetch,
+            Some(self.callbacks()),
+            Some(self.proxy_options()),
+        )?;
+
+        let head = conn.default_branch()?;
+        head.as_str()
+            .and_then(|h| h.strip_prefix("refs/heads/"))
+            .map(|b| b.to_owned())
             .ok_or_else(|| {
-                CraterError::new(format!(
-                    "unable to determine project name from {:?}",
-                    self.url
-                ))
+                git2::Error::from_str(&format!("unable to determine default branch of {}", url))
             })
     }
+
+    /// Check out the given revision, fetching from the remote if it isn't available locally
+    fn checkout_rev(&self, repo: &Repository, rev: &str) -> Result<(), git2::Error> {
+        let find = || {
+            repo.revparse_single(rev)
+                .or_else(|_| repo.revparse_single(&format!("origin/{}", rev)))
+        };
+
+        let obj = match find() {
+            Ok(o) => Ok(o),
+            Err(e) if self.offline => Err(e),
+            Err(_) => {
+                self.fetch_origin(repo)?;
+                find()
+            }
+        }
+        .inspect_err(|e| self.missing_rev.set(e.code() == git2::ErrorCode::NotFound))?;
+
+        repo.checkout_tree(&obj, Some(CheckoutBuilder::new().force()))?;
+        repo.set_head_detached(obj.peel_to_commit()?.id())
+    }
+
+    /// Error for a `rev` that isn't a branch, tag, or commit of the repository cloned
+    /// to the given path, caused by the given git error
+    fn missing_rev_error(&self, path: &Path, err: git2::Error) -> CraterError {
+        let hint = if self.single_branch {
+            ". Only that branch is cloned with `single_branch`, so it can't be a tag or a \
+             commit, turn `single_branch` off to use one"
+        } else {
+            ""
+        };
+
+        CraterError::new_err(
+            format!(
+                "rev {:?} of project {} wasn't found in the clone at {:?}, check that it's \
+                 a branch, tag, or commit of the repository{}",
+                self.rev.as_deref().unwrap_or_default(),
+                self.url,
+                path,
+                hint
+            ),
+            err,
+        )
+    }
+
+    /// Initialize and update every submodule, and their submodules, to the commits
+    /// recorded in the repository
+    fn update_submodules(&self, repo: &Repository) -> Result<(), git2::Error> {
+        for mut submodule in repo.submodules()? {
+            let mut opts = SubmoduleUpdateOptions::new();
+            opts.fetch(self.fetch_options());
+            opts.allow_fetch(!self.offline);
+            submodule.update(true, Some(&mut opts))?;
+
+            self.update_submodules(&submodule.open()?)?;
+        }
+
+        Ok(())
+    }
+
+    fn fetch_options(&self) -> FetchOptions<'_> {
+        let mut fetch = FetchOptions::new();
+        fetch.proxy_options(self.proxy_options());
+        fetch.remote_callbacks(self.callbacks());
+        fetch
+    }
+
+    fn proxy_options(&self) -> ProxyOptions<'_> {
+        let mut proxy = ProxyOptions::new();
+        match self.proxy_url() {
+            Some(url) => proxy.url(&url),
+            None => proxy.auto(),
+        };
+
+        proxy
+    }
+
+    /// Callbacks for cloning or fetching that cancel it once the maximum size is exceeded
+    fn callbacks(&self) -> RemoteCallbacks<'_> {
+        let mut callbacks = RemoteCallbacks::new();
+        if let Some(max) = self.max_size {
+            callbacks.transfer_progress(move |stats| {
+                if stats.received_bytes() as u64 > max {
+                    self.exceeded.set(true);
+                    return false;
+                }
+
+                true
+            });
+        }
+
+        callbacks
+    }
+
+    /// Fetch and check out the Git LFS files of the checkout at the given path if it
+    /// uses LFS, recording why they weren't when they can't be
+    ///
+    /// libgit2 doesn't support LFS so the files are left as pointers by the clone and
+    /// are only fetched when `git` and `git-lfs` are installed.
+    fn pull_lfs(&self, path: &Path) {
+        let missing = if !uses_lfs(path) {
+            None
+        } else if self.offline {
+            Some("running offline".to_owned())
+        } else {
+            let mut command = Command::new("git");
+            command.args(["lfs", "pull"]).current_dir(path);
+            if let Some(proxy) = self.proxy_url() {
+                command.env("HTTP_PROXY", &proxy).env("HTTPS_PROXY", &proxy);
+            }
+
+            lfs_pull(command).err()
+        };
+
+        self.missing_lfs.replace(missing);
+    }
+
+    /// Determine the proxy to use from the explicitly set proxy or the environment
+    fn proxy_url(&self) -> Option<String> {
+        if self.proxy.is_some() {
+            return self.proxy.clone();
+        }
+
+        let vars: &[&str] = if self.url.starts_with("https://") {
+            &["HTTPS_PROXY", "https_proxy"]
+        } else if self.url.starts_with("http://") {
+            &["HTTP_PROXY", "http_proxy"]
+        } else {
+            &[]
+        };
+
+        vars.iter()
+            .filter_map(|v| env::var(v).ok())
+            .find(|v| !v.is_empty())
+    }
+}
+
+impl Source for RemoteRepo {
+    fn fetch(&self, into: &Path) -> Result<Fetched, CraterError> {
+        self.download(into)
+    }
+
+    fn find_fetched(&self, into: &Path) -> Result<Fetched, CraterError> {
+        let full = fetched_dir(into.join(repo_dir_name(&self.url)?), &self.url)?;
+
+        // Discard changes made by previous runs and check out `rev` just like when
+        // an existing clone is reused by `download`.
+        self.clone_or_open(&full)?;
+        Ok(Fetched::new(full, FetchKind::Reused))
+    }
+
+    fn cloned_from(&self) -> Option<String> {
+        self.cloned_from.borrow().clone()
+    }
+
+    fn exceeded_max_size(&self) -> Option<u64> {
+        self.max_size.filter(|_| self.exceeded.get())
+    }
+
+    fn missing_lfs(&self) -> Option<String> {
+        self.missing_lfs.borrow().clone()
+    }
+}
+
+/// Returns true if the `.gitattributes` at the root of a checkout stores any paths
+/// with Git LFS
+pub fn uses_lfs(path: &Path) -> bool {
+    fs::read_to_string(path.join(".gitattributes"))
+        .map(|contents| {
+            contents.lines().any(|line| {
+                let line = line.trim();
+                !line.starts_with('#')
+                    && line
+                        .split_whitespace()
+                        .skip(1)
+                        .any(|attr| attr == "filter=lfs")
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Run a `git lfs pull` command, returning why it didn't fetch the LFS files if it failed
+fn lfs_pull(mut command: Command) -> Result<(), String> {
+    let output = command.output().map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => "git isn't installed".to_owned(),
+        _ => format!("unable to run git lfs pull: {}", e),
+    })?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.contains("'lfs' is not a git command") {
+        Err("git-lfs isn't installed".to_owned())
+    } else {
+        Err(format!(
+            "git lfs pull failed: {}",
+            stderr.lines().next().unwrap_or_default().trim()
+        ))
+    }
+}
+
+/// Get the URL of a mirror of the repository under the given base URL
+///
+/// The scheme and any user are removed from the repository URL and the remaining
+/// host and path are appended to the base. `None` is returned if the repository URL
+/// doesn't have a host.
+pub fn mirror_url(base: &str, url: &str) -> Option<String> {
+    let without_scheme = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+
+    let without_user = match without_scheme.split_once('@') {
+        Some((_, rest)) if !rest.is_empty() => rest,
+        _ => without_scheme,
+    };
+
+    // scp-like ssh URLs (`host:path`) separate the host and path with a colon
+    let (host, path) = match without_user.split_once([':', '/']) {
+        Some((host, path)) => (host, path.trim_start_matches('/')),
+        None => return None,
+    };
+
+    if host.is_empty() || path.is_empty() {
+        return None;
+    }
+
+    Some(format!("{}/{}/{}", base.trim_end_matches('/'), host, path))
+}
+
+/// Check out the default branch of the `origin` remote in a repository with a detached HEAD
+///
+/// The default branch is the target of `origin/HEAD` if it exists or the only local
+/// branch otherwise. An error describing the detached state is returned if the default
+/// branch can't be determined.
+fn checkout_default_branch(repo: &Repository) -> Result<(), git2::Error> {
+    let from_origin = repo
+        .find_reference("refs/remotes/origin/HEAD")
+        .ok()
+        .and_then(|r| r.symbolic_target().map(|t| t.to_owned()))
+        .and_then(|t| {
+            t.strip_prefix("refs/remotes/origin/")
+                .map(|b| format!("refs/heads/{}", b))
+        })
+        .filter(|b| repo.find_reference(b).is_ok());
+
+    let branch = match from_origin {
+        Some(b) => b,
+        None => {
+            let mut local = Vec::new();
+            for branch in repo.branches(Some(BranchType::Local))? {
+                if let Some(name) = branch?.0.get().name() {
+                    local.push(name.to_owned());
+                }
+            }
+
+            if local.len() != 1 {
+                let head = repo.head()?.peel_to_commit()?.id();
+                return Err(git2::Error::from_str(&format!(
+                    "existing clone has a detached HEAD at {} and its default branch \
+                     could not be determined, remove the clone or set `rev`",
+                    head
+                )));
+            }
+
+            local.remove(0)
+        }
+    };
+
+    let obj = repo.revparse_single(&branch)?;
+    repo.checkout_tree(&obj, Some(CheckoutBuilder::new().force()))?;
+    repo.set_head(&branch)
+}
+
+/// Get the checkout at `path` left by an earlier fetch of the project from `repo`,
+/// returning an error if it hasn't been fetched
+pub fn fetched_dir(path: PathBuf, repo: &str) -> Result<PathBuf, CraterError> {
+    if path.is_dir() {
+        Ok(path)
+    } else {
+        Err(CraterError::new(format!(
+            "{} hasn't been fetched into {:?}, fetch it with `cadence-crater fetch` first",
+            repo, path
+        )))
+    }
+}
+
+/// Get the commit checked out in the git repository containing `path`, if any
+pub fn head_sha(path: &Path) -> Option<String> {
+    Repository::discover(path)
+        .and_then(|r| r.head()?.peel_to_commit().map(|c| c.id().to_string()))
+        .ok()
+}
+
+/// Get the name of the directory a repository is cloned into from its URL
+///
+/// This is the last path component of the URL without any trailing slashes or
+/// `.git` suffix, e.g. `cadence` for both `https://github.com/56quarters/cadence/`
+/// and `git@github.com:56quarters/cadence.git`. The extension of archives is removed
+/// as well, e.g. `cadence-1.0` for `https://example.com/cadence-1.0.tar.gz`.
+pub fn repo_dir_name(url: &str) -> Result<String, CraterError> {
+    let trimmed = url.trim().trim_end_matches('/');
+    let last = trimmed.rsplit(['/', ':']).next().unwrap_or(trimmed);
+    let last = last.split(['?', '#']).next().unwrap_or(last);
+    let name = [".git", ".tar.gz", ".tgz", ".zip"]
+        .iter()
+        .find_map(|s| last.strip_suffix(s))
+        .unwrap_or(last);
+
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(CraterError::new(format!(
+            "unable to determine project name from {:?}",
+            url
+        )));
+    }
+
+    Ok(name.to_owned())
+}
+
+/// Stable key for a cached clone of a repository at a particular revision
+///
+/// The key is the directory name of the repository followed by a hash of the URL
+/// and revision (see `stable_hash`).
+fn cache_key(url: &str, rev: &str) -> Result<String, CraterError> {
+    let hash = stable_hash(&[url, rev]);
+    Ok(format!("{}-{:016x}", repo_dir_name(url)?, hash))
+}
+
+/// Hash the given strings, separated by NUL bytes, for use in file names that must be
+/// the same from one run to the next
+///
+/// This uses the 64-bit FNV-1a hash since, unlike the standard library hasher, its
+/// output is guaranteed to be the same across Rust versions and runs.
+pub fn stable_hash(parts: &[&str]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in parts.join("\0").bytes() {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash
+}
+
+/// Prefix of repository URLs that refer to a directory on the local filesystem
+const FILE_URL_PREFIX: &str = "file://";
+
+/// Copy a project from a local directory instead of cloning it
+///
+/// By default the project is copied into the destination directory so that
+/// patching doesn't modify the original. When `in_place` is set, the original
+/// directory is used (and patched) directly.
+#[derive(Debug)]
+pub struct LocalDirSource {
+    path: PathBuf,
+    in_place: bool,
+}
+
+impl LocalDirSource {
+    pub fn new<P: Into<PathBuf>>(path: P, in_place: bool) -> Self {
+        LocalDirSource {
+            path: path.into(),
+            in_place,
+        }
+    }
+
+    /// Create a `LocalDirSource` if the given repository refers to a local directory
+    ///
+    /// Repositories are considered local if they use a `file://` URL or are a
+    /// path to an existing directory.
+    pub fn from_repo(repo: &str, in_place: bool) -> Option<Self> {
+        if let Some(path) = repo.strip_prefix(FILE_URL_PREFIX) {
+            return Some(Self::new(path, in_place));
+        }
+
+        if Path::new(repo).is_dir() {
+            Some(Self::new(repo, in_place))
+        } else {
+            None
+        }
+    }
+
+    /// Directory the project is copied from (or patched in, when in place)
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Source for LocalDirSource {
+    fn fetch(&self, into: &Path) -> Result<Fetched, CraterError> {
+        let src = self.path.canonicalize().map_err(|e| {
+            CraterError::new_err(
+                format!("unable to open local project directory {:?}", self.path),
+                e,
+            )
+        })?;
+
+        if self.in_place {
+            return Ok(Fetched::new(src, FetchKind::InPlace));
+        }
+
+        let name = src.file_name().ok_or_else(|| {
+            CraterError::new(format!(
+                "unable to determine project name from {:?}",
+                self.path
+            ))
+        })?;
+
+        // Start from a fresh copy each time so that changes made to the original
+        // since the last run (and patches made to the previous copy) don't linger.
+        let full = into.join(name);
+        if full.starts_with(&src) {
+            return Err(CraterError::new(format!(
+                "unable to copy local project {:?} into itself at {:?}",
+                src, full
+            )));
+        }
+
+        let copy = || {
+            if full.exists() {
+                fs::remove_dir_all(&full)?;
+            }
+
+            let _tracked = cleanup::track(Kind::PartialClone, &full);
+
+            copy_dir(&src, &full)
+        };
+
+        copy().map_err(|e: io::Error| {
+            CraterError::new_err(
+                format!("unable to copy local project {:?} to {:?}", src, full),
+                e,
+            )
+        })?;
+
+        Ok(Fetched::new(full, FetchKind::Copied))
+    }
+
+    fn find_fetched(&self, into: &Path) -> Result<Fetched, CraterError> {
+        if self.in_place {
+            return self.fetch(into);
+        }
+
+        // The original may have been removed since it was copied
+        let src = self
+            .path
+            .canonicalize()
+            .unwrap_or_else(|_| self.path.clone());
+        let name = src.file_name().ok_or_else(|| {
+            CraterError::new(format!(
+                "unable to determine project name from {:?}",
+                self.path
+            ))
+        })?;
+
+        let repo = self.path.to_string_lossy();
+        let full = fetched_dir(into.join(name), &repo)?;
+        Ok(Fetched::new(full, FetchKind::Reused))
+    }
+}
+
+/// Recursively copy a directory, skipping any top-level build output
+///
+/// Symbolic links are recreated as links rather than copying what they point to,
+/// so links to directories (or loops of them) aren't followed.
+fn copy_dir(src: &Path, dst: &Path) -> io::Result<()> {
+    copy_dir_at(src, dst, 0)
+}
+
+/// Recursively copy a directory `depth` levels below the top-level one being copied
+fn copy_dir_at(src: &Path, dst: &Path, depth: usize) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        let dest = dst.join(entry.file_name());
+
+        if depth == 0 && entry.file_name() == "target" {
+            continue;
+        }
+
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_symlink() {
+            copy_link(&path, &dest)?;
+        } else if file_type.is_dir() {
+            copy_dir_at(&path, &dest, depth + 1)?;
+        } else {
+            fs::copy(&path, &dest)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Create a symbolic link at `dst` pointing to the same target as the link at `src`
+#[cfg(unix)]
+fn copy_link(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+
+/// Create a symbolic link at `dst` pointing to the same target as the link at `src`
+#[cfg(windows)]
+fn copy_link(src: &Path, dst: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+
+    let target = fs::read_link(src)?;
+    if fs::metadata(src).map(|m| m.is_dir()).unwrap_or(false) {
+        symlink_dir(target, dst)
+    } else {
+        symlink_file(target, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cache_key, copy_dir, mirror_url, repo_dir_name, stable_hash, uses_lfs, FetchKind,
+        RemoteRepo, Source,
+    };
+    use crate::testing::ScratchDir;
+    use git2::{Repository, Signature};
+    use std::fs;
+    use std::path::Path;
+
+    /// Commit a file with the given contents to the current branch of the repository
+    fn commit(repo: &Repository, contents: &str) -> git2::Oid {
+        let dir = repo.workdir().unwrap();
+        fs::write(dir.join("README"), contents).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("README")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("crater", "crater@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+
+        repo.commit(Some("HEAD"), &sig, &sig, contents, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_uses_lfs() {
+        let dir = ScratchDir::new("lfs");
+        assert!(!uses_lfs(&dir));
+
+        fs::write(
+            dir.join(".gitattributes"),
+            "*.rs text eol=lf\n# *.bin filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        assert!(!uses_lfs(&dir));
+
+        fs::write(
+            dir.join(".gitattributes"),
+            "*.rs text eol=lf\nassets/** filter=lfs diff=lfs merge=lfs -text\n",
+        )
+        .unwrap();
+        assert!(uses_lfs(&dir));
+    }
+
+    #[test]
+    fn test_fetch_only() {
+        let dir = ScratchDir::new("fetch");
+        let origin = Repository::init(dir.join("origin")).unwrap();
+        let first = commit(&origin, "first");
+
+        let url = format!("file://{}", dir.join("origin").display());
+        let remote = RemoteRepo::new(url);
+        let clone_path = remote.download(dir.join("clones")).unwrap().into_path();
+        let reused = remote.download(dir.join("clones")).unwrap();
+
+        let second = commit(&origin, "second");
+        let updated = remote.fetch_only(&clone_path).unwrap();
+        let unchanged = remote.fetch_only(&clone_path).unwrap();
+
+        let clone = Repository::open(&clone_path).unwrap();
+        let head = clone.head().unwrap().peel_to_commit().unwrap().id();
+        let contents = fs::read_to_string(clone_path.join("README")).unwrap();
+        let branch = origin.head().unwrap().shorthand().unwrap().to_owned();
+        let fetched = clone
+            .revparse_single(&format!("origin/{}", branch))
+            .unwrap()
+            .id();
+        let offline = RemoteRepo::new("file:///nowhere".to_owned())
+            .with_offline(true)
+            .fetch_only(&clone_path);
+
+        assert_eq!(FetchKind::Reused, reused.kind());
+        assert_eq!(clone_path, reused.path());
+        assert_eq!(vec![format!("refs/remotes/origin/{}", branch)], updated);
+        assert!(unchanged.is_empty());
+        assert_eq!(first, head);
+        assert_eq!("first", contents);
+        assert_eq!(second, fetched);
+        assert!(offline.is_err());
+    }
+
+    #[test]
+    fn test_download_single_branch() {
+        let dir = ScratchDir::new("single");
+        let origin = Repository::init(dir.join("origin")).unwrap();
+        let first = commit(&origin, "first");
+        let default = origin.head().unwrap().shorthand().unwrap().to_owned();
+        origin
+            .branch("feature", &origin.find_commit(first).unwrap(), false)
+            .unwrap();
+        origin.set_head("refs/heads/feature").unwrap();
+        let second = commit(&origin, "second");
+        origin.set_head(&format!("refs/heads/{}", default)).unwrap();
+
+        let url = format!("file://{}", dir.join("origin").display());
+        // Newer versions of libgit2 also create `origin/HEAD`, which isn't a branch
+        let remote_branches = |path: &Path| {
+            let repo = Repository::open(path).unwrap();
+            let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+            let mut names: Vec<String> = repo
+                .branches(Some(git2::BranchType::Remote))
+                .unwrap()
+                .map(|b| b.unwrap().0.name().unwrap().unwrap().to_owned())
+                .filter(|name| name != "origin/HEAD")
+                .collect();
+            names.sort();
+            (head, names)
+        };
+
+        let default_clone = RemoteRepo::new(url.clone())
+            .with_single_branch(true)
+            .download(dir.join("default"))
+            .unwrap();
+        let feature_clone = RemoteRepo::new(url)
+            .with_single_branch(true)
+            .with_rev("feature")
+            .download(dir.join("feature"))
+            .unwrap();
+
+        let default_branches = remote_branches(default_clone.path());
+        let feature_branches = remote_branches(feature_clone.path());
+
+        assert_eq!(
+            (first, vec![format!("origin/{}", default)]),
+            default_branches
+        );
+        assert_eq!(
+            (second, vec!["origin/feature".to_owned()]),
+            feature_branches
+        );
+    }
+
+    #[test]
+    fn test_download_missing_rev() {
+        let dir = ScratchDir::new("missing-rev");
+        let origin = Repository::init(dir.join("origin")).unwrap();
+        commit(&origin, "first");
+
+        let url = format!("file://{}", dir.join("origin").display());
+        let missing = RemoteRepo::new(url.clone())
+            .with_rev("no-such-branch")
+            .download(dir.join("all"))
+            .unwrap_err()
+            .to_string();
+        let single = RemoteRepo::new(url.clone())
+            .with_single_branch(true)
+            .with_rev("no-such-branch")
+            .download(dir.join("single"))
+            .unwrap_err()
+            .to_string();
+
+        let expected = format!("rev \"no-such-branch\" of project {} wasn't found", url);
+        assert!(missing.starts_with(&expected));
+        assert!(!missing.contains("single_branch"));
+        assert!(single.starts_with(&expected));
+        assert!(single.contains("Only that branch is cloned with `single_branch`"));
+    }
+
+    #[test]
+    fn test_download_max_size() {
+        let dir = ScratchDir::new("max-size");
+        let origin = Repository::init(dir.join("origin")).unwrap();
+        let contents: Vec<String> = (0..20_000u64)
+            .map(|i| (i * 7919 % 10_007).to_string())
+            .collect();
+        commit(&origin, &contents.join(","));
+
+        let url = format!("file://{}", dir.join("origin").display());
+        let small = RemoteRepo::new(url.clone()).with_max_size(1024);
+        let res = small.download(dir.join("small"));
+        let partial = dir.join("small").join("origin").exists();
+
+        let large = RemoteRepo::new(url).with_max_size(1024 * 1024);
+        let fetched = large.download(dir.join("large"));
+
+        assert!(res.is_err());
+        assert_eq!(Some(1024), small.exceeded_max_size());
+        assert!(!partial);
+        assert!(fetched.is_ok());
+        assert_eq!(None, large.exceeded_max_size());
+    }
+
+    #[test]
+    fn test_repo_dir_name_https() {
+        assert_eq!(
+            "cadence",
+            repo_dir_name("https://github.com/56quarters/cadence").unwrap()
+        );
+        assert_eq!(
+            "cadence",
+            repo_dir_name("https://github.com/56quarters/cadence.git").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_repo_dir_name_ssh() {
+        assert_eq!(
+            "cadence",
+            repo_dir_name("git@github.com:56quarters/cadence.git").unwrap()
+        );
+        assert_eq!(
+            "cadence",
+            repo_dir_name("git@example.com:cadence.git").unwrap()
+        );
+        assert_eq!(
+            "cadence",
+            repo_dir_name("ssh://git@github.com/56quarters/cadence.git").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_repo_dir_name_trailing_slash() {
+        assert_eq!(
+            "cadence",
+            repo_dir_name("https://github.com/56quarters/cadence/").unwrap()
+        );
+        assert_eq!(
+            "cadence",
+            repo_dir_name("https://github.com/56quarters/cadence.git//").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_repo_dir_name_archive() {
+        assert_eq!(
+            "cadence-1.0",
+            repo_dir_name("https://example.com/cadence-1.0.tar.gz").unwrap()
+        );
+        assert_eq!(
+            "def",
+            repo_dir_name("https://gist.github.com/u/abc/archive/def.zip?raw=1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_repo_dir_name_invalid() {
+        assert!(repo_dir_name("").is_err());
+        assert!(repo_dir_name("https://").is_err());
+        assert!(repo_dir_name("https://example.com/..").is_err());
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let key = cache_key("https://github.com/56quarters/cadence.git", "v1.0.0").unwrap();
+        let other = cache_key("https://github.com/56quarters/cadence.git", "v1.0.1").unwrap();
+
+        assert!(key.starts_with("cadence-"));
+        assert_ne!(key, other);
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        assert_eq!(0xcbf2_9ce4_8422_2325, stable_hash(&[]));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, stable_hash(&["a"]));
+        assert_ne!(stable_hash(&["a", "b"]), stable_hash(&["ab"]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_dir() {
+        use std::os::unix::fs::symlink;
+
+        let dir = ScratchDir::new("copy-dir");
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("target")).unwrap();
+        fs::create_dir_all(src.join("nested").join("target")).unwrap();
+        fs::write(src.join("nested").join("target").join("lib.rs"), "").unwrap();
+        symlink("..", src.join("nested").join("parent")).unwrap();
+
+        let dst = dir.join("dst");
+        let res = copy_dir(&src, &dst);
+        let top_target = dst.join("target").exists();
+        let nested = dst.join("nested").join("target").join("lib.rs").is_file();
+        let link = fs::symlink_metadata(dst.join("nested").join("parent")).map(|m| m.file_type());
+
+        res.unwrap();
+        assert!(!top_target);
+        assert!(nested);
+        assert!(link.unwrap().is_symlink());
+    }
+
+    #[test]
+    fn test_mirror_url() {
+        let base = "https://git.example.com/mirror/";
+
+        assert_eq!(
+            Some("https://git.example.com/mirror/github.com/56quarters/cadence.git".to_owned()),
+            mirror_url(base, "https://github.com/56quarters/cadence.git")
+        );
+        assert_eq!(
+            Some("https://git.example.com/mirror/github.com/56quarters/cadence.git".to_owned()),
+            mirror_url(base, "git@github.com:56quarters/cadence.git")
+        );
+        assert_eq!(
+            Some("https://git.example.com/mirror/github.com/56quarters/cadence.git".to_owned()),
+            mirror_url(base, "ssh://git@github.com/56quarters/cadence.git")
+        );
+        assert_eq!(None, mirror_url(base, "https://github.com"));
+    }
 }
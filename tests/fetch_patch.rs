@@ -0,0 +1,324 @@
+// cadence-crater - backwards compatibility testing for cadence
+//
+// Copyright 2021 Nick Pillitteri
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use cadence_crater::cleanup;
+use cadence_crater::cmd::CraterApplication;
+use cadence_crater::toml::{LocalOverride, LocalVersion};
+use cadence_crater::vcs::{FetchKind, RemoteRepo};
+use clap::Clap;
+use git2::{Repository, Signature};
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use toml::Value;
+
+const PROJECT_CARGO_TOML: &str = r#"[package]
+name = "downstream"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+cadence = { version = "0.20", default-features = false }
+"#;
+
+const CADENCE_CARGO_TOML: &str = r#"[package]
+name = "cadence"
+version = "0.99.0"
+edition = "2018"
+"#;
+
+/// Empty scratch directory for a test, removed along with everything in it when
+/// dropped, even if the test fails
+struct Scratch {
+    path: PathBuf,
+}
+
+impl Deref for Scratch {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Empty scratch directory unique to the test and process
+fn scratch(name: &str) -> Scratch {
+    let dir = env::temp_dir().join(format!("cadence-crater-it-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    Scratch {
+        path: dir.canonicalize().unwrap(),
+    }
+}
+
+/// Create a local Cadence crate and a git repository of a project depending on it
+/// under `dir`, returning their paths
+fn fixtures(dir: &Path) -> (PathBuf, PathBuf) {
+    let cadence = dir.join("cadence");
+    fs::create_dir_all(cadence.join("src")).unwrap();
+    fs::write(cadence.join("Cargo.toml"), CADENCE_CARGO_TOML).unwrap();
+    fs::write(cadence.join("src").join("lib.rs"), "").unwrap();
+
+    (cadence, project_repo(dir, "downstream"))
+}
+
+/// Create a git repository of a project depending on Cadence under `dir` with the
+/// given name, returning its path
+fn project_repo(dir: &Path, name: &str) -> PathBuf {
+    let project = dir.join(name);
+    fs::create_dir_all(project.join("src")).unwrap();
+    fs::write(project.join("Cargo.toml"), PROJECT_CARGO_TOML).unwrap();
+    fs::write(project.join("src").join("lib.rs"), "").unwrap();
+
+    let repo = Repository::init(&project).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new("Cargo.toml")).unwrap();
+    index.add_path(Path::new("src/lib.rs")).unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = Signature::now("crater", "crater@example.com").unwrap();
+    repo.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[])
+        .unwrap();
+
+    project
+}
+
+fn load(path: &Path) -> Value {
+    fs::read_to_string(path).unwrap().parse().unwrap()
+}
+
+fn get<'a>(value: &'a Value, keys: &[&str]) -> Option<&'a Value> {
+    keys.iter().try_fold(value, |v, key| v.get(*key))
+}
+
+#[test]
+fn test_clone_and_patch() {
+    let dir = scratch("clone");
+    let (cadence, project) = fixtures(&dir);
+
+    let url = format!("file://{}", project.display());
+    let fetched = RemoteRepo::new(url).download(dir.join("clones")).unwrap();
+    let checkout = fetched.path().to_path_buf();
+
+    let local = LocalVersion::new(&cadence);
+    let version = local.version().unwrap();
+    let path = local.path().unwrap();
+    let written = LocalOverride::new(checkout.join("Cargo.toml"), vec![])
+        .patch(&version, &path)
+        .unwrap();
+
+    let patched = load(&checkout.join("Cargo.toml"));
+    let original = fs::read_to_string(project.join("Cargo.toml")).unwrap();
+
+    assert_eq!(FetchKind::Cloned, fetched.kind());
+    assert_eq!(vec![checkout.join("Cargo.toml")], written);
+    assert_eq!(dir.join("clones").join("downstream"), checkout);
+    assert_eq!(
+        Some(&Value::String("0.99.0".to_owned())),
+        get(&patched, &["dependencies", "cadence", "version"])
+    );
+    assert_eq!(
+        Some(&Value::Boolean(false)),
+        get(&patched, &["dependencies", "cadence", "default-features"])
+    );
+    assert_eq!(
+        Some(&Value::String(path)),
+        get(&patched, &["patch", "crates-io", "cadence", "path"])
+    );
+    assert_eq!(PROJECT_CARGO_TOML, original);
+}
+
+#[test]
+fn test_concurrent_clone_and_patch() {
+    const PROJECTS: usize = 16;
+
+    let dir = scratch("concurrent");
+    let (cadence, _) = fixtures(&dir);
+    let local = LocalVersion::new(&cadence);
+    let version = local.version().unwrap();
+    let path = local.path().unwrap();
+    let clones = dir.join("clones");
+
+    let urls: Vec<String> = (0..PROJECTS)
+        .map(|i| {
+            format!(
+                "file://{}",
+                project_repo(&dir, &format!("project-{}", i)).display()
+            )
+        })
+        .collect();
+
+    // Clone every project into the same destination at once, then patch each of them
+    // from two threads at once so that their temporary files would collide if they
+    // weren't unique.
+    let checkouts: Vec<PathBuf> = urls
+        .into_iter()
+        .map(|url| {
+            let clones = clones.clone();
+            thread::spawn(move || RemoteRepo::new(url).download(clones).unwrap().into_path())
+        })
+        .collect::<Vec<_>>()
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect();
+
+    let patches: Vec<_> = checkouts
+        .iter()
+        .chain(checkouts.iter())
+        .map(|checkout| {
+            let manifest = checkout.join("Cargo.toml");
+            let (version, path) = (version.clone(), path.clone());
+            thread::spawn(move || {
+                LocalOverride::new(manifest, vec![])
+                    .patch(&version, &path)
+                    .map_err(|e| e.to_string())
+            })
+        })
+        .collect();
+    let results: Vec<_> = patches.into_iter().map(|h| h.join().unwrap()).collect();
+
+    let patched: Vec<Value> = checkouts
+        .iter()
+        .map(|checkout| load(&checkout.join("Cargo.toml")))
+        .collect();
+    let leftover = cleanup::find_temp_files(&clones).unwrap();
+
+    assert_eq!(
+        Vec::<String>::new(),
+        results
+            .into_iter()
+            .filter_map(Result::err)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(PROJECTS, patched.len());
+    for (i, manifest) in patched.iter().enumerate() {
+        assert_eq!(clones.join(format!("project-{}", i)), checkouts[i]);
+        assert_eq!(
+            Some(&Value::String("0.99.0".to_owned())),
+            get(manifest, &["dependencies", "cadence", "version"])
+        );
+        assert_eq!(
+            Some(&Value::String(path.clone())),
+            get(manifest, &["patch", "crates-io", "cadence", "path"])
+        );
+    }
+    assert!(leftover.is_empty());
+}
+
+#[test]
+fn test_application_patch_only() {
+    let dir = scratch("app");
+    let (cadence, project) = fixtures(&dir);
+
+    let config = dir.join("crater.toml");
+    fs::write(
+        &config,
+        format!("[[projects]]\nrepo = \"file://{}\"\n", project.display()),
+    )
+    .unwrap();
+
+    let dest = dir.join("dest");
+    let app = CraterApplication::try_parse_from(vec![
+        "cadence-crater".as_ref(),
+        "--quiet".as_ref(),
+        "--no-cache".as_ref(),
+        "--patch-suffix".as_ref(),
+        ".crater".as_ref(),
+        "--dest".as_ref(),
+        dest.as_os_str(),
+        cadence.as_os_str(),
+        config.as_os_str(),
+    ])
+    .unwrap();
+    let res = app.run();
+
+    let original = fs::read_to_string(dest.join("downstream").join("Cargo.toml")).unwrap();
+    let patched = load(&dest.join("downstream").join("Cargo.toml.crater"));
+
+    assert!(res.is_ok());
+    assert_eq!(PROJECT_CARGO_TOML, original);
+    assert_eq!(
+        Some(&Value::String("0.99.0".to_owned())),
+        get(&patched, &["dependencies", "cadence", "version"])
+    );
+    assert_eq!(
+        Some(&Value::String(cadence.to_str().unwrap().to_owned())),
+        get(&patched, &["patch", "crates-io", "cadence", "path"])
+    );
+}
+
+#[test]
+fn test_application_fetch_then_run_built() {
+    let dir = scratch("run-built");
+    let (cadence, project) = fixtures(&dir);
+
+    let config = dir.join("crater.toml");
+    fs::write(
+        &config,
+        format!("[[projects]]\nrepo = \"file://{}\"\n", project.display()),
+    )
+    .unwrap();
+
+    let dest = dir.join("dest");
+    let report = dir.join("report");
+    let fetch = CraterApplication::try_parse_from(vec![
+        "cadence-crater".as_ref(),
+        "fetch".as_ref(),
+        "--no-cache".as_ref(),
+        "--dest".as_ref(),
+        dest.as_os_str(),
+        config.as_os_str(),
+    ])
+    .unwrap();
+    let fetched = fetch.run();
+
+    // The later stage only uses what was fetched, not the original
+    fs::remove_dir_all(&project).unwrap();
+
+    let run_built = CraterApplication::try_parse_from(vec![
+        "cadence-crater".as_ref(),
+        "--quiet".as_ref(),
+        "--no-cache".as_ref(),
+        "--patch-suffix".as_ref(),
+        ".crater".as_ref(),
+        "--dest".as_ref(),
+        dest.as_os_str(),
+        "--output-dir".as_ref(),
+        report.as_os_str(),
+        cadence.as_os_str(),
+        config.as_os_str(),
+        "run-built".as_ref(),
+    ])
+    .unwrap();
+    let res = run_built.run();
+
+    let patched = load(&dest.join("downstream").join("Cargo.toml.crater"));
+    let diff = fs::read_dir(report.join("projects"))
+        .unwrap()
+        .next()
+        .and_then(|project| fs::read_to_string(project.unwrap().path().join("manifest.diff")).ok());
+
+    assert!(fetched.is_ok());
+    assert!(res.is_ok());
+    assert_eq!(
+        Some(&Value::String("0.99.0".to_owned())),
+        get(&patched, &["dependencies", "cadence", "version"])
+    );
+    assert!(diff.unwrap().contains("+version = \"0.99.0\"\n"));
+}